@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE idempotency_keys (
+    username TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    request_fingerprint TEXT NOT NULL,
+    response_status SMALLINT,
+    response_body TEXT,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    expires_at timestamptz NOT NULL,
+
+    PRIMARY KEY (username, idempotency_key),
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
@@ -0,0 +1,102 @@
+-- Add migration script here
+CREATE TABLE ledger_accounts (
+    account_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- exactly one of username / system_name identifies the owner of the account
+    username TEXT UNIQUE,
+    system_name TEXT UNIQUE,
+    -- cached running balance, kept equal to the sum of the postings of the account by the trigger below
+    balance BIGINT NOT NULL DEFAULT 0,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username),
+    CHECK ((username IS NULL) <> (system_name IS NULL))
+);
+
+CREATE TABLE journal_entries (
+    entry_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    kind TEXT NOT NULL,
+    transaction_id uuid,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (transaction_id) REFERENCES transactions(transaction_id)
+);
+
+CREATE TABLE postings (
+    posting_id BIGSERIAL PRIMARY KEY,
+    entry_id uuid NOT NULL,
+    account_id uuid NOT NULL,
+    amount BIGINT NOT NULL CHECK (amount <> 0),
+    -- balance of the account right after this posting
+    balance_after BIGINT NOT NULL DEFAULT 0,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (entry_id) REFERENCES journal_entries(entry_id),
+    FOREIGN KEY (account_id) REFERENCES ledger_accounts(account_id)
+);
+
+CREATE INDEX postings_account_id_idx ON postings(account_id, posting_id);
+CREATE INDEX postings_entry_id_idx ON postings(entry_id);
+CREATE INDEX journal_entries_transaction_id_idx ON journal_entries(transaction_id);
+
+-- Postings are applied to the cached balance as they are written
+CREATE FUNCTION apply_posting() RETURNS trigger AS $$
+BEGIN
+    UPDATE ledger_accounts SET balance = balance + NEW.amount
+    WHERE account_id = NEW.account_id
+    RETURNING balance INTO NEW.balance_after;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER postings_apply BEFORE INSERT ON postings
+    FOR EACH ROW EXECUTE FUNCTION apply_posting();
+
+-- Every journal entry must sum to zero once its database transaction commits
+CREATE FUNCTION check_journal_entry_balanced() RETURNS trigger AS $$
+BEGIN
+    IF (SELECT SUM(amount) FROM postings WHERE entry_id = NEW.entry_id) <> 0 THEN
+        RAISE EXCEPTION 'journal entry % does not sum to zero', NEW.entry_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER postings_balanced AFTER INSERT ON postings
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION check_journal_entry_balanced();
+
+-- The ledger is append only, mistakes are corrected with new entries
+CREATE FUNCTION reject_ledger_modification() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION '% is append only', TG_TABLE_NAME;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER postings_append_only BEFORE UPDATE OR DELETE ON postings
+    FOR EACH ROW EXECUTE FUNCTION reject_ledger_modification();
+
+CREATE TRIGGER journal_entries_append_only BEFORE UPDATE OR DELETE ON journal_entries
+    FOR EACH ROW EXECUTE FUNCTION reject_ledger_modification();
+
+-- Money deposited into the system is posted against this account
+INSERT INTO ledger_accounts(system_name) VALUES ('funding');
+
+-- Carry the existing balances over as opening balances funded by the funding account
+INSERT INTO ledger_accounts(username) SELECT username FROM user_credentials;
+
+CREATE TEMPORARY TABLE opening_balances AS
+    SELECT gen_random_uuid() AS entry_id, ledger_accounts.account_id, user_credentials.balance
+    FROM user_credentials JOIN ledger_accounts USING (username)
+    WHERE user_credentials.balance <> 0;
+
+INSERT INTO journal_entries(entry_id, kind) SELECT entry_id, 'opening_balance' FROM opening_balances;
+
+INSERT INTO postings(entry_id, account_id, amount)
+    SELECT entry_id, account_id, balance FROM opening_balances
+    UNION ALL
+    SELECT entry_id, (SELECT account_id FROM ledger_accounts WHERE system_name = 'funding'), -balance
+    FROM opening_balances;
+
+DROP TABLE opening_balances;
+
+ALTER TABLE user_credentials DROP COLUMN balance;
@@ -0,0 +1,25 @@
+-- Add migration script here
+CREATE TYPE withdrawal_status AS ENUM ('pending', 'processing', 'completed', 'failed');
+
+CREATE TABLE withdrawals (
+    withdrawal_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    amount int NOT NULL CHECK (amount > 0),
+    destination TEXT NOT NULL,
+    status withdrawal_status NOT NULL DEFAULT 'pending',
+    provider_reference TEXT,
+    failure_reason TEXT,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX withdrawals_username_idx ON withdrawals(username);
+
+ALTER TABLE journal_entries ADD COLUMN withdrawal_id uuid REFERENCES withdrawals(withdrawal_id);
+
+-- Funds reserved for payouts that the provider has not confirmed yet
+INSERT INTO ledger_accounts(system_name) VALUES ('payouts_pending');
+-- Funds that left the system through the payout provider
+INSERT INTO ledger_accounts(system_name) VALUES ('payouts');
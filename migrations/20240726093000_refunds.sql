@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TYPE transaction_kind AS ENUM ('transfer', 'refund', 'reversal');
+
+ALTER TABLE transactions
+    ADD COLUMN kind transaction_kind NOT NULL DEFAULT 'transfer',
+    -- the transfer a refund or reversal gives money back for
+    ADD COLUMN original_transaction_id uuid REFERENCES transactions(transaction_id),
+    ADD CHECK ((kind = 'transfer') = (original_transaction_id IS NULL));
+
+CREATE INDEX transactions_original_transaction_id_idx ON transactions(original_transaction_id);
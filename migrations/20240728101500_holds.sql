@@ -0,0 +1,24 @@
+-- Add migration script here
+CREATE TYPE hold_status AS ENUM ('active', 'captured', 'voided', 'expired');
+
+CREATE TABLE holds (
+    hold_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- the user whose funds are held
+    payer TEXT NOT NULL,
+    -- the user who can capture the held funds
+    merchant TEXT NOT NULL,
+    amount int NOT NULL CHECK (amount > 0),
+    status hold_status NOT NULL DEFAULT 'active',
+    captured_amount int,
+    capture_transaction_id uuid REFERENCES transactions(transaction_id),
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (payer) REFERENCES user_credentials(username),
+    FOREIGN KEY (merchant) REFERENCES user_credentials(username),
+    CHECK (captured_amount IS NULL OR captured_amount BETWEEN 1 AND amount)
+);
+
+-- Active holds are summed up every time an account is locked to compute its available balance
+CREATE INDEX holds_active_payer_idx ON holds(payer) WHERE status = 'active';
@@ -0,0 +1,47 @@
+-- Add migration script here
+CREATE TYPE transfer_frequency AS ENUM ('once', 'daily', 'weekly', 'monthly');
+CREATE TYPE insufficient_balance_policy AS ENUM ('retry', 'skip');
+CREATE TYPE scheduled_transfer_status AS ENUM ('active', 'completed', 'cancelled');
+CREATE TYPE scheduled_run_outcome AS ENUM ('succeeded', 'retrying', 'skipped');
+
+CREATE TABLE scheduled_transfers (
+    schedule_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    from_user TEXT NOT NULL,
+    to_user TEXT NOT NULL,
+    amount int NOT NULL CHECK (amount > 0),
+    frequency transfer_frequency NOT NULL,
+    -- occurrences are computed from the start so monthly transfers don't drift
+    start_at timestamptz NOT NULL,
+    end_at timestamptz,
+    max_runs int CHECK (max_runs > 0),
+    on_insufficient_balance insufficient_balance_policy NOT NULL,
+    status scheduled_transfer_status NOT NULL DEFAULT 'active',
+    -- occurrences that were executed or skipped
+    completed_runs int NOT NULL DEFAULT 0,
+    -- failed attempts of the current occurrence
+    attempts int NOT NULL DEFAULT 0,
+    next_run_at timestamptz NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (from_user) REFERENCES user_credentials(username),
+    FOREIGN KEY (to_user) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX scheduled_transfers_due_idx ON scheduled_transfers(next_run_at) WHERE status = 'active';
+CREATE INDEX scheduled_transfers_from_user_idx ON scheduled_transfers(from_user);
+
+CREATE TABLE scheduled_transfer_runs (
+    run_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    schedule_id uuid NOT NULL,
+    -- which occurrence of the schedule this run was for, starting at 0
+    occurrence int NOT NULL,
+    outcome scheduled_run_outcome NOT NULL,
+    transaction_id uuid,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (schedule_id) REFERENCES scheduled_transfers(schedule_id),
+    FOREIGN KEY (transaction_id) REFERENCES transactions(transaction_id)
+);
+
+CREATE INDEX scheduled_transfer_runs_schedule_id_idx ON scheduled_transfer_runs(schedule_id, created_at);
@@ -0,0 +1,24 @@
+-- Add migration script here
+CREATE TYPE payment_request_status AS ENUM ('pending', 'accepted', 'declined', 'cancelled', 'expired');
+
+CREATE TABLE payment_requests (
+    payment_request_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- the user asking to be paid
+    requester TEXT NOT NULL,
+    -- the user asked to pay
+    payer TEXT NOT NULL,
+    amount int NOT NULL CHECK (amount > 0),
+    note TEXT,
+    status payment_request_status NOT NULL DEFAULT 'pending',
+    transaction_id uuid REFERENCES transactions(transaction_id),
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (requester) REFERENCES user_credentials(username),
+    FOREIGN KEY (payer) REFERENCES user_credentials(username),
+    CHECK (requester <> payer)
+);
+
+CREATE INDEX payment_requests_requester_idx ON payment_requests(requester);
+CREATE INDEX payment_requests_payer_idx ON payment_requests(payer);
@@ -0,0 +1,59 @@
+-- Add migration script here
+CREATE TABLE webhook_endpoints (
+    endpoint_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    url TEXT NOT NULL,
+    -- payloads are signed with this secret, so it has to be stored in plain text
+    secret TEXT NOT NULL,
+    -- event types delivered to the endpoint, every event type when empty
+    event_types TEXT[] NOT NULL DEFAULT '{}',
+    active BOOLEAN NOT NULL DEFAULT true,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX webhook_endpoints_username_idx ON webhook_endpoints(username);
+
+-- Outbox of events, written in the same database transaction as the change they describe
+CREATE TABLE webhook_events (
+    event_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE TYPE webhook_delivery_status AS ENUM ('pending', 'succeeded', 'failed');
+
+CREATE TABLE webhook_deliveries (
+    delivery_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    event_id uuid NOT NULL,
+    endpoint_id uuid NOT NULL,
+    status webhook_delivery_status NOT NULL DEFAULT 'pending',
+    attempts int NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (event_id) REFERENCES webhook_events(event_id),
+    FOREIGN KEY (endpoint_id) REFERENCES webhook_endpoints(endpoint_id)
+);
+
+CREATE INDEX webhook_deliveries_due_idx ON webhook_deliveries(next_attempt_at) WHERE status = 'pending';
+CREATE INDEX webhook_deliveries_endpoint_id_idx ON webhook_deliveries(endpoint_id, created_at);
+
+CREATE TABLE webhook_delivery_attempts (
+    attempt_id BIGSERIAL PRIMARY KEY,
+    delivery_id uuid NOT NULL,
+    -- HTTP status returned by the endpoint, NULL if the request failed before a response
+    response_status SMALLINT,
+    error TEXT,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (delivery_id) REFERENCES webhook_deliveries(delivery_id)
+);
+
+CREATE INDEX webhook_delivery_attempts_delivery_id_idx ON webhook_delivery_attempts(delivery_id, attempt_id);
@@ -0,0 +1,18 @@
+-- The webhook outbox also feeds the real-time event stream, so it becomes the general account event log
+ALTER TABLE webhook_events RENAME TO account_events;
+
+-- Orders the events of a user, clients resume the stream from it with Last-Event-ID
+ALTER TABLE account_events ADD COLUMN sequence BIGSERIAL NOT NULL;
+
+CREATE UNIQUE INDEX account_events_username_sequence_idx ON account_events(username, sequence);
+
+-- Wakes the open event streams of the user once the writing transaction commits
+CREATE FUNCTION notify_account_event() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('account_events', NEW.username);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER account_events_notify AFTER INSERT ON account_events
+    FOR EACH ROW EXECUTE FUNCTION notify_account_event();
@@ -0,0 +1,3 @@
+-- Keyset pagination of a user's sent and received transactions
+CREATE INDEX transactions_from_user_created_at_idx ON transactions(from_user, created_at, transaction_id);
+CREATE INDEX transactions_to_user_created_at_idx ON transactions(to_user, created_at, transaction_id);
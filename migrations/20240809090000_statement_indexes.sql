@@ -0,0 +1,2 @@
+-- Statements read the postings of an account for a period
+CREATE INDEX postings_account_id_created_at_idx ON postings(account_id, created_at);
@@ -0,0 +1,29 @@
+-- Refresh tokens rotate on every use, all tokens descending from one login share a family
+CREATE TABLE refresh_tokens (
+    token_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    family_id uuid NOT NULL,
+    username TEXT NOT NULL,
+    -- SHA-256 of the token, the token itself is only known to the client
+    token_hash TEXT NOT NULL UNIQUE,
+    -- the access token issued together with this refresh token, revoked with its family
+    access_jti uuid NOT NULL,
+    access_expires_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    -- set once the token was exchanged, presenting it again means it was stolen
+    used_at timestamptz,
+    revoked_at timestamptz,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX refresh_tokens_family_id_idx ON refresh_tokens(family_id);
+CREATE INDEX refresh_tokens_username_idx ON refresh_tokens(username);
+
+-- Access tokens rejected before they expire, rows can be removed once expires_at passed
+CREATE TABLE revoked_access_tokens (
+    jti uuid PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX revoked_access_tokens_expires_at_idx ON revoked_access_tokens(expires_at);
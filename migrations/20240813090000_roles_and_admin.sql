@@ -0,0 +1,31 @@
+CREATE TYPE user_role AS ENUM ('user', 'support', 'admin', 'auditor');
+
+ALTER TABLE user_credentials ADD COLUMN role user_role NOT NULL DEFAULT 'user';
+
+CREATE TYPE account_status AS ENUM ('active', 'frozen');
+
+ALTER TABLE ledger_accounts ADD COLUMN status account_status NOT NULL DEFAULT 'active';
+
+CREATE TYPE adjustment_reason AS ENUM ('correction', 'goodwill', 'chargeback', 'other');
+
+-- Manual balance corrections made by admins
+CREATE TABLE balance_adjustments (
+    adjustment_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    -- positive amounts credit the user, negative amounts debit them
+    amount INT NOT NULL CHECK (amount <> 0),
+    reason adjustment_reason NOT NULL,
+    note TEXT,
+    created_by TEXT NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username),
+    FOREIGN KEY (created_by) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX balance_adjustments_username_idx ON balance_adjustments(username);
+
+ALTER TABLE journal_entries ADD COLUMN adjustment_id uuid REFERENCES balance_adjustments(adjustment_id);
+
+-- Counterpart of manual adjustments
+INSERT INTO ledger_accounts(system_name) VALUES ('adjustments');
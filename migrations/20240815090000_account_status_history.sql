@@ -0,0 +1,23 @@
+ALTER TYPE account_status ADD VALUE 'closed';
+
+-- Frozen accounts keep receiving money unless this is set
+ALTER TABLE ledger_accounts ADD COLUMN block_incoming BOOLEAN NOT NULL DEFAULT false;
+
+-- Every status change of an account, who made it and why
+CREATE TABLE account_status_history (
+    change_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    status account_status NOT NULL,
+    block_incoming BOOLEAN NOT NULL,
+    reason TEXT NOT NULL,
+    changed_by TEXT NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username),
+    FOREIGN KEY (changed_by) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX account_status_history_username_idx ON account_status_history(username, created_at);
+
+-- Scheduled transfers whose sender or recipient account is locked
+ALTER TYPE scheduled_run_outcome ADD VALUE 'blocked';
@@ -0,0 +1,18 @@
+-- Long-lived credentials for server-to-server clients
+CREATE TABLE api_keys (
+    key_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- first characters of the key, shown to tell keys apart
+    prefix TEXT NOT NULL,
+    -- SHA-256 of the key, the key itself is only shown once
+    key_hash TEXT NOT NULL UNIQUE,
+    expires_at timestamptz,
+    last_used_at timestamptz,
+    revoked_at timestamptz,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX api_keys_username_idx ON api_keys(username, created_at);
@@ -0,0 +1,39 @@
+-- TOTP second factor of a user, usable once confirmed with a first code
+CREATE TABLE user_totp (
+    username TEXT PRIMARY KEY,
+    -- base32 shared secret, needed in clear to compute the expected codes
+    secret TEXT NOT NULL,
+    confirmed_at timestamptz,
+    -- time step of the last accepted code, so a code can't be used twice
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    -- transfers above this amount need a fresh code, the configured default when NULL
+    step_up_threshold INT CHECK (step_up_threshold >= 0),
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+-- Single use codes for when the authenticator is lost, stored as SHA-256 digests
+CREATE TABLE totp_recovery_codes (
+    code_hash TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    used_at timestamptz,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX totp_recovery_codes_username_idx ON totp_recovery_codes(username);
+
+-- Logins of users with a second factor that still wait for a code
+CREATE TABLE login_challenges (
+    challenge_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    attempts INT NOT NULL DEFAULT 0,
+    expires_at timestamptz NOT NULL,
+    completed_at timestamptz,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
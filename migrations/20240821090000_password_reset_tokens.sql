@@ -0,0 +1,12 @@
+-- Single use tokens of the password reset flow, stored as SHA-256 digests
+CREATE TABLE password_reset_tokens (
+    token_hash TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    expires_at timestamptz NOT NULL,
+    used_at timestamptz,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX password_reset_tokens_username_idx ON password_reset_tokens(username);
@@ -0,0 +1,15 @@
+CREATE TYPE login_outcome AS ENUM ('succeeded', 'failed', 'throttled');
+
+-- Every password login, also for usernames that don't exist. Failed attempts drive the
+-- per-username and per-IP throttling, so there is no foreign key on the username
+CREATE TABLE login_attempts (
+    attempt_id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip_address TEXT NOT NULL,
+    user_agent TEXT,
+    outcome login_outcome NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX login_attempts_username_idx ON login_attempts(username, created_at);
+CREATE INDEX login_attempts_ip_address_idx ON login_attempts(ip_address, created_at);
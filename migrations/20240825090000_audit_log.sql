@@ -0,0 +1,41 @@
+CREATE TYPE audit_action AS ENUM (
+    'login_succeeded',
+    'login_failed',
+    'signup',
+    'deposit',
+    'transfer',
+    'role_change',
+    'status_change'
+);
+
+-- Security and financial events for reconstructing incidents. Rows are only ever inserted,
+-- the triggers below reject every update, delete and truncate
+CREATE TABLE audit_log (
+    audit_id BIGSERIAL PRIMARY KEY,
+    -- The user acting, or the username tried for failed logins
+    actor TEXT,
+    action audit_action NOT NULL,
+    -- The user or object acted on
+    target TEXT,
+    request_id TEXT,
+    ip_address TEXT,
+    before JSONB,
+    after JSONB,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX audit_log_actor_idx ON audit_log(actor, audit_id);
+CREATE INDEX audit_log_target_idx ON audit_log(target, audit_id);
+CREATE INDEX audit_log_action_idx ON audit_log(action, audit_id);
+
+CREATE FUNCTION reject_audit_log_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only, % is not allowed', TG_OP;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION reject_audit_log_change();
+
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION reject_audit_log_change();
@@ -0,0 +1,35 @@
+-- Limits shared by a group of users. A NULL limit doesn't apply, daily, weekly and monthly
+-- totals are summed over the last 24 hours, 7 days and 30 days
+CREATE TABLE limit_tiers (
+    tier TEXT PRIMARY KEY,
+    max_transfer_amount BIGINT CHECK (max_transfer_amount >= 0),
+    daily_outgoing BIGINT CHECK (daily_outgoing >= 0),
+    weekly_outgoing BIGINT CHECK (weekly_outgoing >= 0),
+    monthly_outgoing BIGINT CHECK (monthly_outgoing >= 0),
+    hourly_transfers BIGINT CHECK (hourly_transfers >= 0),
+    daily_deposit BIGINT CHECK (daily_deposit >= 0),
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL
+);
+
+-- Users without a row here are on the standard tier
+INSERT INTO limit_tiers(tier, max_transfer_amount, daily_outgoing, weekly_outgoing,
+    monthly_outgoing, hourly_transfers, daily_deposit)
+VALUES ('standard', 500000, 1000000, 2500000, 5000000, 30, 1000000);
+
+-- Tier of a user and limits overriding the ones of the tier, NULL keeps the limit of the tier
+CREATE TABLE user_limits (
+    username TEXT PRIMARY KEY,
+    tier TEXT NOT NULL,
+    max_transfer_amount BIGINT CHECK (max_transfer_amount >= 0),
+    daily_outgoing BIGINT CHECK (daily_outgoing >= 0),
+    weekly_outgoing BIGINT CHECK (weekly_outgoing >= 0),
+    monthly_outgoing BIGINT CHECK (monthly_outgoing >= 0),
+    hourly_transfers BIGINT CHECK (hourly_transfers >= 0),
+    daily_deposit BIGINT CHECK (daily_deposit >= 0),
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username),
+    FOREIGN KEY (tier) REFERENCES limit_tiers(tier)
+);
+
+ALTER TYPE audit_action ADD VALUE 'limit_change';
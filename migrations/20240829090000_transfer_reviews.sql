@@ -0,0 +1,25 @@
+CREATE TYPE transfer_review_status AS ENUM ('pending', 'approved', 'rejected');
+
+-- Transfers a risk rule held back until the operations team approves or rejects them
+CREATE TABLE transfer_reviews (
+    review_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    from_user TEXT NOT NULL,
+    to_user TEXT NOT NULL,
+    amount INT NOT NULL CHECK (amount > 0),
+    rule TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    status transfer_review_status NOT NULL DEFAULT 'pending',
+    transaction_id uuid REFERENCES transactions(transaction_id),
+    decided_by TEXT,
+    decision_note TEXT,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    decided_at timestamptz,
+
+    FOREIGN KEY (from_user) REFERENCES user_credentials(username),
+    FOREIGN KEY (to_user) REFERENCES user_credentials(username),
+    FOREIGN KEY (decided_by) REFERENCES user_credentials(username)
+);
+
+CREATE INDEX transfer_reviews_status_idx ON transfer_reviews(status, created_at);
+
+ALTER TYPE audit_action ADD VALUE 'transfer_review';
@@ -0,0 +1,29 @@
+CREATE TYPE fee_operation AS ENUM ('transfer', 'deposit', 'withdrawal');
+
+-- Fees charged for a money movement. A schedule applies to amounts from min_amount up to the
+-- min_amount of the next schedule of the same operation and tier. Schedules of the tier of a
+-- user win over the ones without a tier. The fee is flat_fee plus percent_bps hundredths of a
+-- percent of the amount, clamped to min_fee and max_fee
+CREATE TABLE fee_schedules (
+    schedule_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    operation fee_operation NOT NULL,
+    tier TEXT,
+    min_amount INT NOT NULL DEFAULT 0 CHECK (min_amount >= 0),
+    flat_fee INT NOT NULL DEFAULT 0 CHECK (flat_fee >= 0),
+    percent_bps INT NOT NULL DEFAULT 0 CHECK (percent_bps BETWEEN 0 AND 10000),
+    min_fee INT CHECK (min_fee >= 0),
+    max_fee INT CHECK (max_fee >= 0),
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (tier) REFERENCES limit_tiers(tier),
+    UNIQUE NULLS NOT DISTINCT (operation, tier, min_amount),
+    CHECK (min_fee <= max_fee)
+);
+
+-- System account the fees are credited to
+INSERT INTO ledger_accounts(system_name) VALUES ('revenue');
+
+ALTER TABLE transactions ADD COLUMN fee INT NOT NULL DEFAULT 0 CHECK (fee >= 0);
+ALTER TABLE withdrawals ADD COLUMN fee INT NOT NULL DEFAULT 0 CHECK (fee >= 0);
+
+ALTER TYPE audit_action ADD VALUE 'fee_change';
@@ -0,0 +1,51 @@
+-- Annual interest rate paid on the balances of a tier, in hundredths of a percent
+CREATE TABLE interest_rates (
+    tier TEXT PRIMARY KEY,
+    annual_rate_bps INT NOT NULL CHECK (annual_rate_bps BETWEEN 0 AND 10000),
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (tier) REFERENCES limit_tiers(tier)
+);
+
+-- Interest earned on the closing balance of a day, in millionths of a minor unit so the
+-- fractions of a day add up instead of being rounded away
+CREATE TABLE interest_accruals (
+    username TEXT NOT NULL,
+    accrual_date DATE NOT NULL,
+    balance BIGINT NOT NULL,
+    annual_rate_bps INT NOT NULL,
+    amount_micros BIGINT NOT NULL CHECK (amount_micros >= 0),
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    PRIMARY KEY (username, accrual_date),
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+-- Interest paid for a month, period is the first day of the month. Whole minor units are paid,
+-- the fraction left over is paid with a later month
+CREATE TABLE interest_payouts (
+    payout_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    username TEXT NOT NULL,
+    period DATE NOT NULL,
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    UNIQUE (username, period),
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
+
+ALTER TABLE journal_entries ADD COLUMN interest_payout_id uuid REFERENCES interest_payouts(payout_id);
+
+-- What the interest job did for a day, summed over all its runs
+CREATE TABLE interest_runs (
+    run_date DATE PRIMARY KEY,
+    accruals BIGINT NOT NULL,
+    payouts BIGINT NOT NULL,
+    paid_out BIGINT NOT NULL,
+    completed_at timestamptz default CURRENT_TIMESTAMP NOT NULL
+);
+
+-- System account interest is paid from
+INSERT INTO ledger_accounts(system_name) VALUES ('interest_expense');
+
+ALTER TYPE audit_action ADD VALUE 'interest_rate_change';
@@ -0,0 +1,35 @@
+CREATE TYPE account_type AS ENUM ('personal', 'merchant');
+
+-- Merchant accounts accept payments through checkout sessions and carry the name payers see
+ALTER TABLE user_credentials ADD COLUMN account_type account_type NOT NULL DEFAULT 'personal';
+ALTER TABLE user_credentials ADD COLUMN business_name TEXT;
+ALTER TABLE user_credentials ADD CONSTRAINT user_credentials_business_name_check
+    CHECK ((account_type = 'merchant') = (business_name IS NOT NULL));
+
+CREATE TYPE checkout_session_status AS ENUM ('open', 'paid', 'cancelled', 'expired');
+
+-- A payment a merchant asks for, paid by whichever wallet user confirms it first
+CREATE TABLE checkout_sessions (
+    session_id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    merchant TEXT NOT NULL,
+    amount INT NOT NULL CHECK (amount > 0),
+    -- the merchant's own reference, e.g. an order number
+    reference TEXT NOT NULL,
+    success_url TEXT NOT NULL,
+    cancel_url TEXT NOT NULL,
+    status checkout_session_status NOT NULL DEFAULT 'open',
+    payer TEXT,
+    transaction_id uuid REFERENCES transactions(transaction_id),
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (merchant) REFERENCES user_credentials(username),
+    FOREIGN KEY (payer) REFERENCES user_credentials(username),
+    CHECK (payer <> merchant)
+);
+
+CREATE INDEX checkout_sessions_merchant_idx ON checkout_sessions(merchant, created_at);
+CREATE INDEX checkout_sessions_open_idx ON checkout_sessions(expires_at) WHERE status = 'open';
+
+ALTER TYPE audit_action ADD VALUE 'account_type_change';
@@ -0,0 +1,11 @@
+-- Failed second factor checks of a user in a row, across logins, step-ups and changes of the
+-- two-factor settings. Written outside the database transaction of the request, so failures
+-- also count when the request is rolled back
+CREATE TABLE totp_failures (
+    username TEXT PRIMARY KEY,
+    failed_attempts INT NOT NULL DEFAULT 0,
+    -- no code is checked until then
+    locked_until timestamptz,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
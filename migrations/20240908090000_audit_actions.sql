@@ -0,0 +1,5 @@
+ALTER TYPE audit_action ADD VALUE 'withdrawal';
+ALTER TYPE audit_action ADD VALUE 'balance_adjustment';
+ALTER TYPE audit_action ADD VALUE 'password_change';
+ALTER TYPE audit_action ADD VALUE 'password_reset';
+ALTER TYPE audit_action ADD VALUE 'two_factor_change';
@@ -0,0 +1,8 @@
+-- Interest accrued but not paid yet when the account was closed. It is never paid out
+CREATE TABLE interest_forfeitures (
+    username TEXT PRIMARY KEY,
+    amount_micros BIGINT NOT NULL CHECK (amount_micros >= 0),
+    created_at timestamptz default CURRENT_TIMESTAMP NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES user_credentials(username)
+);
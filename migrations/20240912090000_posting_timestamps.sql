@@ -0,0 +1,12 @@
+-- Postings are stamped when they are applied, while the balance of their account is locked.
+-- The postings of an account are then in the same order by time as by their balance_after,
+-- so a balance at a point in time is the balance_after of the last posting before it
+CREATE OR REPLACE FUNCTION apply_posting() RETURNS trigger AS $$
+BEGIN
+    UPDATE ledger_accounts SET balance = balance + NEW.amount
+    WHERE account_id = NEW.account_id
+    RETURNING balance INTO NEW.balance_after;
+    NEW.created_at := clock_timestamp();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1 @@
+ALTER TYPE webhook_delivery_status ADD VALUE 'cancelled';
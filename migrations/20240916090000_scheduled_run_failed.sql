@@ -0,0 +1 @@
+ALTER TYPE scheduled_run_outcome ADD VALUE 'failed';
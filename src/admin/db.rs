@@ -0,0 +1,318 @@
+use serde_json::json;
+use sqlx::PgConnection;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+    ledger::{AccountStatus, EntryKind, EntryReference, ADJUSTMENTS_ACCOUNT},
+    user::{AccountType, Role},
+};
+
+use super::{
+    AccountStatusChange, AccountTypeRequest, AdjustmentOutcome, AdjustmentReason,
+    AdjustmentRequest, AdminUser, BalanceAdjustment, StatusChangeOutcome,
+};
+
+impl Db {
+    pub async fn search_users(&self, prefix: &str) -> sqlx::Result<Vec<AdminUser>> {
+        sqlx::query_as!(
+            AdminUser,
+            r#"SELECT user_credentials.username, user_credentials.role AS "role: Role",
+            user_credentials.account_type AS "account_type: AccountType",
+            user_credentials.business_name, ledger_accounts.status AS "status: AccountStatus", ledger_accounts.block_incoming,
+            ledger_accounts.balance AS ledger_balance,
+            user_credentials.created_at
+            FROM user_credentials JOIN ledger_accounts USING (username)
+            WHERE starts_with(user_credentials.username, $1)
+            ORDER BY user_credentials.username
+            LIMIT 100"#,
+            prefix
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_admin_user(&self, username: &str) -> sqlx::Result<Option<AdminUser>> {
+        sqlx::query_as!(
+            AdminUser,
+            r#"SELECT user_credentials.username, user_credentials.role AS "role: Role",
+            user_credentials.account_type AS "account_type: AccountType",
+            user_credentials.business_name, ledger_accounts.status AS "status: AccountStatus", ledger_accounts.block_incoming,
+            ledger_accounts.balance AS ledger_balance,
+            user_credentials.created_at
+            FROM user_credentials JOIN ledger_accounts USING (username)
+            WHERE user_credentials.username = $1"#,
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Changes the role of the user and returns the role they had before,
+    /// None if the user doesn't exist
+    pub async fn set_user_role(
+        &self,
+        username: &str,
+        role: Role,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<Option<Role>> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(previous_role) = sqlx::query!(
+            r#"SELECT role AS "role: Role" FROM user_credentials WHERE username = $1 FOR UPDATE"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .map(|record| record.role) else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            "UPDATE user_credentials SET role = $2 WHERE username = $1",
+            username,
+            role as Role
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::RoleChange,
+                target: Some(username),
+                before: Some(json!({ "role": previous_role })),
+                after: Some(json!({ "role": role })),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(Some(previous_role))
+    }
+
+    /// Changes the type of the user's account, returns false if the user doesn't exist
+    pub async fn set_account_type(
+        &self,
+        username: &str,
+        account_type_request: &AccountTypeRequest,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<bool> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(previous) = sqlx::query_as!(
+            AccountTypeRequest,
+            r#"SELECT account_type AS "account_type: AccountType", business_name
+            FROM user_credentials WHERE username = $1 FOR UPDATE"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        sqlx::query!(
+            "UPDATE user_credentials SET account_type = $2, business_name = $3 WHERE username = $1",
+            username,
+            account_type_request.account_type as AccountType,
+            account_type_request.business_name
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::AccountTypeChange,
+                target: Some(username),
+                before: Some(json!(previous)),
+                after: Some(json!(account_type_request)),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Changes the status of the user's account and records who changed it and why.
+    /// The account row is locked like for any money movement, so movements that already
+    /// passed the status check are written before the new status applies.
+    pub async fn set_account_status(
+        &self,
+        username: &str,
+        status: AccountStatus,
+        block_incoming: bool,
+        reason: &str,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<StatusChangeOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(account) = sqlx::query!(
+            r#"SELECT status AS "status: AccountStatus", block_incoming, balance FROM ledger_accounts
+            WHERE username = $1 FOR UPDATE"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(StatusChangeOutcome::UserNotFound);
+        };
+
+        if account.status == AccountStatus::Closed {
+            return Ok(StatusChangeOutcome::AlreadyClosed);
+        }
+
+        if status == AccountStatus::Closed && account.balance != 0 {
+            return Ok(StatusChangeOutcome::BalanceNotZero);
+        }
+
+        // Closed accounts are not paid interest anymore, so closing gives up what is still unpaid
+        let forfeited_interest_micros = match status {
+            AccountStatus::Closed => {
+                Some(Self::forfeit_interest(&mut transaction, username).await?)
+            }
+            _ => None,
+        };
+
+        sqlx::query!(
+            "UPDATE ledger_accounts SET status = $2, block_incoming = $3 WHERE username = $1",
+            username,
+            status as AccountStatus,
+            block_incoming
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO account_status_history(username, status, block_incoming, reason, changed_by)
+            VALUES($1, $2, $3, $4, $5)",
+            username,
+            status as AccountStatus,
+            block_incoming,
+            reason,
+            changed_by
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::StatusChange,
+                target: Some(username),
+                before: Some(json!({
+                    "status": account.status,
+                    "block_incoming": account.block_incoming
+                })),
+                after: Some(json!({
+                    "status": status,
+                    "block_incoming": block_incoming,
+                    "reason": reason,
+                    "forfeited_interest_micros": forfeited_interest_micros
+                })),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(StatusChangeOutcome::Changed)
+    }
+
+    /// Status changes of the user's account, newest first
+    pub async fn get_account_status_history(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<AccountStatusChange>> {
+        sqlx::query_as!(
+            AccountStatusChange,
+            r#"SELECT change_id, username, status AS "status: AccountStatus", block_incoming, reason,
+            changed_by, created_at
+            FROM account_status_history WHERE username = $1
+            ORDER BY created_at DESC"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Records an adjustment and posts it against the adjustments account inside an already
+    /// open database transaction. Debits can't take more than the available balance
+    pub async fn adjust_balance(
+        conn: &mut PgConnection,
+        username: &str,
+        admin: &str,
+        adjustment_request: &AdjustmentRequest,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<AdjustmentOutcome> {
+        let Some(account) = Self::lock_user_accounts(&mut *conn, &[username])
+            .await?
+            .pop()
+        else {
+            return Ok(AdjustmentOutcome::UserNotFound);
+        };
+
+        if account.available + (adjustment_request.amount as i64) < 0 {
+            return Ok(AdjustmentOutcome::InsufficientBalance);
+        }
+
+        let adjustment = sqlx::query_as!(
+            BalanceAdjustment,
+            r#"INSERT INTO balance_adjustments(username, amount, reason, note, created_by)
+            VALUES($1, $2, $3, $4, $5)
+            RETURNING adjustment_id, username, amount, reason AS "reason: AdjustmentReason", note,
+            created_by, created_at"#,
+            username,
+            adjustment_request.amount,
+            adjustment_request.reason as AdjustmentReason,
+            adjustment_request.note,
+            admin
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let adjustments_account_id =
+            Self::system_account_id(&mut *conn, ADJUSTMENTS_ACCOUNT).await?;
+
+        Self::post_journal_entry(
+            &mut *conn,
+            EntryKind::Adjustment,
+            EntryReference::Adjustment(adjustment.adjustment_id),
+            &[
+                (account.account_id, adjustment.amount as i64),
+                (adjustments_account_id, -(adjustment.amount as i64)),
+            ],
+        )
+        .await?;
+
+        Self::record_audit(
+            &mut *conn,
+            audit_context,
+            AuditEntry {
+                actor: Some(admin),
+                action: AuditAction::BalanceAdjustment,
+                target: Some(username),
+                before: Some(json!({ "balance": account.balance })),
+                after: Some(json!({
+                    "balance": account.balance + adjustment.amount as i64,
+                    "adjustment_id": adjustment.adjustment_id,
+                    "amount": adjustment.amount,
+                    "reason": adjustment.reason,
+                })),
+            },
+        )
+        .await?;
+
+        Ok(AdjustmentOutcome::Adjusted(adjustment))
+    }
+}
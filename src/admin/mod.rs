@@ -0,0 +1,610 @@
+mod db;
+
+use axum::{
+    extract::{Path, Query, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    ledger::AccountStatus,
+    user::{AccountType, Role},
+    utils::{AdminRole, OperatorRole, RoleInfo, StaffRole},
+};
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/users", get(search_users))
+        .route("/users/:username", get(get_user))
+        .route("/users/:username/role", put(set_user_role))
+        .route("/users/:username/account-type", put(set_account_type))
+        .route("/users/:username/freeze", post(freeze_account))
+        .route("/users/:username/unfreeze", post(unfreeze_account))
+        .route("/users/:username/close", post(close_account))
+        .route("/users/:username/status-history", get(get_status_history))
+        .route("/users/:username/adjustments", post(adjust_balance))
+        .route("/transactions/:id", get(get_any_transaction))
+        .with_state(app_state)
+}
+
+///Users whose username starts with the given prefix
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "Admin",
+    params(UserSearchQuery),
+    responses(
+        (status = 200, description = "Users successfully retreived", body = Vec<AdminUser>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn search_users(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Query(query): Query<UserSearchQuery>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(
+        db.search_users(query.prefix.as_deref().unwrap_or_default())
+            .await?,
+    ))
+}
+
+///Account details of any User
+#[utoipa::path(
+    get,
+    path = "/admin/users/{username}",
+    tag = "Admin",
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "User successfully retreived", body = AdminUser),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 404, description = "User not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn get_user(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Path(username): Path<String>,
+) -> AppResult<impl IntoResponse> {
+    match db.get_admin_user(&username).await? {
+        Some(user) => Ok(Json(user).into_response()),
+        None => Ok((http::StatusCode::NOT_FOUND, "User not found").into_response()),
+    }
+}
+
+///Changes the role of a User. The user's tokens are revoked so the new role applies right away
+#[utoipa::path(
+    put,
+    path = "/admin/users/{username}/role",
+    tag = "Admin",
+    request_body = RoleRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Role successfully changed"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "User not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_user_role(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(role_request): Json<RoleRequest>,
+) -> AppResult<impl IntoResponse> {
+    if db
+        .set_user_role(&username, role_request.role, &admin, &audit_context)
+        .await?
+        .is_none()
+    {
+        return Ok((http::StatusCode::NOT_FOUND, "User not found").into_response());
+    }
+    db.revoke_user_tokens(&username).await?;
+
+    Ok(http::StatusCode::OK.into_response())
+}
+
+///Turns the account of a User into a merchant account or back into a personal one.
+///Open checkout sessions of a former merchant can still be paid
+#[utoipa::path(
+    put,
+    path = "/admin/users/{username}/account-type",
+    tag = "Admin",
+    request_body = AccountTypeRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Account type successfully changed"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "User not found"),
+        (status = 422, description = "Merchant accounts need a business name and personal accounts can't have one"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_account_type(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(account_type_request): Json<AccountTypeRequest>,
+) -> AppResult<impl IntoResponse> {
+    account_type_request.validate()?;
+    if (account_type_request.account_type == AccountType::Merchant)
+        != account_type_request.business_name.is_some()
+    {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "Merchant accounts need a business name and personal accounts can't have one",
+        )
+            .into_response());
+    }
+
+    if !db
+        .set_account_type(&username, &account_type_request, &admin, &audit_context)
+        .await?
+    {
+        return Ok((http::StatusCode::NOT_FOUND, "User not found").into_response());
+    }
+
+    Ok(http::StatusCode::OK.into_response())
+}
+
+///Freezes the account of a User. A frozen account can't send, deposit or withdraw money and
+///only receives transfers unless incoming transfers are blocked too
+#[utoipa::path(
+    post,
+    path = "/admin/users/{username}/freeze",
+    tag = "Admin",
+    request_body = FreezeRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Account successfully frozen"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support or admin"),
+        (status = 404, description = "User not found"),
+        (status = 409, description = "The account is closed"),
+        (status = 422, description = "Invalid reason"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn freeze_account(
+    State(db): State<Db>,
+    RoleInfo {
+        username: operator, ..
+    }: RoleInfo<OperatorRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(freeze_request): Json<FreezeRequest>,
+) -> AppResult<impl IntoResponse> {
+    freeze_request.validate()?;
+
+    let outcome = db
+        .set_account_status(
+            &username,
+            AccountStatus::Frozen,
+            freeze_request.block_incoming,
+            &freeze_request.reason,
+            &operator,
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Lifts the freeze of an account
+#[utoipa::path(
+    post,
+    path = "/admin/users/{username}/unfreeze",
+    tag = "Admin",
+    request_body = StatusChangeRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Account successfully unfrozen"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support or admin"),
+        (status = 404, description = "User not found"),
+        (status = 409, description = "The account is closed"),
+        (status = 422, description = "Invalid reason"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn unfreeze_account(
+    State(db): State<Db>,
+    RoleInfo {
+        username: operator, ..
+    }: RoleInfo<OperatorRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(status_change_request): Json<StatusChangeRequest>,
+) -> AppResult<impl IntoResponse> {
+    status_change_request.validate()?;
+
+    let outcome = db
+        .set_account_status(
+            &username,
+            AccountStatus::Active,
+            false,
+            &status_change_request.reason,
+            &operator,
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Closes the account of a User for good. Only accounts without any money left can be closed.
+///Interest accrued and not paid out yet is forfeited
+#[utoipa::path(
+    post,
+    path = "/admin/users/{username}/close",
+    tag = "Admin",
+    request_body = StatusChangeRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Account successfully closed"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "User not found"),
+        (status = 409, description = "The account is already closed or its balance is not zero"),
+        (status = 422, description = "Invalid reason"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn close_account(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(status_change_request): Json<StatusChangeRequest>,
+) -> AppResult<impl IntoResponse> {
+    status_change_request.validate()?;
+
+    let outcome = db
+        .set_account_status(
+            &username,
+            AccountStatus::Closed,
+            true,
+            &status_change_request.reason,
+            &admin,
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Status changes of a User's account with who made them and why, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/users/{username}/status-history",
+    tag = "Admin",
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Status history successfully retreived", body = Vec<AccountStatusChange>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn get_status_history(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Path(username): Path<String>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_account_status_history(&username).await?))
+}
+
+///Credits or debits a User's balance outside of the normal money movements.
+///Every adjustment needs a reason code and is posted against the adjustments ledger account
+#[utoipa::path(
+    post,
+    path = "/admin/users/{username}/adjustments",
+    tag = "Admin",
+    request_body = AdjustmentRequest,
+    params(
+        ("username" = String, Path, description = "Username"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of adjusting again")
+    ),
+    responses(
+        (status = 201, description = "Balance successfully adjusted", body = BalanceAdjustment),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 402, description = "A debit exceeds the available balance"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "User not found"),
+        (status = 422, description = "Invalid adjustment or Idempotency-Key was already used with a different request"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn adjust_balance(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(adjustment_request): Json<AdjustmentRequest>,
+) -> AppResult<impl IntoResponse> {
+    adjustment_request.validate()?;
+    if adjustment_request.amount == 0 {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "The amount of an adjustment can't be 0",
+        )
+            .into_response());
+    }
+
+    let fingerprint = request_fingerprint(
+        &format!("POST /admin/users/{username}/adjustments"),
+        &adjustment_request,
+    )?;
+
+    let mut response = run_idempotent(&db, &admin, idempotency_key, fingerprint, |conn, admin| {
+        Box::pin(async move {
+            let outcome =
+                Db::adjust_balance(conn, &username, admin, &adjustment_request, &audit_context)
+                    .await?;
+
+            Ok(outcome.into_response_parts()?)
+        })
+    })
+    .await?;
+
+    // Stored responses are replayed as plain text, only the created adjustment is JSON
+    if response.status() == http::StatusCode::CREATED {
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+    }
+
+    Ok(response)
+}
+
+///Any transaction, regardless of who sent or received it
+#[utoipa::path(
+    get,
+    path = "/admin/transactions/{id}",
+    tag = "Admin",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Transaction id")
+    ),
+    responses(
+        (status = 200, description = "Transacion successfully retreived", body = Transaction),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 404, description = "Transaction not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn get_any_transaction(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    match db.get_transaction(id).await {
+        Ok(transaction) => Ok(Json(transaction).into_response()),
+        Err(sqlx::Error::RowNotFound) => {
+            Ok((http::StatusCode::NOT_FOUND, "Transaction not found").into_response())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct UserSearchQuery {
+    /// Every user when left empty
+    pub prefix: Option<String>,
+}
+
+/// A user as seen by the operations team
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AdminUser {
+    pub username: String,
+    pub role: Role,
+    pub account_type: AccountType,
+    /// Only set for merchant accounts
+    pub business_name: Option<String>,
+    pub status: AccountStatus,
+    /// Whether the frozen account refuses incoming transfers too
+    pub block_incoming: bool,
+    pub ledger_balance: i64,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RoleRequest {
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct AccountTypeRequest {
+    pub account_type: AccountType,
+    /// Shown to payers, required for merchant accounts
+    #[validate(length(min = 1, max = 100))]
+    pub business_name: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub(crate) struct FreezeRequest {
+    #[validate(length(min = 1, max = 500))]
+    pub reason: String,
+    /// Refuse incoming transfers as well
+    #[serde(default)]
+    pub block_incoming: bool,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub(crate) struct StatusChangeRequest {
+    #[validate(length(min = 1, max = 500))]
+    pub reason: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AccountStatusChange {
+    pub change_id: Uuid,
+    pub username: String,
+    pub status: AccountStatus,
+    pub block_incoming: bool,
+    pub reason: String,
+    pub changed_by: String,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+pub(crate) enum StatusChangeOutcome {
+    Changed,
+    UserNotFound,
+    /// Closing is final
+    AlreadyClosed,
+    /// Only accounts without money can be closed
+    BalanceNotZero,
+}
+
+impl StatusChangeOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            StatusChangeOutcome::Changed => (http::StatusCode::OK, String::new()),
+            StatusChangeOutcome::UserNotFound => {
+                (http::StatusCode::NOT_FOUND, "User not found".to_string())
+            }
+            StatusChangeOutcome::AlreadyClosed => (
+                http::StatusCode::CONFLICT,
+                "The account is closed".to_string(),
+            ),
+            StatusChangeOutcome::BalanceNotZero => (
+                http::StatusCode::CONFLICT,
+                "Only accounts with a balance of zero can be closed".to_string(),
+            ),
+        }
+    }
+}
+
+/// Stored as the `adjustment_reason` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "adjustment_reason", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AdjustmentReason {
+    /// Fixes a booking error
+    Correction,
+    /// Compensates the user
+    Goodwill,
+    /// Money the user's bank pulled back
+    Chargeback,
+    /// Explained in the note
+    Other,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct AdjustmentRequest {
+    /// Positive amounts credit the user, negative amounts debit them
+    pub amount: i32,
+    pub reason: AdjustmentReason,
+    #[validate(length(max = 500))]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BalanceAdjustment {
+    pub adjustment_id: Uuid,
+    pub username: String,
+    pub amount: i32,
+    pub reason: AdjustmentReason,
+    pub note: Option<String>,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+pub(crate) enum AdjustmentOutcome {
+    Adjusted(BalanceAdjustment),
+    UserNotFound,
+    InsufficientBalance,
+}
+
+impl AdjustmentOutcome {
+    fn into_response_parts(self) -> anyhow::Result<(http::StatusCode, String)> {
+        Ok(match self {
+            AdjustmentOutcome::Adjusted(adjustment) => (
+                http::StatusCode::CREATED,
+                serde_json::to_string(&adjustment)?,
+            ),
+            AdjustmentOutcome::UserNotFound => {
+                (http::StatusCode::NOT_FOUND, "User not found".to_string())
+            }
+            AdjustmentOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "The debit exceeds the available balance of the user".to_string(),
+            ),
+        })
+    }
+}
@@ -1,12 +1,49 @@
 use utoipa::{
-    openapi::security::{Http, SecurityScheme},
+    openapi::security::{ApiKey, ApiKeyValue, Http, SecurityScheme},
     Modify, OpenApi,
 };
 
 use crate::{
-    balance::DepositAmount,
-    transaction::{Transaction, TransactionRequest},
-    user::UserCredentials,
+    admin::{
+        AccountStatusChange, AccountTypeRequest, AdjustmentReason, AdjustmentRequest, AdminUser,
+        BalanceAdjustment, FreezeRequest, RoleRequest, StatusChangeRequest,
+    },
+    api_key::{ApiKeyNameRequest, ApiKeyRequest, CreatedApiKey, API_KEY_HEADER},
+    audit::{AuditAction, AuditRecord},
+    balance::{Balance, DepositAmount, HistoryEntry},
+    checkout::{CheckoutSession, CheckoutSessionStatus, CreateCheckoutSession},
+    fee::{FeeOperation, FeeQuote, FeeSchedule, FeeScheduleRequest},
+    hold::{CaptureRequest, Hold, HoldRequest, HoldStatus},
+    interest::{
+        InterestPayout, InterestRate, InterestRateRequest, InterestRun, InterestRunRecord,
+        InterestRunRequest, InterestSummary,
+    },
+    ledger::AccountStatus,
+    limit::{Allowance, LimitKind, LimitTier, LimitWindow, Limits, UserLimitsRequest},
+    login_attempt::{LoginAttempt, LoginOutcome},
+    payment_request::{CreatePaymentRequest, PaymentRequest, PaymentRequestStatus},
+    risk::{ReviewDecision, ReviewStatus, RuleAction, RuleConfig, TransferReview},
+    schedule::{
+        Frequency, InsufficientBalancePolicy, RunOutcome, ScheduledTransfer,
+        ScheduledTransferRequest, ScheduledTransferRun, ScheduledTransferStatus,
+    },
+    statement::StatementFormat,
+    totp::{CodeRequest, RecoveryCodes, SecondFactor, StepUpRequest, TotpEnrollment, TotpStatus},
+    transaction::{
+        BatchItemError, BatchItemResult, BatchMode, BatchRequest, BatchResponse, Direction,
+        RefundRequest, SortOrder, Transaction, TransactionKind, TransactionPage,
+        TransactionRequest,
+    },
+    user::{
+        AccountType, LoginChallenge, LoginChallengeRequest, PasswordChangeRequest,
+        PasswordResetConfirmation, PasswordResetRequest, RefreshRequest, Role, TokenPair,
+        UserCredentials,
+    },
+    webhook::{
+        CreatedWebhookEndpoint, DeliveryStatus, EventType, WebhookDelivery, WebhookDeliveryAttempt,
+        WebhookEndpoint, WebhookEndpointRequest,
+    },
+    withdrawal::{Withdrawal, WithdrawalRequest, WithdrawalStatus},
 };
 
 #[derive(OpenApi)]
@@ -14,19 +51,196 @@ use crate::{
     paths(
         crate::user::signup,
         crate::user::login,
+        crate::user::complete_login,
         crate::user::whoami,
+        crate::user::refresh,
+        crate::user::logout,
+        crate::user::logout_all,
+        crate::user::change_password,
+        crate::user::request_password_reset,
+        crate::user::reset_password,
+        crate::login_attempt::login_attempts_list,
         crate::transaction::create_transaction,
+        crate::transaction::create_batch,
         crate::transaction::get_transaction_by_id,
         crate::transaction::transactions_list,
+        crate::transaction::refund_transaction,
+        crate::transaction::reverse_transaction,
         crate::balance::deposit,
         crate::balance::get_balance,
+        crate::balance::balance_history,
+        crate::withdrawal::withdraw,
+        crate::withdrawal::get_withdrawal_by_id,
+        crate::hold::create_hold,
+        crate::hold::get_hold_by_id,
+        crate::hold::capture_hold,
+        crate::hold::void_hold,
+        crate::schedule::create_scheduled_transfer,
+        crate::schedule::scheduled_transfers_list,
+        crate::schedule::get_scheduled_transfer_by_id,
+        crate::schedule::scheduled_transfer_runs,
+        crate::schedule::cancel_scheduled_transfer,
+        crate::payment_request::create_payment_request,
+        crate::payment_request::payment_requests_list,
+        crate::payment_request::get_payment_request_by_id,
+        crate::payment_request::accept_payment_request,
+        crate::payment_request::decline_payment_request,
+        crate::payment_request::cancel_payment_request,
+        crate::checkout::create_checkout_session,
+        crate::checkout::checkout_sessions_list,
+        crate::checkout::get_checkout_session_by_id,
+        crate::checkout::pay_checkout_session,
+        crate::checkout::cancel_checkout_session,
+        crate::webhook::create_webhook_endpoint,
+        crate::webhook::webhook_endpoints_list,
+        crate::webhook::delete_webhook_endpoint,
+        crate::webhook::webhook_deliveries_list,
+        crate::webhook::webhook_delivery_attempts,
+        crate::webhook::redeliver_webhook,
+        crate::events::event_stream,
+        crate::statement::statement,
+        crate::totp::totp_status,
+        crate::totp::enroll_totp,
+        crate::totp::confirm_totp,
+        crate::totp::regenerate_recovery_codes,
+        crate::totp::set_step_up_threshold,
+        crate::totp::disable_totp,
+        crate::api_key::create_api_key,
+        crate::api_key::api_keys_list,
+        crate::api_key::rename_api_key,
+        crate::api_key::revoke_api_key,
+        crate::admin::search_users,
+        crate::admin::get_user,
+        crate::admin::set_user_role,
+        crate::admin::set_account_type,
+        crate::audit::audit_log,
+        crate::limit::get_allowance,
+        crate::limit::limit_tiers_list,
+        crate::limit::set_limit_tier,
+        crate::limit::get_user_allowance,
+        crate::limit::set_user_limits,
+        crate::fee::fee_quote,
+        crate::fee::fee_schedules_list,
+        crate::fee::set_fee_schedule,
+        crate::fee::delete_fee_schedule,
+        crate::interest::get_interest,
+        crate::interest::interest_rates_list,
+        crate::interest::set_interest_rate,
+        crate::interest::interest_runs_list,
+        crate::interest::run_interest,
+        crate::risk::transfer_reviews_list,
+        crate::risk::approve_transfer,
+        crate::risk::reject_transfer,
+        crate::risk::risk_rules_list,
+        crate::risk::reload_risk_rules,
+        crate::admin::freeze_account,
+        crate::admin::unfreeze_account,
+        crate::admin::close_account,
+        crate::admin::get_status_history,
+        crate::admin::adjust_balance,
+        crate::admin::get_any_transaction,
     ),
     components(
         schemas(
             UserCredentials,
+            TokenPair,
+            RefreshRequest,
+            PasswordChangeRequest,
+            PasswordResetRequest,
+            PasswordResetConfirmation,
+            LoginChallenge,
+            AuditRecord,
+            Limits,
+            LimitTier,
+            LimitKind,
+            UserLimitsRequest,
+            Allowance,
+            LimitWindow,
+            FeeOperation,
+            FeeQuote,
+            FeeSchedule,
+            FeeScheduleRequest,
+            InterestRate,
+            InterestRateRequest,
+            InterestRun,
+            InterestRunRecord,
+            InterestRunRequest,
+            InterestSummary,
+            InterestPayout,
+            TransferReview,
+            ReviewStatus,
+            ReviewDecision,
+            RuleConfig,
+            RuleAction,
+            AuditAction,
+            LoginAttempt,
+            LoginOutcome,
+            LoginChallengeRequest,
+            SecondFactor,
+            TotpEnrollment,
+            TotpStatus,
+            RecoveryCodes,
+            CodeRequest,
+            StepUpRequest,
+            Role,
+            AccountType,
+            AccountTypeRequest,
+            AdminUser,
+            AccountStatus,
+            ApiKeyRequest,
+            ApiKeyNameRequest,
+            crate::api_key::ApiKey,
+            CreatedApiKey,
+            RoleRequest,
+            FreezeRequest,
+            StatusChangeRequest,
+            AccountStatusChange,
+            AdjustmentRequest,
+            AdjustmentReason,
+            BalanceAdjustment,
             DepositAmount,
+            Balance,
             TransactionRequest,
             Transaction,
+            TransactionKind,
+            TransactionPage,
+            Direction,
+            SortOrder,
+            RefundRequest,
+            BatchRequest,
+            BatchMode,
+            BatchResponse,
+            BatchItemResult,
+            BatchItemError,
+            WithdrawalRequest,
+            Withdrawal,
+            WithdrawalStatus,
+            HistoryEntry,
+            StatementFormat,
+            HoldRequest,
+            CaptureRequest,
+            Hold,
+            HoldStatus,
+            ScheduledTransferRequest,
+            ScheduledTransfer,
+            ScheduledTransferRun,
+            ScheduledTransferStatus,
+            Frequency,
+            InsufficientBalancePolicy,
+            RunOutcome,
+            CreatePaymentRequest,
+            PaymentRequest,
+            PaymentRequestStatus,
+            CreateCheckoutSession,
+            CheckoutSession,
+            CheckoutSessionStatus,
+            EventType,
+            WebhookEndpointRequest,
+            WebhookEndpoint,
+            CreatedWebhookEndpoint,
+            WebhookDelivery,
+            DeliveryStatus,
+            WebhookDeliveryAttempt,
         )
     ),
     modifiers(&SecurityAddon),
@@ -34,6 +248,18 @@ use crate::{
       (name = "User Management", description = "User authentication and management"),  
       (name = "Account Balance Management", description = "Account Balances Management"),  
       (name = "Transactions" ),  
+      (name = "Holds", description = "Authorizing funds now and capturing them later"),
+      (name = "Scheduled Transfers", description = "Future-dated and recurring transfers"),
+      (name = "Payment Requests", description = "Asking other users for money"),
+      (name = "Checkout", description = "Hosted checkout sessions for merchants taking payments"),
+      (name = "Webhooks", description = "Signed notifications about account events"),
+      (name = "Events", description = "Real-time stream of account events"),
+      (name = "Two-Factor Authentication", description = "TOTP second factor for logins and large transfers"),
+      (name = "API Keys", description = "Credentials for server-to-server clients"),
+      (name = "Limits", description = "Transfer and deposit limits of the logged in user"),
+      (name = "Fees", description = "Fees charged on transfers, deposits and withdrawals"),
+      (name = "Interest", description = "Interest earned on the balance of the logged in user"),
+      (name = "Admin", description = "Operations endpoints for support, admins and auditors"),
     ),
 )]
 pub(crate) struct ApiDoc;
@@ -46,7 +272,11 @@ impl Modify for SecurityAddon {
             components.add_security_scheme(
                 "USER_JWT",
                 SecurityScheme::Http(Http::new(utoipa::openapi::security::HttpAuthScheme::Bearer)),
-            )
+            );
+            components.add_security_scheme(
+                "API_KEY",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+            );
         }
     }
 }
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::db::Db;
+
+use super::ApiKey;
+
+impl Db {
+    pub async fn create_api_key(
+        &self,
+        username: &str,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> sqlx::Result<ApiKey> {
+        sqlx::query_as!(
+            ApiKey,
+            "INSERT INTO api_keys(username, name, prefix, key_hash, expires_at) VALUES($1, $2, $3, $4, $5)
+            RETURNING key_id, name, prefix, expires_at, last_used_at, revoked_at, created_at",
+            username,
+            name,
+            prefix,
+            key_hash,
+            expires_at
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_api_keys_list(&self, username: &str) -> sqlx::Result<Vec<ApiKey>> {
+        sqlx::query_as!(
+            ApiKey,
+            "SELECT key_id, name, prefix, expires_at, last_used_at, revoked_at, created_at
+            FROM api_keys WHERE username = $1 ORDER BY created_at DESC",
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Returns false if the user has no key with this id
+    pub async fn rename_api_key(&self, id: Uuid, username: &str, name: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE api_keys SET name = $3 WHERE key_id = $1 AND username = $2",
+            id,
+            username,
+            name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Returns false if the user has no active key with this id
+    pub async fn revoke_api_key(&self, id: Uuid, username: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE api_keys SET revoked_at = now()
+            WHERE key_id = $1 AND username = $2 AND revoked_at IS NULL",
+            id,
+            username
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub(crate) async fn revoke_user_api_keys(
+        conn: &mut sqlx::PgConnection,
+        username: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE api_keys SET revoked_at = now() WHERE username = $1 AND revoked_at IS NULL",
+            username
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the owner of the key if it is neither revoked nor expired and records its use
+    pub async fn authenticate_api_key(&self, key_hash: &str) -> sqlx::Result<Option<String>> {
+        sqlx::query!(
+            "UPDATE api_keys SET last_used_at = now()
+            WHERE key_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+            RETURNING username",
+            key_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map(|record| record.map(|record| record.username))
+    }
+}
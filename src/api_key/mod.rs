@@ -0,0 +1,213 @@
+mod db;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{delete, get, patch, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{app_state::AppState, db::Db, error::AppResult, utils::SessionInfo};
+
+/// Header server-to-server clients send their API key in
+pub(crate) const API_KEY_HEADER: &str = "X-API-Key";
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", post(create_api_key))
+        .route("/", get(api_keys_list))
+        .route("/:id", patch(rename_api_key))
+        .route("/:id", delete(revoke_api_key))
+        .with_state(app_state)
+}
+
+///Creates an API key for the logged in user. The key is only shown once.
+///API keys can't manage API keys, these endpoints need a login session
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    tag = "API Keys",
+    request_body = ApiKeyRequest,
+    responses(
+        (status = 201, description = "API key successfully created", body = CreatedApiKey),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 422, description = "Invalid name or expiry in the past"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn create_api_key(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    Json(api_key_request): Json<ApiKeyRequest>,
+) -> AppResult<impl IntoResponse> {
+    api_key_request.validate()?;
+    if api_key_request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "The expiry of an API key must be in the future",
+        )
+            .into_response());
+    }
+
+    let prefix = format!("spk_{}", &Uuid::new_v4().simple().to_string()[..8]);
+    // Two v4 uuids give 244 random bits from the OS random number generator
+    let key = format!(
+        "{prefix}_{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+
+    let api_key = db
+        .create_api_key(
+            &username,
+            &api_key_request.name,
+            &prefix,
+            &hash_api_key(&key),
+            api_key_request.expires_at,
+        )
+        .await?;
+
+    Ok((
+        http::StatusCode::CREATED,
+        Json(CreatedApiKey { api_key, key }),
+    )
+        .into_response())
+}
+
+///API keys of the logged in user, including expired and revoked ones
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    tag = "API Keys",
+    responses(
+        (status = 200, description = "API keys successfully retreived", body = Vec<ApiKey>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn api_keys_list(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_api_keys_list(&username).await?))
+}
+
+///Renames an API key
+#[utoipa::path(
+    patch,
+    path = "/api-keys/{id}",
+    tag = "API Keys",
+    request_body = ApiKeyNameRequest,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "API key id")
+    ),
+    responses(
+        (status = 200, description = "API key successfully renamed"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 404, description = "API key not found"),
+        (status = 422, description = "Invalid name"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn rename_api_key(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    Path(id): Path<Uuid>,
+    Json(name_request): Json<ApiKeyNameRequest>,
+) -> AppResult<impl IntoResponse> {
+    name_request.validate()?;
+
+    if !db.rename_api_key(id, &username, &name_request.name).await? {
+        return Ok(http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(http::StatusCode::OK)
+}
+
+///Revokes an API key, requests using it are rejected from now on
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    tag = "API Keys",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "API key id")
+    ),
+    responses(
+        (status = 200, description = "API key successfully revoked"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 404, description = "API key not found or already revoked"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn revoke_api_key(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if !db.revoke_api_key(id, &username).await? {
+        return Ok(http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(http::StatusCode::OK)
+}
+
+/// API keys are stored as SHA-256 digests so a database leak doesn't expose usable keys
+pub(crate) fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct ApiKeyRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    /// The key never expires when left out
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct ApiKeyNameRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct ApiKey {
+    pub key_id: Uuid,
+    pub name: String,
+    /// Start of the key, to tell keys apart
+    pub prefix: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    /// Sent in the `X-API-Key` header, only shown once
+    pub key: String,
+}
@@ -1,11 +1,18 @@
 use axum::extract::FromRef;
 use sqlx::postgres::PgPoolOptions;
 
-use crate::{config::config, db::Db};
+use crate::{
+    checkout, config::config, db::Db, events::Events, hold, interest, notifier::Notifications,
+    payment_request, payout::Payouts, risk::RiskEngine, schedule, webhook,
+};
 
 #[derive(FromRef, Clone)]
 pub(crate) struct AppState {
     pub db: Db,
+    pub payouts: Payouts,
+    pub events: Events,
+    pub notifications: Notifications,
+    pub risk_engine: RiskEngine,
 }
 
 impl AppState {
@@ -17,6 +24,25 @@ impl AppState {
 
         sqlx::migrate!("./migrations").run(&pool).await?;
 
-        Ok(AppState { db: Db::init(pool) })
+        let db = Db::init(pool);
+        db.promote_admins(&config().ADMIN_USERNAMES).await?;
+        let payouts = Payouts::init(&db);
+        let events = Events::init(&db);
+        let notifications = Notifications::init();
+        let risk_engine = RiskEngine::init();
+        hold::spawn_expiry_task(db.clone());
+        schedule::spawn_scheduler(db.clone(), risk_engine.clone());
+        payment_request::spawn_expiry_task(db.clone());
+        checkout::spawn_expiry_task(db.clone());
+        webhook::spawn_delivery_worker(db.clone());
+        interest::spawn_interest_job(db.clone());
+
+        Ok(AppState {
+            db,
+            payouts,
+            events,
+            notifications,
+            risk_engine,
+        })
     }
 }
@@ -0,0 +1,59 @@
+use sqlx::{types::Json, PgConnection};
+
+use crate::db::Db;
+
+use super::{AuditAction, AuditContext, AuditEntry, AuditLogQuery, AuditRecord};
+
+impl Db {
+    /// Appends to the audit log. Financial and administrative actions pass the connection of
+    /// their database transaction, so the record is only kept if the action is
+    pub async fn record_audit(
+        conn: &mut PgConnection,
+        context: &AuditContext,
+        entry: AuditEntry<'_>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO audit_log(actor, action, target, request_id, ip_address, before, after)
+            VALUES($1, $2, $3, $4, $5, $6, $7)",
+            entry.actor,
+            entry.action as AuditAction,
+            entry.target,
+            context.request_id,
+            context.ip_address,
+            entry.before.map(Json) as _,
+            entry.after.map(Json) as _
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_audit_log(&self, query: &AuditLogQuery) -> sqlx::Result<Vec<AuditRecord>> {
+        sqlx::query_as!(
+            AuditRecord,
+            r#"SELECT audit_id, actor, action AS "action: AuditAction", target, request_id,
+            ip_address, before, after, created_at
+            FROM audit_log
+            WHERE ($1::TEXT IS NULL OR actor = $1)
+            AND ($2::TEXT IS NULL OR target = $2)
+            AND ($3::audit_action IS NULL OR action = $3)
+            AND ($4::TEXT IS NULL OR request_id = $4)
+            AND ($5::timestamptz IS NULL OR created_at >= $5)
+            AND ($6::timestamptz IS NULL OR created_at < $6)
+            AND ($7::BIGINT IS NULL OR audit_id < $7)
+            ORDER BY audit_id DESC
+            LIMIT $8"#,
+            query.actor,
+            query.target,
+            query.action as Option<AuditAction>,
+            query.request_id,
+            query.from,
+            query.to,
+            query.before_id,
+            query.limit.unwrap_or(100)
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
@@ -0,0 +1,175 @@
+mod db;
+
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, Request, State},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    db::Db,
+    error::AppResult,
+    utils::{AuditorRole, RoleInfo},
+};
+
+/// Header carrying the id of a request. Clients may send their own, otherwise one is generated.
+/// It is returned on every response and stored with the audit records of the request
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/audit-log", get(audit_log))
+        .with_state(app_state)
+}
+
+/// Makes sure every request has an id and echoes it on the response
+pub(crate) async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let request_id = match request.headers().get(REQUEST_ID_HEADER) {
+        Some(request_id) if !request_id.is_empty() && request_id.len() <= 255 => request_id.clone(),
+        _ => HeaderValue::from_str(&Uuid::new_v4().to_string())
+            .expect("A uuid is a valid header value"),
+    };
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+///Audit records matching all given filters, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/audit-log",
+    tag = "Admin",
+    params(AuditLogQuery),
+    responses(
+        (status = 200, description = "Audit records successfully retreived", body = Vec<AuditRecord>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin or auditor"),
+        (status = 422, description = "Invalid filters"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn audit_log(
+    State(db): State<Db>,
+    _: RoleInfo<AuditorRole>,
+    Query(query): Query<AuditLogQuery>,
+) -> AppResult<impl IntoResponse> {
+    query.validate()?;
+
+    Ok(Json(db.get_audit_log(&query).await?))
+}
+
+/// Request an audited action was made in
+pub(crate) struct AuditContext {
+    pub request_id: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuditContext {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(AuditContext {
+            request_id: parts
+                .headers
+                .get(REQUEST_ID_HEADER)
+                .and_then(|request_id| request_id.to_str().ok())
+                .map(String::from),
+            ip_address: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip().to_string()),
+        })
+    }
+}
+
+/// Who moves money and in which request, for transfers audited where they are written.
+/// `actor` is None for transfers the system makes on its own, like scheduled ones
+pub(crate) struct AuditSource<'a> {
+    pub actor: Option<&'a str>,
+    pub context: &'a AuditContext,
+}
+
+/// An audited action, written with [`Db::record_audit`]
+pub(crate) struct AuditEntry<'a> {
+    pub actor: Option<&'a str>,
+    pub action: AuditAction,
+    pub target: Option<&'a str>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Stored as the `audit_action` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "audit_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    Signup,
+    Deposit,
+    Transfer,
+    RoleChange,
+    StatusChange,
+    LimitChange,
+    TransferReview,
+    FeeChange,
+    InterestRateChange,
+    AccountTypeChange,
+    Withdrawal,
+    BalanceAdjustment,
+    PasswordChange,
+    PasswordReset,
+    TwoFactorChange,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AuditRecord {
+    pub audit_id: i64,
+    pub actor: Option<String>,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub request_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct AuditLogQuery {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: Option<AuditAction>,
+    pub request_id: Option<String>,
+    /// Only records created at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only records created before this time
+    pub to: Option<DateTime<Utc>>,
+    /// `audit_id` of the last record of the previous page
+    pub before_id: Option<i64>,
+    /// Page size, 100 by default
+    #[validate(range(min = 1, max = 500))]
+    pub limit: Option<i64>,
+}
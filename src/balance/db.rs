@@ -1,40 +1,85 @@
-use crate::db::Db;
+use sqlx::PgConnection;
+
+use crate::{
+    db::Db,
+    error::AppResult,
+    fee::FeeOperation,
+    ledger::{EntryKind, EntryReference, Movement, FUNDING_ACCOUNT},
+    webhook::EventType,
+};
+
+use super::{Balance, Deposited};
 
 impl Db {
-    pub async fn get_balance_of_user(&self, username: &str) -> sqlx::Result<i64> {
-        sqlx::query!(
-            "SELECT balance FROM user_credentials WHERE username = $1",
+    /// The ledger balance is derived from the postings of the user's ledger account,
+    /// the available balance additionally excludes the active holds on it
+    pub async fn get_balance_of_user(&self, username: &str) -> sqlx::Result<Balance> {
+        sqlx::query_as!(
+            Balance,
+            r#"WITH ledger AS (
+                SELECT COALESCE(SUM(postings.amount), 0)::BIGINT AS balance
+                FROM ledger_accounts LEFT JOIN postings USING (account_id)
+                WHERE ledger_accounts.username = $1
+                GROUP BY ledger_accounts.account_id
+            )
+            SELECT balance AS "ledger_balance!",
+            balance - COALESCE((
+                SELECT SUM(amount) FROM holds
+                WHERE payer = $1 AND status = 'active' AND expires_at > now()
+            ), 0)::BIGINT AS "available_balance!"
+            FROM ledger"#,
             username
         )
         .fetch_one(&self.pool)
         .await
-        .map(|record| record.balance)
     }
 
-    pub async fn deposit(self, username: &str, amount: i32) -> sqlx::Result<i64> {
-        let mut transaction = self.pool.begin().await?;
+    /// Credits the user inside an already open database transaction, taking the deposit fee out
+    /// of the amount, and returns the new balance
+    pub async fn deposit(
+        conn: &mut PgConnection,
+        username: &str,
+        amount: i32,
+    ) -> AppResult<Deposited> {
+        let account = Self::lock_user_accounts(&mut *conn, &[username])
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)?;
+        account.ensure_allows(Movement::Deposit)?;
 
-        let balance = sqlx::query!(
-            "SELECT balance FROM user_credentials WHERE username = $1",
-            username
-        )
-        .fetch_one(&mut *transaction)
-        .await?
-        .balance;
+        Self::get_limit_usage(&mut *conn, username)
+            .await?
+            .check_deposit(amount as i64)?;
 
-        let new_balance = balance + amount as i64;
+        let fee = Self::get_fee_plan(&mut *conn, username, FeeOperation::Deposit)
+            .await?
+            .deposit_fee(amount);
 
-        let updated_balance = sqlx::query!(
-            "UPDATE user_credentials SET balance = $1 WHERE username = $2 RETURNING balance",
-            new_balance,
-            username
+        let funding_account_id = Self::system_account_id(&mut *conn, FUNDING_ACCOUNT).await?;
+
+        Self::post_journal_entry(
+            &mut *conn,
+            EntryKind::Deposit,
+            EntryReference::None,
+            &[
+                (account.account_id, amount as i64),
+                (funding_account_id, -(amount as i64)),
+            ],
         )
-        .fetch_one(&mut *transaction)
-        .await?
-        .balance;
+        .await?;
+
+        Self::charge_fee(&mut *conn, account.account_id, fee, EntryReference::None).await?;
 
-        transaction.commit().await?;
+        let balance = account.balance + amount as i64 - fee as i64;
+
+        Self::record_event(
+            &mut *conn,
+            username,
+            EventType::DepositCompleted,
+            &serde_json::json!({ "amount": amount, "fee": fee, "balance": balance }),
+        )
+        .await?;
 
-        Ok(updated_balance)
+        Ok(Deposited { balance, fee })
     }
 }
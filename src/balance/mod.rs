@@ -2,24 +2,36 @@ mod db;
 
 use axum::{
     extract::State,
+    http,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use utoipa::ToSchema;
 use validator::Validate;
 
-use crate::{app_state::AppState, db::Db, error::AppResult, utils::UserInfo};
+use crate::{
+    app_state::AppState,
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    transaction::Transaction,
+    utils::UserInfo,
+    withdrawal::Withdrawal,
+};
 
 pub(super) fn get_router(app_state: AppState) -> Router {
     Router::new()
         .route("/", get(get_balance))
         .route("/deposit", post(deposit))
+        .route("/history", get(balance_history))
         .with_state(app_state)
 }
 
-#[derive(Deserialize, Validate, ToSchema)]
+#[derive(Serialize, Deserialize, Validate, ToSchema)]
 pub(crate) struct DepositAmount {
     #[validate(range(min = 1))]
     deposit_amount: i32,
@@ -30,25 +42,66 @@ pub(crate) struct DepositAmount {
     path = "/balance/deposit",
     tag = "Account Balance Management",
     request_body =  DepositAmount,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of depositing again")
+    ),
     responses(
-        (status = 200, description = "Successfully deposited money", body = i64),
+        (status = 200, description = "Successfully deposited money, returns the new balance after the deposit fee", body = i64),
         (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "The daily deposit limit would be exceeded"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
         (status = 500, description = "Internal Server Error"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn deposit(
     State(db): State<Db>,
     UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
     Json(deposit_amount): Json<DepositAmount>,
 ) -> AppResult<impl IntoResponse> {
     deposit_amount.validate()?;
-    Ok(db
-        .deposit(&username, deposit_amount.deposit_amount)
-        .await?
-        .to_string())
+
+    let fingerprint = request_fingerprint("POST /balance/deposit", &deposit_amount)?;
+
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let Deposited { balance, fee } =
+                    Db::deposit(conn, username, deposit_amount.deposit_amount).await?;
+
+                Db::record_audit(
+                    conn,
+                    &audit_context,
+                    AuditEntry {
+                        actor: Some(username),
+                        action: AuditAction::Deposit,
+                        target: Some(username),
+                        before: Some(json!({
+                            "balance": balance - deposit_amount.deposit_amount as i64 + fee as i64
+                        })),
+                        after: Some(json!({
+                            "balance": balance,
+                            "amount": deposit_amount.deposit_amount,
+                            "fee": fee
+                        })),
+                    },
+                )
+                .await?;
+                Ok((http::StatusCode::OK, balance.to_string()))
+            })
+        },
+    )
+    .await
 }
 
 #[utoipa::path(
@@ -56,16 +109,85 @@ async fn deposit(
     path = "/balance",
     tag = "Account Balance Management",
     responses(
-        (status = 200, description = "Current balance of user"),
+        (status = 200, description = "Current balance of user", body = Balance),
         (status = 401, description = "Invalid bearer token"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn get_balance(
     State(db): State<Db>,
     UserInfo { username }: UserInfo,
 ) -> AppResult<impl IntoResponse> {
-    Ok((db.get_balance_of_user(&username).await?.to_string()).into_response())
+    Ok(Json(db.get_balance_of_user(&username).await?).into_response())
+}
+
+/// Result of [`Db::deposit`]
+pub(crate) struct Deposited {
+    pub balance: i64,
+    /// Taken out of the deposited amount
+    pub fee: i32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct Balance {
+    /// Sum of all postings on the user's account
+    pub ledger_balance: i64,
+    /// Ledger balance minus the funds held by active holds
+    pub available_balance: i64,
+}
+
+///Transactions and withdrawals of a User, newest first
+#[utoipa::path(
+    get,
+    path = "/balance/history",
+    tag = "Account Balance Management",
+    responses(
+        (status = 200, description = "History successfully retreived", body = Vec<HistoryEntry>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn balance_history(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    let mut history: Vec<HistoryEntry> = db
+        .get_transaction_history(&username)
+        .await?
+        .into_iter()
+        .map(HistoryEntry::Transaction)
+        .chain(
+            db.get_withdrawals_list(&username)
+                .await?
+                .into_iter()
+                .map(HistoryEntry::Withdrawal),
+        )
+        .collect();
+
+    history.sort_by_key(|entry| std::cmp::Reverse(entry.created_at()));
+
+    Ok(Json(history).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum HistoryEntry {
+    Transaction(Transaction),
+    Withdrawal(Withdrawal),
+}
+
+impl HistoryEntry {
+    fn created_at(&self) -> chrono::DateTime<chrono::Utc> {
+        match self {
+            HistoryEntry::Transaction(transaction) => transaction.created_at,
+            HistoryEntry::Withdrawal(withdrawal) => withdrawal.created_at,
+        }
+    }
 }
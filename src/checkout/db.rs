@@ -0,0 +1,208 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditSource},
+    db::Db,
+    error::AppResult,
+    risk::{RiskDenied, RiskEngine},
+    transaction::{TransactionRequest, TransferOutcome},
+};
+
+use super::{CheckoutOutcome, CheckoutSession, CheckoutSessionStatus, CreateCheckoutSession};
+
+impl Db {
+    /// Returns None without writing anything if the user is not a merchant
+    pub async fn create_checkout_session(
+        &self,
+        username: &str,
+        checkout_session: &CreateCheckoutSession,
+    ) -> sqlx::Result<Option<Uuid>> {
+        sqlx::query_scalar!(
+            "INSERT INTO checkout_sessions(merchant, amount, reference, success_url, cancel_url,
+            expires_at)
+            SELECT username, $2, $3, $4, $5, now() + make_interval(secs => $6)
+            FROM user_credentials WHERE username = $1 AND account_type = 'merchant'
+            RETURNING session_id",
+            username,
+            checkout_session.amount,
+            checkout_session.reference,
+            checkout_session.success_url,
+            checkout_session.cancel_url,
+            checkout_session.expires_in_seconds as f64
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Open sessions past their expiry are reported as expired before they are marked
+    pub async fn get_checkout_session(&self, id: Uuid) -> sqlx::Result<Option<CheckoutSession>> {
+        sqlx::query_as!(
+            CheckoutSession,
+            r#"SELECT checkout_sessions.session_id, checkout_sessions.merchant,
+            user_credentials.business_name, checkout_sessions.amount, checkout_sessions.reference,
+            checkout_sessions.success_url, checkout_sessions.cancel_url,
+            CASE WHEN checkout_sessions.status = 'open' AND checkout_sessions.expires_at <= now()
+                THEN 'expired' ELSE checkout_sessions.status
+            END AS "status!: CheckoutSessionStatus",
+            checkout_sessions.payer, checkout_sessions.transaction_id, checkout_sessions.expires_at,
+            checkout_sessions.created_at, checkout_sessions.updated_at
+            FROM checkout_sessions
+            JOIN user_credentials ON user_credentials.username = checkout_sessions.merchant
+            WHERE checkout_sessions.session_id = $1"#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn get_checkout_sessions_list(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<CheckoutSession>> {
+        sqlx::query_as!(
+            CheckoutSession,
+            r#"SELECT checkout_sessions.session_id, checkout_sessions.merchant,
+            user_credentials.business_name, checkout_sessions.amount, checkout_sessions.reference,
+            checkout_sessions.success_url, checkout_sessions.cancel_url,
+            CASE WHEN checkout_sessions.status = 'open' AND checkout_sessions.expires_at <= now()
+                THEN 'expired' ELSE checkout_sessions.status
+            END AS "status!: CheckoutSessionStatus",
+            checkout_sessions.payer, checkout_sessions.transaction_id, checkout_sessions.expires_at,
+            checkout_sessions.created_at, checkout_sessions.updated_at
+            FROM checkout_sessions
+            JOIN user_credentials ON user_credentials.username = checkout_sessions.merchant
+            WHERE checkout_sessions.merchant = $1
+            ORDER BY checkout_sessions.created_at DESC
+            LIMIT 100"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Pays the checkout session from `username` inside an already open database transaction.
+    /// Payments above the step-up threshold need `step_up_code` like any other transfer
+    pub async fn pay_checkout_session(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+        username: &str,
+        risk_engine: &RiskEngine,
+        step_up_code: Option<&str>,
+        audit_context: &AuditContext,
+    ) -> AppResult<CheckoutOutcome> {
+        let Some(checkout_session) = sqlx::query!(
+            r#"SELECT merchant, amount, status = 'open' AND expires_at > now() AS "open!"
+            FROM checkout_sessions WHERE session_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Ok(CheckoutOutcome::NotFound);
+        };
+
+        if checkout_session.merchant == username {
+            return Ok(CheckoutOutcome::NotAllowed);
+        }
+
+        if !checkout_session.open {
+            return Ok(CheckoutOutcome::NotOpen);
+        }
+
+        self.require_step_up(
+            &mut *conn,
+            username,
+            checkout_session.amount as i64,
+            step_up_code,
+        )
+        .await?;
+
+        let transaction_id = match Self::process_transaction(
+            &mut *conn,
+            username,
+            &TransactionRequest {
+                to_user: checkout_session.merchant,
+                amount: checkout_session.amount,
+            },
+            Some(risk_engine),
+            &AuditSource {
+                actor: Some(username),
+                context: audit_context,
+            },
+        )
+        .await?
+        {
+            TransferOutcome::Executed(transaction_id) => transaction_id,
+            TransferOutcome::InsufficientBalance => {
+                return Ok(CheckoutOutcome::InsufficientBalance)
+            }
+            TransferOutcome::ReviewRequired { rule, reason } => {
+                return Err(RiskDenied::review_required(rule, reason).into())
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE checkout_sessions SET status = 'paid', payer = $1, transaction_id = $2,
+            updated_at = now()
+            WHERE session_id = $3",
+            username,
+            transaction_id,
+            id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(CheckoutOutcome::Paid(transaction_id))
+    }
+
+    pub async fn cancel_checkout_session(
+        &self,
+        id: Uuid,
+        username: &str,
+    ) -> sqlx::Result<CheckoutOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(checkout_session) = sqlx::query!(
+            r#"SELECT merchant, status = 'open' AND expires_at > now() AS "open!"
+            FROM checkout_sessions WHERE session_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(CheckoutOutcome::NotFound);
+        };
+
+        if checkout_session.merchant != username {
+            return Ok(CheckoutOutcome::NotAllowed);
+        }
+
+        if !checkout_session.open {
+            return Ok(CheckoutOutcome::NotOpen);
+        }
+
+        sqlx::query!(
+            "UPDATE checkout_sessions SET status = $1, updated_at = now() WHERE session_id = $2",
+            CheckoutSessionStatus::Cancelled as CheckoutSessionStatus,
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(CheckoutOutcome::Cancelled)
+    }
+
+    /// Marks open checkout sessions past their expiry as expired and returns how many there were
+    pub async fn expire_checkout_sessions(&self) -> sqlx::Result<u64> {
+        sqlx::query!(
+            "UPDATE checkout_sessions SET status = 'expired', updated_at = now()
+            WHERE status = 'open' AND expires_at <= now()"
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+}
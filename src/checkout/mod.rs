@@ -0,0 +1,373 @@
+mod db;
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use url::{Host, Url};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    risk::RiskEngine,
+    totp::StepUpCode,
+    utils::UserInfo,
+};
+
+/// How often open checkout sessions past their expiry are marked as expired
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", post(create_checkout_session))
+        .route("/", get(checkout_sessions_list))
+        .route("/:id", get(get_checkout_session_by_id))
+        .route("/:id/pay", post(pay_checkout_session))
+        .route("/:id/cancel", post(cancel_checkout_session))
+        .with_state(app_state)
+}
+
+/// Marks open checkout sessions past their expiry as expired in the background.
+/// Sessions past their expiry can't be paid and are reported as expired even before they are marked.
+pub(crate) fn spawn_expiry_task(db: Db) {
+    tokio::spawn(async move {
+        loop {
+            match db.expire_checkout_sessions().await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {expired} checkout sessions"),
+                Err(err) => tracing::error!("failed to expire checkout sessions: {err}"),
+            }
+            tokio::time::sleep(EXPIRY_INTERVAL).await;
+        }
+    });
+}
+
+///Creates a checkout session for a payment to the logged in merchant
+#[utoipa::path(
+    post,
+    path = "/checkout-sessions",
+    tag = "Checkout",
+    request_body = CreateCheckoutSession,
+    responses(
+        (status = 201, description = "Checkout session successfully created", body = CheckoutSession),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not a merchant"),
+        (status = 422, description = "Invalid checkout session"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_checkout_session(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Json(checkout_session): Json<CreateCheckoutSession>,
+) -> AppResult<impl IntoResponse> {
+    checkout_session.validate()?;
+
+    let Some(session_id) = db
+        .create_checkout_session(&username, &checkout_session)
+        .await?
+    else {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "Only merchant accounts can create checkout sessions",
+        )
+            .into_response());
+    };
+
+    let checkout_session = db
+        .get_checkout_session(session_id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+    Ok((http::StatusCode::CREATED, Json(checkout_session)).into_response())
+}
+
+///Latest checkout sessions of the logged in merchant, newest first
+#[utoipa::path(
+    get,
+    path = "/checkout-sessions",
+    tag = "Checkout",
+    responses(
+        (status = 200, description = "Checkout sessions successfully retreived", body = Vec<CheckoutSession>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn checkout_sessions_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_checkout_sessions_list(&username).await?))
+}
+
+///Current state of a checkout session, for merchants polling for the payment and for payers
+///about to pay. Any user can view an open session, once it is closed only the merchant and the payer
+#[utoipa::path(
+    get,
+    path = "/checkout-sessions/{id}",
+    tag = "Checkout",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Checkout session id")
+    ),
+    responses(
+        (status = 200, description = "Checkout session successfully retreived", body = CheckoutSession),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not authorized to view this checkout session"),
+        (status = 404, description = "Checkout session not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_checkout_session_by_id(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let Some(checkout_session) = db.get_checkout_session(id).await? else {
+        return Ok((http::StatusCode::NOT_FOUND, "Checkout session not found").into_response());
+    };
+
+    if checkout_session.status != CheckoutSessionStatus::Open
+        && checkout_session.merchant != username
+        && checkout_session.payer.as_deref() != Some(username.as_str())
+    {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this checkout session",
+        )
+            .into_response());
+    }
+
+    Ok(Json(checkout_session).into_response())
+}
+
+///Pays an open checkout session with a transfer from the logged in User to the merchant.
+///Returns the id of the transaction, the payer is then sent to the success url of the session
+#[utoipa::path(
+    post,
+    path = "/checkout-sessions/{id}/pay",
+    tag = "Checkout",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Checkout session id"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of paying again"),
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required above the step-up threshold of users with two-factor authentication")
+    ),
+    responses(
+        (status = 200, description = "Checkout session successfully paid", body = Uuid),
+        (status = 402, description = "Insufficient balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "Merchants can't pay their own checkout sessions, a fresh TOTP code is required for this amount, a transfer limit would be exceeded or a risk rule refused the transfer"),
+        (status = 404, description = "Checkout session not found"),
+        (status = 409, description = "The checkout session is not open anymore"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn pay_checkout_session(
+    State(db): State<Db>,
+    State(risk_engine): State<RiskEngine>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    StepUpCode(step_up_code): StepUpCode,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let fingerprint = request_fingerprint(&format!("POST /checkout-sessions/{id}/pay"), &())?;
+
+    let step_up_db = db.clone();
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let outcome = step_up_db
+                    .pay_checkout_session(
+                        conn,
+                        id,
+                        username,
+                        &risk_engine,
+                        step_up_code.as_deref(),
+                        &audit_context,
+                    )
+                    .await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/checkout-sessions/{id}/cancel",
+    tag = "Checkout",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Checkout session id")
+    ),
+    responses(
+        (status = 200, description = "Checkout session successfully cancelled"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the merchant of this checkout session"),
+        (status = 404, description = "Checkout session not found"),
+        (status = 409, description = "The checkout session is not open anymore"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn cancel_checkout_session(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let outcome = db.cancel_checkout_session(id, &username).await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+/// Stored as the `checkout_session_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "checkout_session_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CheckoutSessionStatus {
+    /// Waiting for a payer
+    Open,
+    Paid,
+    /// Withdrawn by the merchant
+    Cancelled,
+    Expired,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct CheckoutSession {
+    pub session_id: Uuid,
+    pub merchant: String,
+    /// Name of the merchant shown to payers, missing if the account is not a merchant anymore
+    pub business_name: Option<String>,
+    pub amount: i32,
+    /// The merchant's own reference, e.g. an order number
+    pub reference: String,
+    /// Where the payer is sent after paying
+    pub success_url: String,
+    /// Where the payer is sent when they don't pay
+    pub cancel_url: String,
+    pub status: CheckoutSessionStatus,
+    /// The user who paid the session
+    pub payer: Option<String>,
+    /// The transfer that paid the session
+    pub transaction_id: Option<Uuid>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct CreateCheckoutSession {
+    #[validate(range(min = 1))]
+    pub amount: i32,
+    #[validate(length(min = 1, max = 255))]
+    pub reference: String,
+    /// https, or http for localhost while testing
+    #[validate(url, custom(function = "validate_redirect_url"))]
+    pub success_url: String,
+    /// https, or http for localhost while testing
+    #[validate(url, custom(function = "validate_redirect_url"))]
+    pub cancel_url: String,
+    /// Seconds until the session expires, at most a day
+    #[validate(range(min = 60, max = 86400))]
+    #[serde(default = "default_checkout_session_expiry")]
+    pub expires_in_seconds: i64,
+}
+
+fn default_checkout_session_expiry() -> i64 {
+    30 * 60
+}
+
+/// Payers are sent to these URLs, so schemes like `javascript:` or `data:` are refused
+fn validate_redirect_url(url: &str) -> Result<(), ValidationError> {
+    let url = Url::parse(url).map_err(|_| ValidationError::new("url"))?;
+    let localhost = match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    };
+
+    match (url.scheme(), localhost) {
+        ("https", _) | ("http", true) => Ok(()),
+        _ => Err(ValidationError::new("https_required")),
+    }
+}
+
+pub(crate) enum CheckoutOutcome {
+    Paid(Uuid),
+    Cancelled,
+    NotFound,
+    /// The user is not the party allowed to perform the action
+    NotAllowed,
+    /// The session was already paid, cancelled or is expired
+    NotOpen,
+    InsufficientBalance,
+}
+
+impl CheckoutOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            CheckoutOutcome::Paid(transaction_id) => {
+                (http::StatusCode::OK, transaction_id.to_string())
+            }
+            CheckoutOutcome::Cancelled => (http::StatusCode::OK, String::new()),
+            CheckoutOutcome::NotFound => (
+                http::StatusCode::NOT_FOUND,
+                "Checkout session not found".to_string(),
+            ),
+            CheckoutOutcome::NotAllowed => (
+                http::StatusCode::FORBIDDEN,
+                "User is not allowed to perform this action on the checkout session".to_string(),
+            ),
+            CheckoutOutcome::NotOpen => (
+                http::StatusCode::CONFLICT,
+                "The checkout session is not open anymore".to_string(),
+            ),
+            CheckoutOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "Insufficent balance in user account".to_string(),
+            ),
+        }
+    }
+}
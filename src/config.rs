@@ -1,9 +1,39 @@
-use std::{env, sync::OnceLock};
+use std::{env, path::PathBuf, sync::OnceLock};
+
+use crate::payout::FakePayoutBehaviour;
 
 #[allow(non_snake_case)]
 pub struct Config {
     pub(crate) DATABASE_URL: String,
     pub(crate) JWT_SECRET: String,
+    pub(crate) IDEMPOTENCY_KEY_TTL_SECONDS: i64,
+    pub(crate) PAYOUT_CALLBACK_SECRET: String,
+    pub(crate) PAYOUT_SUBMIT_TIMEOUT_SECONDS: u64,
+    pub(crate) FAKE_PAYOUT_BEHAVIOUR: FakePayoutBehaviour,
+    /// Users promoted to admin at startup, so there is someone to hand out roles
+    pub(crate) ADMIN_USERNAMES: Vec<String>,
+    pub(crate) SCHEDULED_TRANSFER_RETRY_SECONDS: i64,
+    pub(crate) SCHEDULED_TRANSFER_MAX_RETRIES: i32,
+    pub(crate) WEBHOOK_MAX_ATTEMPTS: i32,
+    pub(crate) ACCESS_TOKEN_TTL_SECONDS: i64,
+    pub(crate) REFRESH_TOKEN_TTL_SECONDS: i64,
+    /// Shown next to the account in authenticator apps
+    pub(crate) TOTP_ISSUER: String,
+    pub(crate) LOGIN_CHALLENGE_TTL_SECONDS: i64,
+    /// Transfers above this amount need a fresh TOTP code unless the user set their own threshold
+    pub(crate) STEP_UP_THRESHOLD: i32,
+    pub(crate) PASSWORD_RESET_TTL_SECONDS: i64,
+    /// File the bundled notifier appends notifications to, they are only logged when not set
+    pub(crate) NOTIFICATIONS_FILE: Option<PathBuf>,
+    /// Failed logins of a username or an address before further logins are delayed
+    pub(crate) LOGIN_FREE_FAILURES: i64,
+    pub(crate) LOGIN_LOCKOUT_FAILURES: i64,
+    /// Higher than the per-username limit since many users can share an address
+    pub(crate) LOGIN_IP_LOCKOUT_FAILURES: i64,
+    /// How long a lockout lasts and how long failed logins are counted
+    pub(crate) LOGIN_LOCKOUT_SECONDS: i64,
+    /// JSON file with the risk rules transfers are checked against, none apply when not set
+    pub(crate) RISK_RULES_FILE: Option<PathBuf>,
 }
 pub fn config() -> &'static Config {
     static INSTANCE: OnceLock<Config> = OnceLock::new();
@@ -11,9 +41,71 @@ pub fn config() -> &'static Config {
     INSTANCE.get_or_init(|| Config {
         DATABASE_URL: read_env_var("DATABASE_URL"),
         JWT_SECRET: read_env_var("JWT_SECRET"),
+        IDEMPOTENCY_KEY_TTL_SECONDS: read_env_var_or("IDEMPOTENCY_KEY_TTL_SECONDS", "86400")
+            .parse()
+            .expect("IDEMPOTENCY_KEY_TTL_SECONDS must be a number of seconds"),
+        PAYOUT_CALLBACK_SECRET: read_env_var("PAYOUT_CALLBACK_SECRET"),
+        PAYOUT_SUBMIT_TIMEOUT_SECONDS: read_env_var_or("PAYOUT_SUBMIT_TIMEOUT_SECONDS", "5")
+            .parse()
+            .expect("PAYOUT_SUBMIT_TIMEOUT_SECONDS must be a number of seconds"),
+        FAKE_PAYOUT_BEHAVIOUR: read_env_var_or("FAKE_PAYOUT_BEHAVIOUR", "succeed")
+            .parse()
+            .expect("FAKE_PAYOUT_BEHAVIOUR must be one of succeed, fail or hang"),
+        ADMIN_USERNAMES: read_env_var_or("ADMIN_USERNAMES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|username| !username.is_empty())
+            .map(String::from)
+            .collect(),
+        SCHEDULED_TRANSFER_RETRY_SECONDS: read_env_var_or(
+            "SCHEDULED_TRANSFER_RETRY_SECONDS",
+            "3600",
+        )
+        .parse()
+        .expect("SCHEDULED_TRANSFER_RETRY_SECONDS must be a number of seconds"),
+        SCHEDULED_TRANSFER_MAX_RETRIES: read_env_var_or("SCHEDULED_TRANSFER_MAX_RETRIES", "3")
+            .parse()
+            .expect("SCHEDULED_TRANSFER_MAX_RETRIES must be a number"),
+        WEBHOOK_MAX_ATTEMPTS: read_env_var_or("WEBHOOK_MAX_ATTEMPTS", "10")
+            .parse()
+            .expect("WEBHOOK_MAX_ATTEMPTS must be a number"),
+        ACCESS_TOKEN_TTL_SECONDS: read_env_var_or("ACCESS_TOKEN_TTL_SECONDS", "900")
+            .parse()
+            .expect("ACCESS_TOKEN_TTL_SECONDS must be a number of seconds"),
+        REFRESH_TOKEN_TTL_SECONDS: read_env_var_or("REFRESH_TOKEN_TTL_SECONDS", "2592000")
+            .parse()
+            .expect("REFRESH_TOKEN_TTL_SECONDS must be a number of seconds"),
+        TOTP_ISSUER: read_env_var_or("TOTP_ISSUER", "Simple Payment System"),
+        LOGIN_CHALLENGE_TTL_SECONDS: read_env_var_or("LOGIN_CHALLENGE_TTL_SECONDS", "300")
+            .parse()
+            .expect("LOGIN_CHALLENGE_TTL_SECONDS must be a number of seconds"),
+        STEP_UP_THRESHOLD: read_env_var_or("STEP_UP_THRESHOLD", "100000")
+            .parse()
+            .expect("STEP_UP_THRESHOLD must be an amount"),
+        PASSWORD_RESET_TTL_SECONDS: read_env_var_or("PASSWORD_RESET_TTL_SECONDS", "1800")
+            .parse()
+            .expect("PASSWORD_RESET_TTL_SECONDS must be a number of seconds"),
+        NOTIFICATIONS_FILE: env::var("NOTIFICATIONS_FILE").ok().map(PathBuf::from),
+        LOGIN_FREE_FAILURES: read_env_var_or("LOGIN_FREE_FAILURES", "3")
+            .parse()
+            .expect("LOGIN_FREE_FAILURES must be a number"),
+        LOGIN_LOCKOUT_FAILURES: read_env_var_or("LOGIN_LOCKOUT_FAILURES", "10")
+            .parse()
+            .expect("LOGIN_LOCKOUT_FAILURES must be a number"),
+        LOGIN_IP_LOCKOUT_FAILURES: read_env_var_or("LOGIN_IP_LOCKOUT_FAILURES", "100")
+            .parse()
+            .expect("LOGIN_IP_LOCKOUT_FAILURES must be a number"),
+        LOGIN_LOCKOUT_SECONDS: read_env_var_or("LOGIN_LOCKOUT_SECONDS", "900")
+            .parse()
+            .expect("LOGIN_LOCKOUT_SECONDS must be a number of seconds"),
+        RISK_RULES_FILE: env::var("RISK_RULES_FILE").ok().map(PathBuf::from),
     })
 }
 
 fn read_env_var(var: &str) -> String {
     env::var(var).unwrap_or_else(|_| panic!("Unable to read the env variable {var}"))
 }
+
+fn read_env_var_or(var: &str, default: &str) -> String {
+    env::var(var).unwrap_or_else(|_| default.to_string())
+}
@@ -1,12 +1,32 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
+use crate::{ledger::AccountLocked, limit::LimitExceeded, risk::RiskDenied};
+
 pub(crate) type AppResult<T> = Result<T, AppError>;
 
 #[derive(Error, Debug)]
 pub(crate) enum AppError {
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("A fresh TOTP code in the X-TOTP-Code header is required for this amount")]
+    StepUpRequired,
+    #[error("{0}")]
+    AccountLocked(#[from] AccountLocked),
+    #[error("{0}")]
+    LimitExceeded(#[from] LimitExceeded),
+    #[error("{0}")]
+    RiskDenied(#[from] RiskDenied),
+    #[error("Too many failed logins, try again later")]
+    TooManyLoginAttempts { retry_at: DateTime<Utc> },
+    #[error("Too many invalid codes, try again later")]
+    TooManyCodeAttempts { retry_at: DateTime<Utc> },
     #[error("{0}")]
     SqlxError(#[from] sqlx::Error),
     #[error("{0}")]
@@ -23,6 +43,23 @@ impl IntoResponse for AppError {
 
         match self {
             AppError::Unauthorized => StatusCode::UNAUTHORIZED.into_response(),
+            AppError::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            AppError::StepUpRequired => {
+                (StatusCode::FORBIDDEN, AppError::StepUpRequired.to_string()).into_response()
+            }
+            AppError::AccountLocked(err) => (StatusCode::LOCKED, err.to_string()).into_response(),
+            AppError::LimitExceeded(err) => {
+                (StatusCode::FORBIDDEN, err.to_string()).into_response()
+            }
+            AppError::RiskDenied(err) => (StatusCode::FORBIDDEN, err.to_string()).into_response(),
+            AppError::TooManyLoginAttempts { retry_at } => too_many_attempts(
+                retry_at,
+                AppError::TooManyLoginAttempts { retry_at }.to_string(),
+            ),
+            AppError::TooManyCodeAttempts { retry_at } => too_many_attempts(
+                retry_at,
+                AppError::TooManyCodeAttempts { retry_at }.to_string(),
+            ),
             AppError::SqlxError(err) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
             }
@@ -36,3 +73,14 @@ impl IntoResponse for AppError {
         }
     }
 }
+
+fn too_many_attempts(retry_at: DateTime<Utc>, message: String) -> axum::response::Response {
+    let retry_after_seconds = (retry_at - Utc::now()).num_seconds().max(0) + 1;
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after_seconds.to_string())],
+        message,
+    )
+        .into_response()
+}
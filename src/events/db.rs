@@ -0,0 +1,35 @@
+use crate::db::Db;
+
+use super::AccountEvent;
+
+impl Db {
+    pub async fn get_account_events_after(
+        &self,
+        username: &str,
+        after_sequence: i64,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AccountEvent>> {
+        sqlx::query_as!(
+            AccountEvent,
+            "SELECT sequence, event_type, payload FROM account_events
+            WHERE username = $1 AND sequence > $2
+            ORDER BY sequence
+            LIMIT $3",
+            username,
+            after_sequence,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Sequence number of the user's newest event, 0 if there is none
+    pub async fn get_latest_event_sequence(&self, username: &str) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(MAX(sequence), 0) AS "sequence!" FROM account_events WHERE username = $1"#,
+            username
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+}
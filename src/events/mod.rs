@@ -0,0 +1,205 @@
+mod db;
+
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use sqlx::postgres::PgListener;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use crate::{app_state::AppState, db::Db, error::AppResult, utils::UserInfo};
+
+/// Postgres channel the `account_events` trigger notifies with the username of every new event
+const NOTIFY_CHANNEL: &str = "account_events";
+/// How many notifications slow streams can fall behind before they reload from the database
+const NOTIFICATION_BUFFER: usize = 1024;
+/// How long the listener waits before reconnecting after losing the database connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Most events sent at once, the rest follows right after
+const REPLAY_BATCH_SIZE: i64 = 500;
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(event_stream))
+        .with_state(app_state)
+}
+
+/// Fans the notifications of a single database connection out to all open event streams
+#[derive(Clone)]
+pub(crate) struct Events {
+    notifications: broadcast::Sender<String>,
+}
+
+impl Events {
+    /// Starts listening for new account events
+    pub fn init(db: &Db) -> Self {
+        let (notifications, _) = broadcast::channel(NOTIFICATION_BUFFER);
+
+        let events = Events {
+            notifications: notifications.clone(),
+        };
+
+        let pool = db.pool.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = forward_notifications(&pool, &notifications).await {
+                    tracing::error!("account event listener failed: {err}");
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+
+        events
+    }
+}
+
+async fn forward_notifications(
+    pool: &sqlx::PgPool,
+    notifications: &broadcast::Sender<String>,
+) -> sqlx::Result<()> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(NOTIFY_CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+        // Nobody is subscribed while no stream is open
+        let _ = notifications.send(notification.payload().to_string());
+    }
+}
+
+///Server-Sent Events stream of the logged in user's account events.
+///Every event carries its sequence number as id, reconnecting clients send the last one they
+///received in the Last-Event-ID header to get the events they missed.
+///A `balance.updated` event with the current balance follows every batch of events.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "Events",
+    params(
+        ("Last-Event-ID" = Option<i64>, Header, description = "Id of the last event received before reconnecting")
+    ),
+    responses(
+        (status = 200, description = "Event stream opened", content_type = "text/event-stream"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn event_stream(
+    State(db): State<Db>,
+    State(events): State<Events>,
+    UserInfo { username }: UserInfo,
+    headers: HeaderMap,
+) -> AppResult<impl IntoResponse> {
+    // Subscribe before reading the database so no event written in between is missed
+    let notifications = events.notifications.subscribe();
+
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    // New clients only get events from now on
+    let last_sequence = match last_event_id {
+        Some(last_event_id) => last_event_id,
+        None => db.get_latest_event_sequence(&username).await?,
+    };
+
+    let (sender, receiver) = mpsc::channel(REPLAY_BATCH_SIZE as usize);
+    tokio::spawn(async move {
+        if let Err(err) = stream_events(db, username, last_sequence, notifications, sender).await {
+            tracing::error!("failed to stream account events: {err}");
+        }
+    });
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), receiver))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Sends the user's events after `last_sequence` and then waits for new ones until the client disconnects
+async fn stream_events(
+    db: Db,
+    username: String,
+    mut last_sequence: i64,
+    mut notifications: broadcast::Receiver<String>,
+    sender: mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    // The client's balance may be stale, whether or not there are events to replay
+    let mut send_balance = true;
+
+    loop {
+        let account_events = db
+            .get_account_events_after(&username, last_sequence, REPLAY_BATCH_SIZE)
+            .await?;
+        let replayed_everything = (account_events.len() as i64) < REPLAY_BATCH_SIZE;
+
+        for account_event in account_events {
+            let event = Event::default()
+                .id(account_event.sequence.to_string())
+                .event(account_event.event_type)
+                .json_data(account_event.payload)?;
+            if sender.send(event).await.is_err() {
+                return Ok(());
+            }
+            last_sequence = account_event.sequence;
+            send_balance = true;
+        }
+
+        if !replayed_everything {
+            continue;
+        }
+
+        if send_balance {
+            let balance = db.get_balance_of_user(&username).await?;
+            let event = Event::default()
+                .event("balance.updated")
+                .json_data(balance)?;
+            if sender.send(event).await.is_err() {
+                return Ok(());
+            }
+            send_balance = false;
+        }
+
+        // Wait until the user has new events or the client went away
+        loop {
+            tokio::select! {
+                notification = notifications.recv() => match notification {
+                    Ok(notified_username) if notified_username == username => break,
+                    Ok(_) => {}
+                    // Some notifications were dropped, reloading finds any events among them
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = sender.closed() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// An entry of the account event log as read by the event stream
+#[derive(Serialize)]
+pub(crate) struct AccountEvent {
+    pub sequence: i64,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+}
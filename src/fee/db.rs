@@ -0,0 +1,211 @@
+use serde_json::json;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+    ledger::{EntryKind, EntryReference, REVENUE_ACCOUNT},
+    limit::DEFAULT_TIER,
+};
+
+use super::{FeeOperation, FeePlan, FeeSchedule, FeeScheduleOutcome, FeeScheduleRequest};
+
+impl Db {
+    /// Fee schedules of `operation` that apply to the tier of the user
+    pub async fn get_fee_plan(
+        conn: &mut PgConnection,
+        username: &str,
+        operation: FeeOperation,
+    ) -> sqlx::Result<FeePlan> {
+        let schedules = sqlx::query_as!(
+            FeeSchedule,
+            r#"SELECT schedule_id, operation AS "operation: FeeOperation", tier, min_amount,
+            flat_fee, percent_bps, min_fee, max_fee, created_at
+            FROM fee_schedules
+            WHERE operation = $1 AND (tier IS NULL OR tier = COALESCE(
+                (SELECT tier FROM user_limits WHERE username = $2), $3
+            ))
+            ORDER BY tier IS NULL, min_amount DESC"#,
+            operation as FeeOperation,
+            username,
+            DEFAULT_TIER
+        )
+        .fetch_all(conn)
+        .await?;
+
+        Ok(FeePlan { schedules })
+    }
+
+    /// Moves a fee from the account into the revenue account inside an already open database
+    /// transaction, as a journal entry of its own next to the movement it is charged for
+    pub async fn charge_fee(
+        conn: &mut PgConnection,
+        account_id: Uuid,
+        fee: i32,
+        reference: EntryReference,
+    ) -> sqlx::Result<()> {
+        Self::post_fee(conn, EntryKind::Fee, account_id, -(fee as i64), reference).await
+    }
+
+    /// Gives a fee charged with [`Db::charge_fee`] back to the account
+    pub async fn refund_fee(
+        conn: &mut PgConnection,
+        account_id: Uuid,
+        fee: i32,
+        reference: EntryReference,
+    ) -> sqlx::Result<()> {
+        Self::post_fee(
+            conn,
+            EntryKind::FeeRefund,
+            account_id,
+            fee as i64,
+            reference,
+        )
+        .await
+    }
+
+    async fn post_fee(
+        conn: &mut PgConnection,
+        kind: EntryKind,
+        account_id: Uuid,
+        amount: i64,
+        reference: EntryReference,
+    ) -> sqlx::Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let revenue_account_id = Self::system_account_id(&mut *conn, REVENUE_ACCOUNT).await?;
+        Self::post_journal_entry(
+            conn,
+            kind,
+            reference,
+            &[(account_id, amount), (revenue_account_id, -amount)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_fee_schedules(&self) -> sqlx::Result<Vec<FeeSchedule>> {
+        sqlx::query_as!(
+            FeeSchedule,
+            r#"SELECT schedule_id, operation AS "operation: FeeOperation", tier, min_amount,
+            flat_fee, percent_bps, min_fee, max_fee, created_at
+            FROM fee_schedules ORDER BY operation, tier NULLS FIRST, min_amount"#
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_fee_schedule(
+        &self,
+        fee_schedule_request: &FeeScheduleRequest,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<FeeScheduleOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        if let Some(tier) = &fee_schedule_request.tier {
+            let tier_exists = sqlx::query!(
+                r#"SELECT EXISTS(SELECT 1 FROM limit_tiers WHERE tier = $1) AS "exists!""#,
+                tier
+            )
+            .fetch_one(&mut *transaction)
+            .await?
+            .exists;
+            if !tier_exists {
+                return Ok(FeeScheduleOutcome::TierNotFound);
+            }
+        }
+
+        let previous = sqlx::query_as!(
+            FeeSchedule,
+            r#"SELECT schedule_id, operation AS "operation: FeeOperation", tier, min_amount,
+            flat_fee, percent_bps, min_fee, max_fee, created_at
+            FROM fee_schedules
+            WHERE operation = $1 AND tier IS NOT DISTINCT FROM $2 AND min_amount = $3
+            FOR UPDATE"#,
+            fee_schedule_request.operation as FeeOperation,
+            fee_schedule_request.tier,
+            fee_schedule_request.min_amount
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let fee_schedule = sqlx::query_as!(
+            FeeSchedule,
+            r#"INSERT INTO fee_schedules(operation, tier, min_amount, flat_fee, percent_bps,
+            min_fee, max_fee)
+            VALUES($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (operation, tier, min_amount) DO UPDATE SET flat_fee = EXCLUDED.flat_fee,
+            percent_bps = EXCLUDED.percent_bps, min_fee = EXCLUDED.min_fee,
+            max_fee = EXCLUDED.max_fee
+            RETURNING schedule_id, operation AS "operation: FeeOperation", tier, min_amount,
+            flat_fee, percent_bps, min_fee, max_fee, created_at"#,
+            fee_schedule_request.operation as FeeOperation,
+            fee_schedule_request.tier,
+            fee_schedule_request.min_amount,
+            fee_schedule_request.flat_fee,
+            fee_schedule_request.percent_bps,
+            fee_schedule_request.min_fee,
+            fee_schedule_request.max_fee
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::FeeChange,
+                target: Some(&format!("fee_schedule:{}", fee_schedule.schedule_id)),
+                before: previous.map(|previous| json!(previous)),
+                after: Some(json!(fee_schedule)),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(FeeScheduleOutcome::Saved(fee_schedule))
+    }
+
+    pub async fn delete_fee_schedule(
+        &self,
+        id: Uuid,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<bool> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(fee_schedule) = sqlx::query_as!(
+            FeeSchedule,
+            r#"DELETE FROM fee_schedules WHERE schedule_id = $1
+            RETURNING schedule_id, operation AS "operation: FeeOperation", tier, min_amount,
+            flat_fee, percent_bps, min_fee, max_fee, created_at"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::FeeChange,
+                target: Some(&format!("fee_schedule:{id}")),
+                before: Some(json!(fee_schedule)),
+                after: None,
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+}
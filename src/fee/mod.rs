@@ -0,0 +1,302 @@
+mod db;
+
+use axum::{
+    extract::{Path, Query, State},
+    http,
+    response::IntoResponse,
+    routing::{delete, get},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    utils::{AdminRole, RoleInfo, StaffRole, UserInfo},
+};
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/quote", get(fee_quote))
+        .with_state(app_state)
+}
+
+pub(super) fn get_admin_router(app_state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/fee-schedules",
+            get(fee_schedules_list).put(set_fee_schedule),
+        )
+        .route("/fee-schedules/:id", delete(delete_fee_schedule))
+        .with_state(app_state)
+}
+
+///Fee the logged in User would pay for a money movement right now
+#[utoipa::path(
+    get,
+    path = "/fees/quote",
+    tag = "Fees",
+    params(FeeQuoteQuery),
+    responses(
+        (status = 200, description = "Fee successfully quoted", body = FeeQuote),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 422, description = "Invalid operation or amount"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn fee_quote(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Query(query): Query<FeeQuoteQuery>,
+) -> AppResult<impl IntoResponse> {
+    query.validate()?;
+
+    let plan = Db::get_fee_plan(&mut *db.pool.acquire().await?, &username, query.operation).await?;
+
+    Ok(Json(plan.quote(query.operation, query.amount)))
+}
+
+///Every fee schedule
+#[utoipa::path(
+    get,
+    path = "/admin/fee-schedules",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Fee schedules successfully retreived", body = Vec<FeeSchedule>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn fee_schedules_list(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_fee_schedules().await?))
+}
+
+///Creates a fee schedule or replaces the one with the same operation, tier and minimum amount
+#[utoipa::path(
+    put,
+    path = "/admin/fee-schedules",
+    tag = "Admin",
+    request_body = FeeScheduleRequest,
+    responses(
+        (status = 200, description = "Fee schedule successfully saved", body = FeeSchedule),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "Tier not found"),
+        (status = 422, description = "Negative fee or amount, or minimum fee above maximum fee"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_fee_schedule(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Json(fee_schedule_request): Json<FeeScheduleRequest>,
+) -> AppResult<impl IntoResponse> {
+    fee_schedule_request.validate()?;
+    if let (Some(min_fee), Some(max_fee)) =
+        (fee_schedule_request.min_fee, fee_schedule_request.max_fee)
+    {
+        if min_fee > max_fee {
+            return Ok((
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+                "min_fee must not be above max_fee",
+            )
+                .into_response());
+        }
+    }
+
+    let outcome = db
+        .set_fee_schedule(&fee_schedule_request, &admin, &audit_context)
+        .await?;
+
+    Ok(match outcome {
+        FeeScheduleOutcome::Saved(fee_schedule) => Json(fee_schedule).into_response(),
+        FeeScheduleOutcome::TierNotFound => {
+            (http::StatusCode::NOT_FOUND, "Tier not found").into_response()
+        }
+    })
+}
+
+///Deletes a fee schedule, the movements it covered fall back to the remaining schedules
+#[utoipa::path(
+    delete,
+    path = "/admin/fee-schedules/{id}",
+    tag = "Admin",
+    params(
+        ("id" = Uuid, Path, description = "Fee schedule id")
+    ),
+    responses(
+        (status = 200, description = "Fee schedule successfully deleted"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "Fee schedule not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn delete_fee_schedule(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if !db.delete_fee_schedule(id, &admin, &audit_context).await? {
+        return Ok(http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(http::StatusCode::OK)
+}
+
+/// Stored as the `fee_operation` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "fee_operation", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FeeOperation {
+    /// Paid by the sender on top of the amount
+    Transfer,
+    /// Taken out of the deposited amount
+    Deposit,
+    /// Paid on top of the amount, given back if the payout fails
+    Withdrawal,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub(crate) struct FeeSchedule {
+    pub schedule_id: Uuid,
+    pub operation: FeeOperation,
+    /// Limit tier the schedule applies to, every tier if missing
+    pub tier: Option<String>,
+    /// Smallest amount the schedule applies to
+    pub min_amount: i32,
+    pub flat_fee: i32,
+    /// Hundredths of a percent of the amount, rounded up
+    pub percent_bps: i32,
+    pub min_fee: Option<i32>,
+    pub max_fee: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl FeeSchedule {
+    fn fee_for(&self, amount: i32) -> i32 {
+        let percentage = (amount as i64 * self.percent_bps as i64 + 9999) / 10000;
+        let mut fee = self.flat_fee as i64 + percentage;
+        if let Some(min_fee) = self.min_fee {
+            fee = fee.max(min_fee as i64);
+        }
+        if let Some(max_fee) = self.max_fee {
+            fee = fee.min(max_fee as i64);
+        }
+        fee.min(i32::MAX as i64) as i32
+    }
+}
+
+/// The fee schedules of one operation that apply to a user, schedules of the user's tier first
+/// and each group ordered from the highest minimum amount down
+pub(crate) struct FeePlan {
+    schedules: Vec<FeeSchedule>,
+}
+
+impl FeePlan {
+    /// Fee of a movement of `amount`, zero if no schedule covers it
+    pub(crate) fn fee(&self, amount: i32) -> i32 {
+        self.schedules
+            .iter()
+            .find(|schedule| schedule.min_amount <= amount)
+            .map_or(0, |schedule| schedule.fee_for(amount))
+    }
+
+    /// Fee of a deposit, which can take at most the deposited amount
+    pub(crate) fn deposit_fee(&self, amount: i32) -> i32 {
+        self.fee(amount).min(amount)
+    }
+
+    fn quote(&self, operation: FeeOperation, amount: i32) -> FeeQuote {
+        let (fee, balance_change) = match operation {
+            FeeOperation::Deposit => {
+                let fee = self.deposit_fee(amount);
+                (fee, amount as i64 - fee as i64)
+            }
+            FeeOperation::Transfer | FeeOperation::Withdrawal => {
+                let fee = self.fee(amount);
+                (fee, -(amount as i64 + fee as i64))
+            }
+        };
+
+        FeeQuote {
+            operation,
+            amount,
+            fee,
+            balance_change,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct FeeQuoteQuery {
+    pub operation: FeeOperation,
+    #[validate(range(min = 1))]
+    pub amount: i32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct FeeQuote {
+    pub operation: FeeOperation,
+    pub amount: i32,
+    pub fee: i32,
+    /// How the balance of the user changes, fee included
+    pub balance_change: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct FeeScheduleRequest {
+    pub operation: FeeOperation,
+    /// Limit tier the schedule applies to, every tier if missing
+    pub tier: Option<String>,
+    /// Smallest amount the schedule applies to
+    #[serde(default)]
+    #[validate(range(min = 0))]
+    pub min_amount: i32,
+    #[serde(default)]
+    #[validate(range(min = 0))]
+    pub flat_fee: i32,
+    /// Hundredths of a percent of the amount, rounded up
+    #[serde(default)]
+    #[validate(range(min = 0, max = 10000))]
+    pub percent_bps: i32,
+    #[validate(range(min = 0))]
+    pub min_fee: Option<i32>,
+    #[validate(range(min = 0))]
+    pub max_fee: Option<i32>,
+}
+
+pub(crate) enum FeeScheduleOutcome {
+    Saved(FeeSchedule),
+    TierNotFound,
+}
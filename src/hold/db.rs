@@ -0,0 +1,208 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditSource},
+    db::Db,
+    error::AppResult,
+    fee::FeeOperation,
+    ledger::Movement,
+    risk::{RiskEngine, TransferAttempt},
+    transaction::TransactionKind,
+};
+
+use super::{Hold, HoldOutcome, HoldRequest, HoldStatus};
+
+impl Db {
+    /// Places a hold on the funds of `username` inside an already open database transaction.
+    /// Nothing is written if the available balance is insufficient.
+    pub async fn place_hold(
+        conn: &mut PgConnection,
+        username: &str,
+        hold_request: &HoldRequest,
+    ) -> AppResult<HoldOutcome> {
+        let account = Self::lock_user_accounts(&mut *conn, &[username])
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)?;
+        account.ensure_allows(Movement::Send)?;
+
+        if account.available < hold_request.amount as i64 {
+            return Ok(HoldOutcome::InsufficientBalance);
+        }
+
+        let hold_id = sqlx::query!(
+            "INSERT INTO holds(payer, merchant, amount, expires_at)
+            VALUES($1, $2, $3, now() + make_interval(secs => $4)) RETURNING hold_id",
+            username,
+            hold_request.merchant,
+            hold_request.amount,
+            hold_request.expires_in_seconds as f64
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .hold_id;
+
+        Ok(HoldOutcome::Placed(hold_id))
+    }
+
+    pub async fn get_hold(&self, id: Uuid) -> sqlx::Result<Hold> {
+        sqlx::query_as!(
+            Hold,
+            r#"SELECT hold_id, payer, merchant, amount, status AS "status: HoldStatus",
+            captured_amount, capture_transaction_id, expires_at, created_at, updated_at
+            FROM holds WHERE hold_id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Moves `amount` of the held funds, or all of them when no amount is given, from the payer
+    /// to the merchant inside an already open database transaction. The rest of the hold is released.
+    /// The limits and risk rules of the payer apply, a capture can't be held for a review.
+    pub async fn capture_hold(
+        conn: &mut PgConnection,
+        id: Uuid,
+        username: &str,
+        amount: Option<i32>,
+        risk_engine: &RiskEngine,
+        audit_context: &AuditContext,
+    ) -> AppResult<HoldOutcome> {
+        let Some(hold) = sqlx::query!(
+            r#"SELECT payer, merchant, amount, status = 'active' AND expires_at > now() AS "active!"
+            FROM holds WHERE hold_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Ok(HoldOutcome::NotFound);
+        };
+
+        if hold.merchant != username {
+            return Ok(HoldOutcome::NotMerchant);
+        }
+
+        if !hold.active {
+            return Ok(HoldOutcome::NotActive);
+        }
+
+        let amount = amount.unwrap_or(hold.amount);
+        if amount > hold.amount {
+            return Ok(HoldOutcome::ExceedsHeldAmount);
+        }
+
+        let accounts =
+            Self::lock_user_accounts(&mut *conn, &[hold.payer.as_str(), hold.merchant.as_str()])
+                .await?;
+
+        let find_account = |username: &str| {
+            accounts
+                .iter()
+                .find(|account| account.username == username)
+                .ok_or(sqlx::Error::RowNotFound)
+        };
+        let payer_account = find_account(&hold.payer)?;
+        let merchant_account = find_account(&hold.merchant)?;
+        payer_account.ensure_allows(Movement::Send)?;
+        merchant_account.ensure_allows(Movement::Receive)?;
+
+        // The captured amount is covered, the held funds were excluded from every spend since the
+        // hold was placed. The fee has to come from the released rest or the available balance
+        let fee = Self::get_fee_plan(&mut *conn, &hold.payer, FeeOperation::Transfer)
+            .await?
+            .fee(amount);
+        if payer_account.available + (hold.amount as i64) < amount as i64 + fee as i64 {
+            return Ok(HoldOutcome::InsufficientBalance);
+        }
+
+        // Limits apply when the money moves, like for any other transfer of the payer
+        Self::get_limit_usage(&mut *conn, &hold.payer)
+            .await?
+            .check_transfers(&[amount as i64])?;
+
+        risk_engine
+            .enforce(
+                &mut *conn,
+                &TransferAttempt {
+                    from_user: &hold.payer,
+                    transfers: vec![(&hold.merchant, amount as i64)],
+                },
+            )
+            .await?;
+
+        // A captured hold is a regular transfer the merchant can refund, with the fee of a transfer
+        let transaction_id = Self::record_transfer(
+            &mut *conn,
+            payer_account,
+            merchant_account,
+            amount,
+            fee,
+            TransactionKind::Transfer,
+            None,
+            &AuditSource {
+                actor: Some(username),
+                context: audit_context,
+            },
+        )
+        .await?;
+
+        sqlx::query!(
+            "UPDATE holds SET status = 'captured', captured_amount = $1, capture_transaction_id = $2,
+            updated_at = now()
+            WHERE hold_id = $3",
+            amount,
+            transaction_id,
+            id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(HoldOutcome::Captured(transaction_id))
+    }
+
+    pub async fn void_hold(&self, id: Uuid, username: &str) -> sqlx::Result<HoldOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(hold) = sqlx::query!(
+            r#"SELECT merchant, status = 'active' AND expires_at > now() AS "active!"
+            FROM holds WHERE hold_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(HoldOutcome::NotFound);
+        };
+
+        if hold.merchant != username {
+            return Ok(HoldOutcome::NotMerchant);
+        }
+
+        if !hold.active {
+            return Ok(HoldOutcome::NotActive);
+        }
+
+        sqlx::query!(
+            "UPDATE holds SET status = 'voided', updated_at = now() WHERE hold_id = $1",
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(HoldOutcome::Voided)
+    }
+
+    /// Marks active holds past their expiry as expired and returns how many there were
+    pub async fn expire_holds(&self) -> sqlx::Result<u64> {
+        sqlx::query!(
+            "UPDATE holds SET status = 'expired', updated_at = now()
+            WHERE status = 'active' AND expires_at <= now()"
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+}
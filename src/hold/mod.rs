@@ -0,0 +1,339 @@
+mod db;
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    risk::RiskEngine,
+    totp::StepUpCode,
+    utils::UserInfo,
+};
+
+/// How often holds past their expiry are marked as expired
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", post(create_hold))
+        .route("/:id", get(get_hold_by_id))
+        .route("/:id/capture", post(capture_hold))
+        .route("/:id/void", post(void_hold))
+        .with_state(app_state)
+}
+
+/// Marks stale holds as expired in the background.
+/// Expired holds stop counting against the available balance even before they are marked.
+pub(crate) fn spawn_expiry_task(db: Db) {
+    tokio::spawn(async move {
+        loop {
+            match db.expire_holds().await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {expired} holds"),
+                Err(err) => tracing::error!("failed to expire holds: {err}"),
+            }
+            tokio::time::sleep(EXPIRY_INTERVAL).await;
+        }
+    });
+}
+
+///Holds funds of the logged in user for a merchant, who can capture them until the hold expires.
+///Returns the hold id
+#[utoipa::path(
+    post,
+    path = "/holds",
+    tag = "Holds",
+    request_body = HoldRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of placing another hold"),
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required above the step-up threshold of users with two-factor authentication")
+    ),
+    responses(
+        (status = 201, description = "Hold successfully placed", body = Uuid),
+        (status = 402, description = "Insufficient available balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "A fresh TOTP code is required for this amount"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_hold(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    StepUpCode(step_up_code): StepUpCode,
+    Json(hold_request): Json<HoldRequest>,
+) -> AppResult<impl IntoResponse> {
+    hold_request.validate()?;
+
+    let fingerprint = request_fingerprint("POST /holds", &hold_request)?;
+
+    let step_up_db = db.clone();
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                // Held funds can be captured without the payer, so the step-up happens here
+                step_up_db
+                    .require_step_up(
+                        conn,
+                        username,
+                        hold_request.amount as i64,
+                        step_up_code.as_deref(),
+                    )
+                    .await?;
+
+                let outcome = Db::place_hold(conn, username, &hold_request).await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/holds/{id}",
+    tag = "Holds",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Hold id")
+    ),
+    responses(
+        (status = 200, description = "Hold successfully retreived", body = Hold),
+        (status = 403, description = "User is not authorized to view this hold"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_hold_by_id(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let hold = db.get_hold(id).await?;
+
+    if (hold.payer != username) && (hold.merchant != username) {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this hold",
+        )
+            .into_response());
+    }
+
+    Ok(Json(hold).into_response())
+}
+
+///Moves the held funds, or a part of them, to the merchant and releases the rest.
+///Only the merchant of the hold can capture it, the payer pays the fee of a transfer. Returns the id of the resulting transaction
+#[utoipa::path(
+    post,
+    path = "/holds/{id}/capture",
+    tag = "Holds",
+    request_body = CaptureRequest,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Hold id"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of capturing again")
+    ),
+    responses(
+        (status = 200, description = "Hold successfully captured", body = Uuid),
+        (status = 402, description = "Insufficient available balance of the payer for the transfer fee"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the merchant of this hold, a transfer limit of the payer would be exceeded or a risk rule refused the transfer"),
+        (status = 404, description = "Hold not found"),
+        (status = 409, description = "The hold is not active anymore or the amount exceeds the held amount"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn capture_hold(
+    State(db): State<Db>,
+    State(risk_engine): State<RiskEngine>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+    Json(capture_request): Json<CaptureRequest>,
+) -> AppResult<impl IntoResponse> {
+    capture_request.validate()?;
+
+    let fingerprint = request_fingerprint(&format!("POST /holds/{id}/capture"), &capture_request)?;
+
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let outcome = Db::capture_hold(
+                    conn,
+                    id,
+                    username,
+                    capture_request.amount,
+                    &risk_engine,
+                    &audit_context,
+                )
+                .await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+///Releases the held funds without moving them. Only the merchant of the hold can void it
+#[utoipa::path(
+    post,
+    path = "/holds/{id}/void",
+    tag = "Holds",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Hold id")
+    ),
+    responses(
+        (status = 200, description = "Hold successfully voided"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the merchant of this hold"),
+        (status = 404, description = "Hold not found"),
+        (status = 409, description = "The hold is not active anymore"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn void_hold(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let outcome = db.void_hold(id, &username).await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+/// Stored as the `hold_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "hold_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HoldStatus {
+    Active,
+    Captured,
+    Voided,
+    Expired,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct Hold {
+    pub hold_id: Uuid,
+    pub payer: String,
+    pub merchant: String,
+    pub amount: i32,
+    pub status: HoldStatus,
+    pub captured_amount: Option<i32>,
+    pub capture_transaction_id: Option<Uuid>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct HoldRequest {
+    /// The user who can capture the held funds
+    #[validate(length(min = 4, max = 16))]
+    pub merchant: String,
+    #[validate(range(min = 1))]
+    pub amount: i32,
+    /// Seconds until the hold expires, at most 30 days
+    #[validate(range(min = 60, max = 2592000))]
+    #[serde(default = "default_hold_expiry")]
+    pub expires_in_seconds: i64,
+}
+
+fn default_hold_expiry() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct CaptureRequest {
+    /// The whole held amount when left out
+    #[validate(range(min = 1))]
+    pub amount: Option<i32>,
+}
+
+pub(crate) enum HoldOutcome {
+    Placed(Uuid),
+    Captured(Uuid),
+    Voided,
+    NotFound,
+    NotMerchant,
+    /// The hold was already captured, voided or is expired
+    NotActive,
+    ExceedsHeldAmount,
+    InsufficientBalance,
+}
+
+impl HoldOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            HoldOutcome::Placed(hold_id) => (http::StatusCode::CREATED, hold_id.to_string()),
+            HoldOutcome::Captured(transaction_id) => {
+                (http::StatusCode::OK, transaction_id.to_string())
+            }
+            HoldOutcome::Voided => (http::StatusCode::OK, String::new()),
+            HoldOutcome::NotFound => (http::StatusCode::NOT_FOUND, "Hold not found".to_string()),
+            HoldOutcome::NotMerchant => (
+                http::StatusCode::FORBIDDEN,
+                "Only the merchant of a hold can capture or void it".to_string(),
+            ),
+            HoldOutcome::NotActive => (
+                http::StatusCode::CONFLICT,
+                "The hold is not active anymore".to_string(),
+            ),
+            HoldOutcome::ExceedsHeldAmount => (
+                http::StatusCode::CONFLICT,
+                "The amount exceeds the held amount".to_string(),
+            ),
+            HoldOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "Insufficent available balance in user account".to_string(),
+            ),
+        }
+    }
+}
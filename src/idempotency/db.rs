@@ -0,0 +1,90 @@
+use sqlx::{PgConnection, Postgres, Transaction};
+
+use crate::{config::config, db::Db};
+
+pub(crate) enum IdempotencyClaim {
+    /// The key was not seen before. The returned transaction holds the claim on the key and
+    /// the operation must run inside it so that the money movement and the stored response
+    /// are committed together.
+    Fresh(Box<Transaction<'static, Postgres>>),
+    /// The key was already used for the same request, the stored response must be replayed.
+    Replay { status: i16, body: String },
+    /// The key was already used for a different request.
+    Mismatch,
+}
+
+impl Db {
+    pub async fn claim_idempotency_key(
+        &self,
+        username: &str,
+        idempotency_key: &str,
+        request_fingerprint: &str,
+    ) -> sqlx::Result<IdempotencyClaim> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE username = $1 AND idempotency_key = $2 AND expires_at < now()",
+            username,
+            idempotency_key
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        // A concurrent request with the same key blocks here until the first one commits or rolls back
+        let claimed = sqlx::query!(
+            "INSERT INTO idempotency_keys(username, idempotency_key, request_fingerprint, expires_at)
+            VALUES($1, $2, $3, now() + make_interval(secs => $4))
+            ON CONFLICT DO NOTHING RETURNING username",
+            username,
+            idempotency_key,
+            request_fingerprint,
+            config().IDEMPOTENCY_KEY_TTL_SECONDS as f64
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        if claimed.is_some() {
+            return Ok(IdempotencyClaim::Fresh(Box::new(transaction)));
+        }
+
+        let stored = sqlx::query!(
+            "SELECT request_fingerprint, response_status, response_body FROM idempotency_keys
+            WHERE username = $1 AND idempotency_key = $2",
+            username,
+            idempotency_key
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        transaction.rollback().await?;
+
+        if stored.request_fingerprint != request_fingerprint {
+            return Ok(IdempotencyClaim::Mismatch);
+        }
+
+        Ok(IdempotencyClaim::Replay {
+            status: stored.response_status.unwrap_or_default(),
+            body: stored.response_body.unwrap_or_default(),
+        })
+    }
+
+    pub async fn save_idempotent_response(
+        conn: &mut PgConnection,
+        username: &str,
+        idempotency_key: &str,
+        status: i16,
+        body: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE idempotency_keys SET response_status = $1, response_body = $2
+            WHERE username = $3 AND idempotency_key = $4",
+            status,
+            body,
+            username,
+            idempotency_key
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+mod db;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+
+use crate::{db::Db, error::AppResult};
+
+use db::IdempotencyClaim;
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Value of the optional `Idempotency-Key` header
+pub(crate) struct IdempotencyKey(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IDEMPOTENCY_KEY_HEADER) else {
+            return Ok(IdempotencyKey(None));
+        };
+
+        match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= 255 => {
+                Ok(IdempotencyKey(Some(key.to_string())))
+            }
+            _ => Err((
+                StatusCode::BAD_REQUEST,
+                "Idempotency-Key must be between 1 and 255 visible ASCII characters",
+            )
+                .into_response()),
+        }
+    }
+}
+
+/// Hash identifying a request, used to detect a key being reused with a different payload
+pub(crate) fn request_fingerprint<T: Serialize>(
+    route: &str,
+    request: &T,
+) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(route.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(request)?);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Runs `operation` for `username` in a database transaction. When an idempotency key is given the response is
+/// stored in the same transaction, so a retried request gets the original response replayed
+/// instead of executing the operation again.
+pub(crate) async fn run_idempotent<F>(
+    db: &Db,
+    username: &str,
+    IdempotencyKey(idempotency_key): IdempotencyKey,
+    request_fingerprint: String,
+    operation: F,
+) -> AppResult<Response>
+where
+    F: for<'c> FnOnce(
+        &'c mut PgConnection,
+        &'c str,
+    ) -> BoxFuture<'c, AppResult<(StatusCode, String)>>,
+{
+    let Some(idempotency_key) = idempotency_key else {
+        let mut transaction = db.pool.begin().await?;
+        let response = operation(&mut transaction, username).await?;
+        transaction.commit().await?;
+        return Ok(response.into_response());
+    };
+
+    match db
+        .claim_idempotency_key(username, &idempotency_key, &request_fingerprint)
+        .await?
+    {
+        IdempotencyClaim::Fresh(mut transaction) => {
+            let (status, body) = operation(&mut transaction, username).await?;
+            Db::save_idempotent_response(
+                &mut transaction,
+                username,
+                &idempotency_key,
+                status.as_u16() as i16,
+                &body,
+            )
+            .await?;
+            transaction.commit().await?;
+            Ok((status, body).into_response())
+        }
+        IdempotencyClaim::Replay { status, body } => {
+            let status = StatusCode::from_u16(status as u16).map_err(anyhow::Error::from)?;
+            Ok((status, body).into_response())
+        }
+        IdempotencyClaim::Mismatch => Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used with a different request",
+        )
+            .into_response()),
+    }
+}
@@ -0,0 +1,323 @@
+use chrono::{Datelike, NaiveDate, Utc};
+use serde_json::json;
+use sqlx::PgConnection;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+    ledger::{AccountStatus, EntryKind, EntryReference, INTEREST_EXPENSE_ACCOUNT},
+    limit::DEFAULT_TIER,
+};
+
+use super::{
+    is_month_end, InterestPayout, InterestRate, InterestRateOutcome, InterestRun,
+    InterestRunRecord, InterestSummary, MICROS_PER_UNIT,
+};
+
+impl Db {
+    /// Runs the interest job, oldest first, for every day since the first one it ran for that
+    /// it has not run for yet, up to yesterday
+    pub async fn catch_up_interest(&self) -> sqlx::Result<Vec<InterestRun>> {
+        let Some(yesterday) = Utc::now().date_naive().pred_opt() else {
+            return Ok(Vec::new());
+        };
+
+        let dates = sqlx::query_scalar!(
+            r#"SELECT day::DATE AS "day!" FROM generate_series(
+                COALESCE((SELECT MIN(run_date) FROM interest_runs), $1), $1, interval '1 day'
+            ) day
+            WHERE NOT EXISTS (SELECT 1 FROM interest_runs WHERE run_date = day::DATE)
+            ORDER BY day"#,
+            yesterday
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut runs = Vec::new();
+        for date in dates {
+            runs.push(self.run_interest(date).await?);
+        }
+
+        Ok(runs)
+    }
+
+    pub async fn get_last_interest_run_date(&self) -> sqlx::Result<Option<NaiveDate>> {
+        sqlx::query_scalar!("SELECT MAX(run_date) FROM interest_runs")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn is_interest_run(&self, date: NaiveDate) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM interest_runs WHERE run_date = $1) AS "run!""#,
+            date
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Accrues a day of interest on the closing balance of every account and pays out the month
+    /// if `date` is its last day. Accounts and months already done are skipped,
+    /// so running a day again never pays twice
+    pub async fn run_interest(&self, date: NaiveDate) -> sqlx::Result<InterestRun> {
+        let accruals = sqlx::query!(
+            "INSERT INTO interest_accruals(username, accrual_date, balance, annual_rate_bps,
+            amount_micros)
+            SELECT ledger_accounts.username, $1, closing.balance, interest_rates.annual_rate_bps,
+            FLOOR(closing.balance::NUMERIC * interest_rates.annual_rate_bps * $3::BIGINT / 10000 / 365)::BIGINT
+            FROM ledger_accounts
+            LEFT JOIN user_limits USING (username)
+            JOIN interest_rates ON interest_rates.tier = COALESCE(user_limits.tier, $2)
+            CROSS JOIN LATERAL (
+                SELECT postings.balance_after AS balance FROM postings
+                WHERE postings.account_id = ledger_accounts.account_id
+                AND postings.created_at < ($1::DATE + 1)::TIMESTAMP AT TIME ZONE 'UTC'
+                ORDER BY postings.created_at DESC, postings.posting_id DESC
+                LIMIT 1
+            ) closing
+            WHERE ledger_accounts.username IS NOT NULL AND ledger_accounts.status <> 'closed'
+            AND closing.balance > 0 AND interest_rates.annual_rate_bps > 0
+            ON CONFLICT (username, accrual_date) DO NOTHING",
+            date,
+            DEFAULT_TIER,
+            MICROS_PER_UNIT
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected() as i64;
+
+        let mut payouts = 0;
+        let mut paid_out = 0;
+        if is_month_end(date) {
+            let period = date.with_day(1).expect("every month has a first day");
+            let usernames = sqlx::query_scalar!(
+                "SELECT DISTINCT username FROM interest_accruals
+                WHERE accrual_date BETWEEN $1 AND $2",
+                period,
+                date
+            )
+            .fetch_all(&self.pool)
+            .await?;
+
+            for username in usernames {
+                if let Some(amount) = self.pay_interest(&username, period, date).await? {
+                    payouts += 1;
+                    paid_out += amount;
+                }
+            }
+        }
+
+        sqlx::query!(
+            "INSERT INTO interest_runs(run_date, accruals, payouts, paid_out)
+            VALUES($1, $2, $3, $4)
+            ON CONFLICT (run_date) DO UPDATE SET accruals = interest_runs.accruals + EXCLUDED.accruals,
+            payouts = interest_runs.payouts + EXCLUDED.payouts,
+            paid_out = interest_runs.paid_out + EXCLUDED.paid_out, completed_at = now()",
+            date,
+            accruals,
+            payouts,
+            paid_out
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(InterestRun {
+            run_date: date,
+            accruals,
+            payouts,
+            paid_out,
+        })
+    }
+
+    /// Pays the whole minor units of interest the user accrued up to `through` and was not paid
+    /// yet, as the payout of `period`. Returns None if the period was paid already, there is
+    /// less than one minor unit to pay or the account is closed
+    async fn pay_interest(
+        &self,
+        username: &str,
+        period: NaiveDate,
+        through: NaiveDate,
+    ) -> sqlx::Result<Option<i64>> {
+        let mut transaction = self.pool.begin().await?;
+
+        // Interest already earned is paid even if the account was frozen since. What a closed
+        // account did not get was forfeited when it was closed
+        let account = Self::lock_user_accounts(&mut transaction, &[username])
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)?;
+        if account.status == AccountStatus::Closed {
+            return Ok(None);
+        }
+
+        let amount = sqlx::query_scalar!(
+            r#"SELECT (FLOOR(COALESCE((
+                SELECT SUM(amount_micros) FROM interest_accruals
+                WHERE username = $1 AND accrual_date <= $2
+            ), 0) / $3::BIGINT) - COALESCE((
+                SELECT SUM(amount) FROM interest_payouts WHERE username = $1
+            ), 0))::BIGINT AS "amount!""#,
+            username,
+            through,
+            MICROS_PER_UNIT
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if amount <= 0 {
+            return Ok(None);
+        }
+
+        let Some(payout_id) = sqlx::query_scalar!(
+            "INSERT INTO interest_payouts(username, period, amount) VALUES($1, $2, $3)
+            ON CONFLICT (username, period) DO NOTHING
+            RETURNING payout_id",
+            username,
+            period,
+            amount
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let expense_account_id =
+            Self::system_account_id(&mut transaction, INTEREST_EXPENSE_ACCOUNT).await?;
+        Self::post_journal_entry(
+            &mut transaction,
+            EntryKind::Interest,
+            EntryReference::InterestPayout(payout_id),
+            &[(account.account_id, amount), (expense_account_id, -amount)],
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(Some(amount))
+    }
+
+    /// Gives up the interest the user accrued and was not paid yet, inside the database
+    /// transaction closing the account. Returns the forfeited millionths of a minor unit
+    pub async fn forfeit_interest(conn: &mut PgConnection, username: &str) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"INSERT INTO interest_forfeitures(username, amount_micros)
+            SELECT $1, (COALESCE((
+                SELECT SUM(amount_micros) FROM interest_accruals WHERE username = $1
+            ), 0) - COALESCE((
+                SELECT SUM(amount) FROM interest_payouts WHERE username = $1
+            ), 0) * $2::BIGINT)::BIGINT
+            RETURNING amount_micros"#,
+            username,
+            MICROS_PER_UNIT
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn get_interest_summary(&self, username: &str) -> sqlx::Result<InterestSummary> {
+        let summary = sqlx::query!(
+            r#"SELECT COALESCE(user_limits.tier, $2) AS "tier!",
+            COALESCE(interest_rates.annual_rate_bps, 0) AS "annual_rate_bps!",
+            (COALESCE((SELECT SUM(amount_micros) FROM interest_accruals WHERE username = $1), 0)
+            - COALESCE((SELECT SUM(amount) FROM interest_payouts WHERE username = $1), 0) * $3::BIGINT
+            - COALESCE((SELECT amount_micros FROM interest_forfeitures WHERE username = $1), 0)
+            )::BIGINT AS "unpaid_micros!"
+            FROM user_credentials
+            LEFT JOIN user_limits USING (username)
+            LEFT JOIN interest_rates ON interest_rates.tier = COALESCE(user_limits.tier, $2)
+            WHERE user_credentials.username = $1"#,
+            username,
+            DEFAULT_TIER,
+            MICROS_PER_UNIT
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let payouts = sqlx::query_as!(
+            InterestPayout,
+            "SELECT payout_id, period, amount, created_at FROM interest_payouts
+            WHERE username = $1 ORDER BY period DESC",
+            username
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(InterestSummary {
+            tier: summary.tier,
+            annual_rate_bps: summary.annual_rate_bps,
+            unpaid_micros: summary.unpaid_micros,
+            payouts,
+        })
+    }
+
+    pub async fn get_interest_rates(&self) -> sqlx::Result<Vec<InterestRate>> {
+        sqlx::query_as!(
+            InterestRate,
+            "SELECT tier, annual_rate_bps, updated_at FROM interest_rates ORDER BY tier"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_interest_rate(
+        &self,
+        tier: &str,
+        annual_rate_bps: i32,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<InterestRateOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let tier_exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM limit_tiers WHERE tier = $1) AS "exists!""#,
+            tier
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if !tier_exists {
+            return Ok(InterestRateOutcome::TierNotFound);
+        }
+
+        let previous = sqlx::query_scalar!(
+            "SELECT annual_rate_bps FROM interest_rates WHERE tier = $1 FOR UPDATE",
+            tier
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO interest_rates(tier, annual_rate_bps) VALUES($1, $2)
+            ON CONFLICT (tier) DO UPDATE SET annual_rate_bps = EXCLUDED.annual_rate_bps,
+            updated_at = now()",
+            tier,
+            annual_rate_bps
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::InterestRateChange,
+                target: Some(&format!("tier:{tier}")),
+                before: previous.map(|previous| json!({ "annual_rate_bps": previous })),
+                after: Some(json!({ "annual_rate_bps": annual_rate_bps })),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(InterestRateOutcome::Saved)
+    }
+
+    pub async fn get_interest_runs(&self) -> sqlx::Result<Vec<InterestRunRecord>> {
+        sqlx::query_as!(
+            InterestRunRecord,
+            "SELECT run_date, accruals, payouts, paid_out, completed_at FROM interest_runs
+            ORDER BY run_date DESC LIMIT 100"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
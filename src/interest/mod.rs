@@ -0,0 +1,330 @@
+mod db;
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    utils::{AdminRole, RoleInfo, StaffRole, UserInfo},
+};
+
+/// How often the interest job looks for days it has not run for yet
+const INTEREST_JOB_INTERVAL: Duration = Duration::from_secs(3600);
+/// Accruals are stored in millionths of a minor unit
+pub(crate) const MICROS_PER_UNIT: i64 = 1_000_000;
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_interest))
+        .with_state(app_state)
+}
+
+pub(super) fn get_admin_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/interest-rates", get(interest_rates_list))
+        .route("/interest-rates/:tier", put(set_interest_rate))
+        .route("/interest-runs", get(interest_runs_list).post(run_interest))
+        .with_state(app_state)
+}
+
+/// Accrues interest for every day that is over and was not run yet and pays it out at the end
+/// of each month. Starts with yesterday when it never ran before
+pub(crate) fn spawn_interest_job(db: Db) {
+    tokio::spawn(async move {
+        loop {
+            match db.catch_up_interest().await {
+                Ok(runs) => {
+                    for run in runs {
+                        tracing::info!(
+                            "interest for {}: {} accruals, {} payouts of {} in total",
+                            run.run_date,
+                            run.accruals,
+                            run.payouts,
+                            run.paid_out
+                        );
+                    }
+                }
+                Err(err) => tracing::error!("failed to run the interest job: {err}"),
+            }
+            tokio::time::sleep(INTEREST_JOB_INTERVAL).await;
+        }
+    });
+}
+
+/// Whether the monthly payout is due after accruing `date`
+fn is_month_end(date: NaiveDate) -> bool {
+    date.succ_opt().is_some_and(|next| next.day() == 1)
+}
+
+/// Last day of the month of `date`, the day its payout happens
+fn month_end(date: NaiveDate) -> Option<NaiveDate> {
+    date.with_day(1)?
+        .checked_add_months(Months::new(1))?
+        .pred_opt()
+}
+
+///Interest rate of the logged in User, interest accrued but not paid yet and past payouts
+#[utoipa::path(
+    get,
+    path = "/interest",
+    tag = "Interest",
+    responses(
+        (status = 200, description = "Interest successfully retreived", body = InterestSummary),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_interest(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_interest_summary(&username).await?))
+}
+
+///Interest rate of every tier that has one
+#[utoipa::path(
+    get,
+    path = "/admin/interest-rates",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Interest rates successfully retreived", body = Vec<InterestRate>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn interest_rates_list(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_interest_rates().await?))
+}
+
+///Sets the annual interest rate of a tier. It applies from the next day accrued on
+#[utoipa::path(
+    put,
+    path = "/admin/interest-rates/{tier}",
+    tag = "Admin",
+    request_body = InterestRateRequest,
+    params(
+        ("tier" = String, Path, description = "Name of the tier")
+    ),
+    responses(
+        (status = 200, description = "Interest rate successfully saved"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "Tier not found"),
+        (status = 422, description = "Rate outside of 0 to 10000"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_interest_rate(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(tier): Path<String>,
+    Json(interest_rate_request): Json<InterestRateRequest>,
+) -> AppResult<impl IntoResponse> {
+    interest_rate_request.validate()?;
+
+    let outcome = db
+        .set_interest_rate(
+            &tier,
+            interest_rate_request.annual_rate_bps,
+            &admin,
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Latest days the interest job ran for, newest first
+#[utoipa::path(
+    get,
+    path = "/admin/interest-runs",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Interest runs successfully retreived", body = Vec<InterestRunRecord>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn interest_runs_list(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_interest_runs().await?))
+}
+
+///Runs the interest job for a day that is over. Days and months already done are skipped,
+///so a day can be run again safely. Days have to be run in order and a month can't be run
+///anymore once it was paid out. Returns what this run added
+#[utoipa::path(
+    post,
+    path = "/admin/interest-runs",
+    tag = "Admin",
+    request_body = InterestRunRequest,
+    responses(
+        (status = 200, description = "Interest job successfully run", body = InterestRun),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 422, description = "The day is not over yet, comes after the next day to run or its month was paid out already"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn run_interest(
+    State(db): State<Db>,
+    _: RoleInfo<AdminRole>,
+    Json(interest_run_request): Json<InterestRunRequest>,
+) -> AppResult<impl IntoResponse> {
+    if interest_run_request.date >= Utc::now().date_naive() {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "Interest can only be run for days that are over",
+        )
+            .into_response());
+    }
+
+    // Skipped days would never be accrued, the catch-up only fills days after the first run
+    if let Some(next) = db
+        .get_last_interest_run_date()
+        .await?
+        .and_then(|last_run| last_run.succ_opt())
+    {
+        if interest_run_request.date > next {
+            return Ok((
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Days have to be run in order, the next day to run is {next}"),
+            )
+                .into_response());
+        }
+    }
+
+    // Interest accrued after the payout of its month would miss that payout
+    if let Some(month_end) = month_end(interest_run_request.date) {
+        if db.is_interest_run(month_end).await? {
+            return Ok((
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+                "The interest of this month was paid out already".to_string(),
+            )
+                .into_response());
+        }
+    }
+
+    Ok(Json(db.run_interest(interest_run_request.date).await?).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InterestRate {
+    pub tier: String,
+    /// Hundredths of a percent per year
+    pub annual_rate_bps: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct InterestRateRequest {
+    /// Hundredths of a percent per year
+    #[validate(range(min = 0, max = 10000))]
+    pub annual_rate_bps: i32,
+}
+
+pub(crate) enum InterestRateOutcome {
+    Saved,
+    TierNotFound,
+}
+
+impl InterestRateOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            InterestRateOutcome::Saved => (http::StatusCode::OK, String::new()),
+            InterestRateOutcome::TierNotFound => {
+                (http::StatusCode::NOT_FOUND, "Tier not found".to_string())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct InterestRunRequest {
+    /// Day to accrue interest for, in UTC
+    pub date: NaiveDate,
+}
+
+/// What a run of the interest job added
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InterestRun {
+    pub run_date: NaiveDate,
+    /// Accounts interest was accrued for
+    pub accruals: i64,
+    /// Monthly payouts made, only on the last day of a month
+    pub payouts: i64,
+    /// Sum of the payouts
+    pub paid_out: i64,
+}
+
+/// What all runs of the interest job for a day added together
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InterestRunRecord {
+    pub run_date: NaiveDate,
+    pub accruals: i64,
+    pub payouts: i64,
+    pub paid_out: i64,
+    /// When the day was last run
+    pub completed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InterestSummary {
+    pub tier: String,
+    /// Hundredths of a percent per year, zero if the tier earns no interest
+    pub annual_rate_bps: i32,
+    /// Accrued and not paid yet, in millionths of a minor unit
+    pub unpaid_micros: i64,
+    /// Newest first
+    pub payouts: Vec<InterestPayout>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InterestPayout {
+    pub payout_id: Uuid,
+    /// First day of the month the interest was paid for
+    pub period: NaiveDate,
+    pub amount: i64,
+    pub created_at: DateTime<Utc>,
+}
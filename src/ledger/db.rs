@@ -0,0 +1,97 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::db::Db;
+
+use super::{AccountStatus, EntryKind, EntryReference, LedgerAccount};
+
+impl Db {
+    pub async fn create_ledger_account(
+        conn: &mut PgConnection,
+        username: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query!("INSERT INTO ledger_accounts(username) VALUES($1)", username)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Locks the ledger accounts of the given users until the end of the database transaction.
+    /// Rows are always locked in account id order so concurrent money movements can't deadlock.
+    /// Holds are only placed while the account is locked, so the available balance stays valid
+    /// until the end of the database transaction.
+    pub async fn lock_user_accounts(
+        conn: &mut PgConnection,
+        usernames: &[&str],
+    ) -> sqlx::Result<Vec<LedgerAccount>> {
+        let usernames: Vec<String> = usernames
+            .iter()
+            .map(|username| username.to_string())
+            .collect();
+        sqlx::query_as!(
+            LedgerAccount,
+            r#"SELECT account_id, username AS "username!", balance,
+            status AS "status: AccountStatus", block_incoming,
+            balance - COALESCE((
+                SELECT SUM(amount) FROM holds
+                WHERE payer = ledger_accounts.username AND status = 'active' AND expires_at > now()
+            ), 0)::BIGINT AS "available!"
+            FROM ledger_accounts
+            WHERE username = ANY($1) ORDER BY account_id FOR UPDATE"#,
+            &usernames
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn system_account_id(
+        conn: &mut PgConnection,
+        system_name: &str,
+    ) -> sqlx::Result<Uuid> {
+        sqlx::query!(
+            "SELECT account_id FROM ledger_accounts WHERE system_name = $1",
+            system_name
+        )
+        .fetch_one(conn)
+        .await
+        .map(|record| record.account_id)
+    }
+
+    /// Writes a journal entry with its postings. Positive amounts increase the balance of an
+    /// account and negative amounts decrease it. The postings of an entry must sum to zero,
+    /// which the database verifies when the transaction commits.
+    pub async fn post_journal_entry(
+        conn: &mut PgConnection,
+        kind: EntryKind,
+        reference: EntryReference,
+        postings: &[(Uuid, i64)],
+    ) -> sqlx::Result<Uuid> {
+        let entry_id = sqlx::query!(
+            "INSERT INTO journal_entries(kind, transaction_id, withdrawal_id, adjustment_id,
+            interest_payout_id)
+            VALUES($1, $2, $3, $4, $5) RETURNING entry_id",
+            kind.as_str(),
+            reference.transaction_id(),
+            reference.withdrawal_id(),
+            reference.adjustment_id(),
+            reference.interest_payout_id()
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .entry_id;
+
+        let (account_ids, amounts): (Vec<Uuid>, Vec<i64>) = postings.iter().copied().unzip();
+
+        sqlx::query!(
+            "INSERT INTO postings(entry_id, account_id, amount)
+            SELECT $1, * FROM UNNEST($2::uuid[], $3::int8[])",
+            entry_id,
+            &account_ids,
+            &amounts
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(entry_id)
+    }
+}
@@ -0,0 +1,190 @@
+mod db;
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// System account that deposits are posted against
+pub(crate) const FUNDING_ACCOUNT: &str = "funding";
+/// System account holding the funds of withdrawals the payout provider has not settled yet
+pub(crate) const PAYOUTS_PENDING_ACCOUNT: &str = "payouts_pending";
+/// System account that completed withdrawals are posted to
+pub(crate) const PAYOUTS_ACCOUNT: &str = "payouts";
+/// System account that manual balance adjustments are posted against
+pub(crate) const ADJUSTMENTS_ACCOUNT: &str = "adjustments";
+/// System account that fees are credited to
+pub(crate) const REVENUE_ACCOUNT: &str = "revenue";
+/// System account that interest is paid from
+pub(crate) const INTEREST_EXPENSE_ACCOUNT: &str = "interest_expense";
+
+/// What a journal entry records, stored as text in `journal_entries.kind`
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum EntryKind {
+    Deposit,
+    Transfer,
+    Refund,
+    Reversal,
+    /// Funds of a withdrawal moved from the user into `payouts_pending`
+    WithdrawalReserve,
+    /// The payout provider paid the withdrawal out
+    WithdrawalComplete,
+    /// The payout provider failed the withdrawal, the funds go back to the user
+    WithdrawalRelease,
+    /// Manual correction of a user's balance by an admin
+    Adjustment,
+    /// Fee moved from the user into the revenue account
+    Fee,
+    /// Fee of a failed withdrawal given back to the user
+    FeeRefund,
+    /// Monthly interest paid from the interest expense account
+    Interest,
+}
+
+impl EntryKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Deposit => "deposit",
+            EntryKind::Transfer => "transfer",
+            EntryKind::Refund => "refund",
+            EntryKind::Reversal => "reversal",
+            EntryKind::WithdrawalReserve => "withdrawal_reserve",
+            EntryKind::WithdrawalComplete => "withdrawal_complete",
+            EntryKind::WithdrawalRelease => "withdrawal_release",
+            EntryKind::Adjustment => "adjustment",
+            EntryKind::Fee => "fee",
+            EntryKind::FeeRefund => "fee_refund",
+            EntryKind::Interest => "interest",
+        }
+    }
+}
+
+/// The business record a journal entry belongs to
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum EntryReference {
+    None,
+    Transaction(Uuid),
+    Withdrawal(Uuid),
+    Adjustment(Uuid),
+    InterestPayout(Uuid),
+}
+
+impl EntryReference {
+    fn transaction_id(self) -> Option<Uuid> {
+        match self {
+            EntryReference::Transaction(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    fn withdrawal_id(self) -> Option<Uuid> {
+        match self {
+            EntryReference::Withdrawal(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    fn adjustment_id(self) -> Option<Uuid> {
+        match self {
+            EntryReference::Adjustment(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    fn interest_payout_id(self) -> Option<Uuid> {
+        match self {
+            EntryReference::InterestPayout(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Stored as the `account_status` database enum on the ledger account of a user
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "account_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccountStatus {
+    Active,
+    /// Frozen by support or an admin, e.g. during an investigation.
+    /// Money can still come in unless incoming transfers are blocked as well.
+    Frozen,
+    /// Closed for good, no money moves in or out anymore
+    Closed,
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Closed => "closed",
+        })
+    }
+}
+
+/// The way money moves through an account, checked against the status of the account
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Movement {
+    /// Transfers and holds paid by the account
+    Send,
+    /// Transfers paid to the account
+    Receive,
+    Deposit,
+    Withdraw,
+}
+
+impl fmt::Display for Movement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Movement::Send => "send money",
+            Movement::Receive => "receive money",
+            Movement::Deposit => "take deposits",
+            Movement::Withdraw => "withdraw money",
+        })
+    }
+}
+
+/// A money movement was refused because of the status of one of the accounts involved
+#[derive(Error, Debug)]
+#[error("The account of {username} is {status} and can't {movement}")]
+pub(crate) struct AccountLocked {
+    pub username: String,
+    pub status: AccountStatus,
+    pub movement: Movement,
+}
+
+pub(crate) struct LedgerAccount {
+    pub account_id: Uuid,
+    pub username: String,
+    /// Cached running balance of the account
+    pub balance: i64,
+    /// Balance minus the active holds on the account, the amount that can be spent
+    pub available: i64,
+    pub status: AccountStatus,
+    /// Whether a frozen account refuses incoming transfers too
+    pub block_incoming: bool,
+}
+
+impl LedgerAccount {
+    /// Checks the status of the account allows the movement. Only meaningful while the account
+    /// is locked, so the status can't change before the movement is written.
+    pub(crate) fn ensure_allows(&self, movement: Movement) -> Result<(), AccountLocked> {
+        let allowed = match (self.status, movement) {
+            (AccountStatus::Active, _) => true,
+            (AccountStatus::Frozen, Movement::Receive) => !self.block_incoming,
+            (AccountStatus::Frozen, _) | (AccountStatus::Closed, _) => false,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(AccountLocked {
+                username: self.username.clone(),
+                status: self.status,
+                movement,
+            })
+        }
+    }
+}
@@ -1,16 +1,37 @@
+mod admin;
 mod api_doc;
+mod api_key;
 mod app_state;
+mod audit;
 mod balance;
+mod checkout;
 mod config;
 mod db;
 mod error;
+mod events;
+mod fee;
+mod hold;
+mod idempotency;
+mod interest;
+mod ledger;
+mod limit;
+mod login_attempt;
+mod notifier;
+mod payment_request;
+mod payout;
+mod risk;
+mod schedule;
+mod statement;
+mod totp;
 mod transaction;
 mod user;
 mod utils;
+mod webhook;
+mod withdrawal;
 
 use api_doc::ApiDoc;
 use app_state::AppState;
-use axum::Router;
+use axum::{middleware, Router};
 
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
@@ -19,9 +40,47 @@ pub async fn get_router() -> anyhow::Result<Router> {
     let app_state = AppState::init().await?;
 
     Ok(Router::new()
-        .nest("/users", user::get_router(app_state.clone()))
+        .nest(
+            "/users",
+            user::get_router(app_state.clone())
+                .merge(totp::get_router(app_state.clone()))
+                .merge(login_attempt::get_router(app_state.clone())),
+        )
         .nest("/transactions", transaction::get_router(app_state.clone()))
-        .nest("/balance", balance::get_router(app_state.clone()))
+        .nest(
+            "/balance",
+            balance::get_router(app_state.clone()).merge(withdrawal::get_router(app_state.clone())),
+        )
+        .nest("/holds", hold::get_router(app_state.clone()))
+        .nest(
+            "/payment-requests",
+            payment_request::get_router(app_state.clone()),
+        )
+        .nest(
+            "/checkout-sessions",
+            checkout::get_router(app_state.clone()),
+        )
+        .nest(
+            "/scheduled-transfers",
+            schedule::get_router(app_state.clone()),
+        )
+        .nest("/payouts", payout::get_router(app_state.clone()))
+        .nest("/webhooks", webhook::get_router(app_state.clone()))
+        .nest("/events", events::get_router(app_state.clone()))
+        .nest("/statements", statement::get_router(app_state.clone()))
+        .nest("/api-keys", api_key::get_router(app_state.clone()))
+        .nest("/limits", limit::get_router(app_state.clone()))
+        .nest("/fees", fee::get_router(app_state.clone()))
+        .nest("/interest", interest::get_router(app_state.clone()))
+        .nest(
+            "/admin",
+            admin::get_router(app_state.clone())
+                .merge(audit::get_router(app_state.clone()))
+                .merge(limit::get_admin_router(app_state.clone()))
+                .merge(risk::get_admin_router(app_state.clone()))
+                .merge(fee::get_admin_router(app_state.clone()))
+                .merge(interest::get_admin_router(app_state.clone())),
+        )
         .merge(
             SwaggerUi::new("/docs")
                 .url("/docs/openapi.json", ApiDoc::openapi())
@@ -31,5 +90,6 @@ pub async fn get_router() -> anyhow::Result<Router> {
                         .request_snippets_enabled(true)
                         .persist_authorization(true),
                 ),
-        ))
+        )
+        .layer(middleware::from_fn(audit::assign_request_id)))
 }
@@ -0,0 +1,238 @@
+use serde_json::json;
+use sqlx::PgConnection;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+};
+
+use super::{LimitTier, LimitUsage, Limits, UserLimitsOutcome, UserLimitsRequest, DEFAULT_TIER};
+
+impl Db {
+    /// Effective limits of the user and their outgoing money and deposits in the limit windows.
+    /// Sent transfers and withdrawals that didn't fail are outgoing, counted when they are made.
+    /// Refunds and reversals don't give allowance back
+    pub async fn get_limit_usage(
+        conn: &mut PgConnection,
+        username: &str,
+    ) -> sqlx::Result<LimitUsage> {
+        sqlx::query_as!(
+            LimitUsage,
+            r#"WITH outgoing_movements AS (
+                SELECT amount, created_at, TRUE AS transfer FROM transactions
+                WHERE from_user = $1 AND kind = 'transfer' AND created_at > now() - interval '30 days'
+                UNION ALL
+                SELECT amount, created_at, FALSE FROM withdrawals
+                WHERE username = $1 AND status <> 'failed' AND created_at > now() - interval '30 days'
+            ),
+            outgoing AS (
+                SELECT
+                COALESCE(SUM(amount) FILTER (WHERE created_at > now() - interval '1 day'), 0)::BIGINT
+                    AS last_day,
+                COALESCE(SUM(amount) FILTER (WHERE created_at > now() - interval '7 days'), 0)::BIGINT
+                    AS last_week,
+                COALESCE(SUM(amount), 0)::BIGINT AS last_month,
+                COUNT(*) FILTER (WHERE transfer AND created_at > now() - interval '1 hour') AS last_hour
+                FROM outgoing_movements
+            ),
+            deposits AS (
+                SELECT COALESCE(SUM(postings.amount), 0)::BIGINT AS last_day
+                FROM ledger_accounts
+                JOIN postings USING (account_id)
+                JOIN journal_entries USING (entry_id)
+                WHERE ledger_accounts.username = $1 AND journal_entries.kind = 'deposit'
+                AND postings.created_at > now() - interval '1 day'
+            )
+            SELECT limit_tiers.tier,
+            COALESCE(user_limits.max_transfer_amount, limit_tiers.max_transfer_amount)
+                AS max_transfer_amount,
+            COALESCE(user_limits.daily_outgoing, limit_tiers.daily_outgoing) AS daily_outgoing,
+            COALESCE(user_limits.weekly_outgoing, limit_tiers.weekly_outgoing) AS weekly_outgoing,
+            COALESCE(user_limits.monthly_outgoing, limit_tiers.monthly_outgoing) AS monthly_outgoing,
+            COALESCE(user_limits.hourly_transfers, limit_tiers.hourly_transfers) AS hourly_transfers,
+            COALESCE(user_limits.daily_deposit, limit_tiers.daily_deposit) AS daily_deposit,
+            outgoing.last_day AS "outgoing_last_day!",
+            outgoing.last_week AS "outgoing_last_week!",
+            outgoing.last_month AS "outgoing_last_month!",
+            outgoing.last_hour AS "transfers_last_hour!",
+            deposits.last_day AS "deposits_last_day!"
+            FROM limit_tiers
+            LEFT JOIN user_limits ON user_limits.username = $1
+            CROSS JOIN outgoing
+            CROSS JOIN deposits
+            WHERE limit_tiers.tier = COALESCE(user_limits.tier, $2)"#,
+            username,
+            DEFAULT_TIER
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn get_limit_tiers(&self) -> sqlx::Result<Vec<LimitTier>> {
+        sqlx::query!(
+            "SELECT tier, max_transfer_amount, daily_outgoing, weekly_outgoing, monthly_outgoing,
+            hourly_transfers, daily_deposit, updated_at
+            FROM limit_tiers ORDER BY tier"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map(|records| {
+            records
+                .into_iter()
+                .map(|record| LimitTier {
+                    tier: record.tier,
+                    limits: Limits {
+                        max_transfer_amount: record.max_transfer_amount,
+                        daily_outgoing: record.daily_outgoing,
+                        weekly_outgoing: record.weekly_outgoing,
+                        monthly_outgoing: record.monthly_outgoing,
+                        hourly_transfers: record.hourly_transfers,
+                        daily_deposit: record.daily_deposit,
+                    },
+                    updated_at: record.updated_at,
+                })
+                .collect()
+        })
+    }
+
+    pub async fn set_limit_tier(
+        &self,
+        tier: &str,
+        limits: &Limits,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let previous = sqlx::query_as!(
+            Limits,
+            "SELECT max_transfer_amount, daily_outgoing, weekly_outgoing, monthly_outgoing,
+            hourly_transfers, daily_deposit
+            FROM limit_tiers WHERE tier = $1 FOR UPDATE",
+            tier
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO limit_tiers(tier, max_transfer_amount, daily_outgoing, weekly_outgoing,
+            monthly_outgoing, hourly_transfers, daily_deposit)
+            VALUES($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (tier) DO UPDATE SET max_transfer_amount = EXCLUDED.max_transfer_amount,
+            daily_outgoing = EXCLUDED.daily_outgoing, weekly_outgoing = EXCLUDED.weekly_outgoing,
+            monthly_outgoing = EXCLUDED.monthly_outgoing,
+            hourly_transfers = EXCLUDED.hourly_transfers, daily_deposit = EXCLUDED.daily_deposit,
+            updated_at = now()",
+            tier,
+            limits.max_transfer_amount,
+            limits.daily_outgoing,
+            limits.weekly_outgoing,
+            limits.monthly_outgoing,
+            limits.hourly_transfers,
+            limits.daily_deposit
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::LimitChange,
+                target: Some(&format!("tier:{tier}")),
+                before: previous.map(|previous| json!(previous)),
+                after: Some(json!(limits)),
+            },
+        )
+        .await?;
+
+        transaction.commit().await
+    }
+
+    pub async fn set_user_limits(
+        &self,
+        username: &str,
+        user_limits_request: &UserLimitsRequest,
+        changed_by: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<UserLimitsOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(user) = sqlx::query!(
+            r#"SELECT user_limits.tier AS "tier?", user_limits.max_transfer_amount,
+            user_limits.daily_outgoing, user_limits.weekly_outgoing, user_limits.monthly_outgoing,
+            user_limits.hourly_transfers, user_limits.daily_deposit
+            FROM user_credentials LEFT JOIN user_limits USING (username)
+            WHERE user_credentials.username = $1
+            FOR UPDATE OF user_credentials"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(UserLimitsOutcome::UserNotFound);
+        };
+
+        let tier_exists = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM limit_tiers WHERE tier = $1) AS "exists!""#,
+            user_limits_request.tier
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .exists;
+        if !tier_exists {
+            return Ok(UserLimitsOutcome::TierNotFound);
+        }
+
+        let overrides = &user_limits_request.overrides;
+        sqlx::query!(
+            "INSERT INTO user_limits(username, tier, max_transfer_amount, daily_outgoing,
+            weekly_outgoing, monthly_outgoing, hourly_transfers, daily_deposit)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (username) DO UPDATE SET tier = EXCLUDED.tier,
+            max_transfer_amount = EXCLUDED.max_transfer_amount,
+            daily_outgoing = EXCLUDED.daily_outgoing, weekly_outgoing = EXCLUDED.weekly_outgoing,
+            monthly_outgoing = EXCLUDED.monthly_outgoing,
+            hourly_transfers = EXCLUDED.hourly_transfers, daily_deposit = EXCLUDED.daily_deposit,
+            updated_at = now()",
+            username,
+            user_limits_request.tier,
+            overrides.max_transfer_amount,
+            overrides.daily_outgoing,
+            overrides.weekly_outgoing,
+            overrides.monthly_outgoing,
+            overrides.hourly_transfers,
+            overrides.daily_deposit
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let before = UserLimitsRequest {
+            tier: user.tier.unwrap_or_else(|| DEFAULT_TIER.to_string()),
+            overrides: Limits {
+                max_transfer_amount: user.max_transfer_amount,
+                daily_outgoing: user.daily_outgoing,
+                weekly_outgoing: user.weekly_outgoing,
+                monthly_outgoing: user.monthly_outgoing,
+                hourly_transfers: user.hourly_transfers,
+                daily_deposit: user.daily_deposit,
+            },
+        };
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(changed_by),
+                action: AuditAction::LimitChange,
+                target: Some(username),
+                before: Some(json!(before)),
+                after: Some(json!(user_limits_request)),
+            },
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(UserLimitsOutcome::Saved)
+    }
+}
@@ -0,0 +1,433 @@
+mod db;
+
+use std::fmt;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    utils::{AdminRole, RoleInfo, StaffRole, UserInfo},
+};
+
+/// Tier of users without limits of their own
+pub(crate) const DEFAULT_TIER: &str = "standard";
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_allowance))
+        .with_state(app_state)
+}
+
+pub(super) fn get_admin_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/limit-tiers", get(limit_tiers_list))
+        .route("/limit-tiers/:tier", put(set_limit_tier))
+        .route("/users/:username/limits", get(get_user_allowance))
+        .route("/users/:username/limits", put(set_user_limits))
+        .with_state(app_state)
+}
+
+///Limits of the logged in User and how much of them is left
+#[utoipa::path(
+    get,
+    path = "/limits",
+    tag = "Limits",
+    responses(
+        (status = 200, description = "Allowance successfully retreived", body = Allowance),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_allowance(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    let usage = Db::get_limit_usage(&mut *db.pool.acquire().await?, &username).await?;
+
+    Ok(Json(usage.allowance()))
+}
+
+///Every limit tier
+#[utoipa::path(
+    get,
+    path = "/admin/limit-tiers",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Limit tiers successfully retreived", body = Vec<LimitTier>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn limit_tiers_list(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_limit_tiers().await?))
+}
+
+///Creates a limit tier or replaces the limits of an existing one. Left out limits don't apply
+#[utoipa::path(
+    put,
+    path = "/admin/limit-tiers/{tier}",
+    tag = "Admin",
+    request_body = Limits,
+    params(
+        ("tier" = String, Path, description = "Name of the tier")
+    ),
+    responses(
+        (status = 200, description = "Limit tier successfully saved"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 422, description = "Invalid tier name or negative limit"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_limit_tier(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(tier): Path<String>,
+    Json(limits): Json<Limits>,
+) -> AppResult<impl IntoResponse> {
+    limits.validate()?;
+    if tier.is_empty() || tier.len() > 50 {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "The name of a tier must be 1 to 50 characters long",
+        )
+            .into_response());
+    }
+
+    db.set_limit_tier(&tier, &limits, &admin, &audit_context)
+        .await?;
+
+    Ok(http::StatusCode::OK.into_response())
+}
+
+///Limits of a User and how much of them is left
+#[utoipa::path(
+    get,
+    path = "/admin/users/{username}/limits",
+    tag = "Admin",
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Allowance successfully retreived", body = Allowance),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 404, description = "User not found"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn get_user_allowance(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Path(username): Path<String>,
+) -> AppResult<impl IntoResponse> {
+    if !db.check_if_username_exists(&username).await? {
+        return Ok((http::StatusCode::NOT_FOUND, "User not found").into_response());
+    }
+
+    let usage = Db::get_limit_usage(&mut *db.pool.acquire().await?, &username).await?;
+
+    Ok(Json(usage.allowance()).into_response())
+}
+
+///Moves a User to a tier and sets the limits overriding the ones of the tier.
+///Left out limits are taken from the tier
+#[utoipa::path(
+    put,
+    path = "/admin/users/{username}/limits",
+    tag = "Admin",
+    request_body = UserLimitsRequest,
+    params(
+        ("username" = String, Path, description = "Username")
+    ),
+    responses(
+        (status = 200, description = "Limits successfully saved"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "User or tier not found"),
+        (status = 422, description = "Negative limit"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_user_limits(
+    State(db): State<Db>,
+    RoleInfo {
+        username: admin, ..
+    }: RoleInfo<AdminRole>,
+    audit_context: AuditContext,
+    Path(username): Path<String>,
+    Json(user_limits_request): Json<UserLimitsRequest>,
+) -> AppResult<impl IntoResponse> {
+    user_limits_request.validate()?;
+
+    let outcome = db
+        .set_user_limits(&username, &user_limits_request, &admin, &audit_context)
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+/// A limit a money movement would go over
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LimitKind {
+    MaxTransferAmount,
+    DailyOutgoing,
+    WeeklyOutgoing,
+    MonthlyOutgoing,
+    HourlyTransfers,
+    DailyDeposit,
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LimitKind::MaxTransferAmount => "max_transfer_amount",
+            LimitKind::DailyOutgoing => "daily_outgoing",
+            LimitKind::WeeklyOutgoing => "weekly_outgoing",
+            LimitKind::MonthlyOutgoing => "monthly_outgoing",
+            LimitKind::HourlyTransfers => "hourly_transfers",
+            LimitKind::DailyDeposit => "daily_deposit",
+        })
+    }
+}
+
+/// A money movement was refused because it goes over one of the limits of the user
+#[derive(Error, Debug)]
+#[error("The {kind} limit of {limit} would be exceeded, {remaining} left")]
+pub(crate) struct LimitExceeded {
+    pub kind: LimitKind,
+    pub limit: i64,
+    pub remaining: i64,
+}
+
+/// Effective limits of a user together with what they used of them. Only meaningful for checks
+/// while the account of the user is locked, so no other movement can be written in between
+pub(crate) struct LimitUsage {
+    pub tier: String,
+    pub max_transfer_amount: Option<i64>,
+    pub daily_outgoing: Option<i64>,
+    pub weekly_outgoing: Option<i64>,
+    pub monthly_outgoing: Option<i64>,
+    pub hourly_transfers: Option<i64>,
+    pub daily_deposit: Option<i64>,
+    pub outgoing_last_day: i64,
+    pub outgoing_last_week: i64,
+    pub outgoing_last_month: i64,
+    pub transfers_last_hour: i64,
+    pub deposits_last_day: i64,
+}
+
+impl LimitUsage {
+    /// Checks sending transfers of the given amounts at once
+    pub(crate) fn check_transfers(&self, amounts: &[i64]) -> Result<(), LimitExceeded> {
+        if let Some(max_transfer_amount) = self.max_transfer_amount {
+            if amounts.iter().any(|amount| *amount > max_transfer_amount) {
+                return Err(LimitExceeded {
+                    kind: LimitKind::MaxTransferAmount,
+                    limit: max_transfer_amount,
+                    remaining: max_transfer_amount,
+                });
+            }
+        }
+
+        self.check_outgoing(amounts.iter().sum())?;
+        check_window(
+            LimitKind::HourlyTransfers,
+            self.hourly_transfers,
+            self.transfers_last_hour,
+            amounts.len() as i64,
+        )
+    }
+
+    /// Withdrawals only count against the outgoing windows
+    pub(crate) fn check_withdrawal(&self, amount: i64) -> Result<(), LimitExceeded> {
+        self.check_outgoing(amount)
+    }
+
+    fn check_outgoing(&self, total: i64) -> Result<(), LimitExceeded> {
+        check_window(
+            LimitKind::DailyOutgoing,
+            self.daily_outgoing,
+            self.outgoing_last_day,
+            total,
+        )?;
+        check_window(
+            LimitKind::WeeklyOutgoing,
+            self.weekly_outgoing,
+            self.outgoing_last_week,
+            total,
+        )?;
+        check_window(
+            LimitKind::MonthlyOutgoing,
+            self.monthly_outgoing,
+            self.outgoing_last_month,
+            total,
+        )
+    }
+
+    pub(crate) fn check_deposit(&self, amount: i64) -> Result<(), LimitExceeded> {
+        check_window(
+            LimitKind::DailyDeposit,
+            self.daily_deposit,
+            self.deposits_last_day,
+            amount,
+        )
+    }
+
+    fn allowance(self) -> Allowance {
+        Allowance {
+            tier: self.tier,
+            max_transfer_amount: self.max_transfer_amount,
+            daily_outgoing: LimitWindow::new(self.daily_outgoing, self.outgoing_last_day),
+            weekly_outgoing: LimitWindow::new(self.weekly_outgoing, self.outgoing_last_week),
+            monthly_outgoing: LimitWindow::new(self.monthly_outgoing, self.outgoing_last_month),
+            hourly_transfers: LimitWindow::new(self.hourly_transfers, self.transfers_last_hour),
+            daily_deposit: LimitWindow::new(self.daily_deposit, self.deposits_last_day),
+        }
+    }
+}
+
+fn check_window(
+    kind: LimitKind,
+    limit: Option<i64>,
+    used: i64,
+    requested: i64,
+) -> Result<(), LimitExceeded> {
+    match limit {
+        Some(limit) if used + requested > limit => Err(LimitExceeded {
+            kind,
+            limit,
+            remaining: (limit - used).max(0),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Limits of a tier or of a single user, a left out limit doesn't apply
+#[derive(Serialize, Deserialize, ToSchema, Validate, Default)]
+pub(crate) struct Limits {
+    /// Largest single transfer
+    #[validate(range(min = 0))]
+    pub max_transfer_amount: Option<i64>,
+    /// Sent or withdrawn in the last 24 hours
+    #[validate(range(min = 0))]
+    pub daily_outgoing: Option<i64>,
+    /// Sent or withdrawn in the last 7 days
+    #[validate(range(min = 0))]
+    pub weekly_outgoing: Option<i64>,
+    /// Sent or withdrawn in the last 30 days
+    #[validate(range(min = 0))]
+    pub monthly_outgoing: Option<i64>,
+    /// Number of transfers sent in the last hour
+    #[validate(range(min = 0))]
+    pub hourly_transfers: Option<i64>,
+    /// Deposited in the last 24 hours
+    #[validate(range(min = 0))]
+    pub daily_deposit: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct LimitTier {
+    pub tier: String,
+    #[serde(flatten)]
+    pub limits: Limits,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct UserLimitsRequest {
+    pub tier: String,
+    /// Limits overriding the ones of the tier
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub overrides: Limits,
+}
+
+pub(crate) enum UserLimitsOutcome {
+    Saved,
+    UserNotFound,
+    TierNotFound,
+}
+
+impl UserLimitsOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            UserLimitsOutcome::Saved => (http::StatusCode::OK, String::new()),
+            UserLimitsOutcome::UserNotFound => {
+                (http::StatusCode::NOT_FOUND, "User not found".to_string())
+            }
+            UserLimitsOutcome::TierNotFound => {
+                (http::StatusCode::NOT_FOUND, "Tier not found".to_string())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Allowance {
+    pub tier: String,
+    /// Largest single transfer, unlimited if missing
+    pub max_transfer_amount: Option<i64>,
+    pub daily_outgoing: LimitWindow,
+    pub weekly_outgoing: LimitWindow,
+    pub monthly_outgoing: LimitWindow,
+    pub hourly_transfers: LimitWindow,
+    pub daily_deposit: LimitWindow,
+}
+
+/// A limit over a rolling time window
+#[derive(Serialize, ToSchema)]
+pub(crate) struct LimitWindow {
+    /// Unlimited if missing
+    pub limit: Option<i64>,
+    pub used: i64,
+    pub remaining: Option<i64>,
+}
+
+impl LimitWindow {
+    fn new(limit: Option<i64>, used: i64) -> Self {
+        LimitWindow {
+            limit,
+            used,
+            remaining: limit.map(|limit| (limit - used).max(0)),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use sqlx::{PgConnection, Postgres, Transaction};
+
+use crate::{config::config, db::Db};
+
+use super::{LoginAttempt, LoginClient, LoginOutcome, RecentFailures};
+
+impl Db {
+    /// Starts the database transaction of a login, which holds a lock on the username until it
+    /// ends. Concurrent logins of the username wait, so each one sees the failures recorded before it
+    pub async fn begin_login(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Transaction<'static, Postgres>> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query!("SELECT pg_advisory_xact_lock(hashtext($1))", username)
+            .execute(&mut *transaction)
+            .await?;
+
+        Ok(transaction)
+    }
+
+    pub async fn record_login_attempt(
+        conn: &mut PgConnection,
+        username: &str,
+        client: &LoginClient,
+        outcome: LoginOutcome,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO login_attempts(username, ip_address, user_agent, outcome)
+            VALUES($1, $2, $3, $4)",
+            username,
+            client.ip_address,
+            client.user_agent,
+            outcome as LoginOutcome
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Failures of the username since its last successful login, within the lockout window
+    pub async fn get_username_login_failures(
+        conn: &mut PgConnection,
+        username: &str,
+    ) -> sqlx::Result<RecentFailures> {
+        sqlx::query_as!(
+            RecentFailures,
+            r#"SELECT COUNT(*) AS "failures!", MAX(created_at) AS last_failed_at
+            FROM login_attempts
+            WHERE username = $1 AND outcome = 'failed'
+            AND created_at > GREATEST(
+                now() - make_interval(secs => $2),
+                (SELECT MAX(created_at) FROM login_attempts
+                    WHERE username = $1 AND outcome = 'succeeded')
+            )"#,
+            username,
+            config().LOGIN_LOCKOUT_SECONDS as f64
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Failures from the address within the lockout window. A successful login doesn't reset
+    /// them, so an attacker can't clear the counter with an account of their own
+    pub async fn get_ip_login_failures(
+        conn: &mut PgConnection,
+        ip_address: &str,
+    ) -> sqlx::Result<RecentFailures> {
+        sqlx::query_as!(
+            RecentFailures,
+            r#"SELECT COUNT(*) AS "failures!", MAX(created_at) AS last_failed_at
+            FROM login_attempts
+            WHERE ip_address = $1 AND outcome = 'failed'
+            AND created_at > now() - make_interval(secs => $2)"#,
+            ip_address,
+            config().LOGIN_LOCKOUT_SECONDS as f64
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn get_login_attempts(
+        &self,
+        username: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<LoginAttempt>> {
+        sqlx::query_as!(
+            LoginAttempt,
+            r#"SELECT ip_address, user_agent, outcome AS "outcome: LoginOutcome", created_at
+            FROM login_attempts WHERE username = $1
+            ORDER BY created_at DESC LIMIT $2"#,
+            username,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+}
@@ -0,0 +1,130 @@
+mod db;
+
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, State},
+    http::{header::USER_AGENT, request::Parts},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, RequestPartsExt, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{app_state::AppState, config::config, db::Db, error::AppResult, utils::UserInfo};
+
+/// Attempts shown in the login history of a user
+const HISTORY_LIMIT: i64 = 100;
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/login-attempts", get(login_attempts_list))
+        .with_state(app_state)
+}
+
+///Most recent logins with the password of the logged in user, also failed and throttled ones
+#[utoipa::path(
+    get,
+    path = "/users/login-attempts",
+    tag = "User Management",
+    responses(
+        (status = 200, description = "Login attempts successfully retreived", body = Vec<LoginAttempt>),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn login_attempts_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_login_attempts(&username, HISTORY_LIMIT).await?))
+}
+
+/// Where a login comes from. The address is the peer of the connection, so behind a reverse
+/// proxy all logins share the address of the proxy
+pub(crate) struct LoginClient {
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LoginClient {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(address) = parts
+            .extract::<ConnectInfo<SocketAddr>>()
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        Ok(LoginClient {
+            ip_address: address.ip().to_string(),
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from),
+        })
+    }
+}
+
+/// Failed logins of a username or an address within the lockout window
+pub(crate) struct RecentFailures {
+    pub failures: i64,
+    pub last_failed_at: Option<DateTime<Utc>>,
+}
+
+impl RecentFailures {
+    /// Until when further logins are refused. The first failures are free, then the wait doubles
+    /// with every failure until `lockout_failures` locks logins for the whole lockout duration
+    fn blocked_until(&self, lockout_failures: i64) -> Option<DateTime<Utc>> {
+        let last_failed_at = self.last_failed_at?;
+        let free_failures = config().LOGIN_FREE_FAILURES;
+
+        if self.failures >= lockout_failures {
+            Some(last_failed_at + Duration::seconds(config().LOGIN_LOCKOUT_SECONDS))
+        } else if self.failures >= free_failures {
+            let exponent = (self.failures - free_failures).min(30) as u32;
+            Some(last_failed_at + Duration::seconds(2_i64.pow(exponent)))
+        } else {
+            None
+        }
+    }
+}
+
+/// Until when logins for this username from this address are refused, if they are
+pub(crate) fn login_blocked_until(
+    username_failures: &RecentFailures,
+    ip_failures: &RecentFailures,
+) -> Option<DateTime<Utc>> {
+    let blocked_until = username_failures
+        .blocked_until(config().LOGIN_LOCKOUT_FAILURES)
+        .max(ip_failures.blocked_until(config().LOGIN_IP_LOCKOUT_FAILURES))?;
+
+    (blocked_until > Utc::now()).then_some(blocked_until)
+}
+
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "login_outcome", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LoginOutcome {
+    Succeeded,
+    Failed,
+    /// Refused without checking the password because of too many failures
+    Throttled,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct LoginAttempt {
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub outcome: LoginOutcome,
+    pub created_at: DateTime<Utc>,
+}
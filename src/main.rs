@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use axum::{
     error_handling::HandleErrorLayer,
@@ -50,10 +50,13 @@ async fn main() -> anyhow::Result<()> {
     ));
     // Create a `TcpListener` using tokio.
     let listener = TcpListener::bind("0.0.0.0:80").await?;
-    // Run the server with graceful shutdown
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    // Run the server with graceful shutdown. The peer address is used to throttle logins
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
     Ok(())
 }
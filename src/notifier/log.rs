@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use axum::async_trait;
+use chrono::Utc;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use super::{Notification, Notifier};
+
+/// Notifier for local development. Every notification is logged and, if a file is configured,
+/// appended to it as a JSON line so tokens can be picked up from there.
+pub(crate) struct LogNotifier {
+    file: Option<PathBuf>,
+}
+
+impl LogNotifier {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        LogNotifier { file }
+    }
+}
+
+#[derive(Serialize)]
+struct NotificationLine<'a> {
+    username: &'a str,
+    sent_at: chrono::DateTime<Utc>,
+    #[serde(flatten)]
+    notification: &'a Notification,
+}
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn send(&self, username: &str, notification: &Notification) -> anyhow::Result<()> {
+        let line = serde_json::to_string(&NotificationLine {
+            username,
+            sent_at: Utc::now(),
+            notification,
+        })?;
+
+        let Some(file) = &self.file else {
+            tracing::info!("notification {line}");
+            return Ok(());
+        };
+
+        tracing::info!("notification for {username} written to {}", file.display());
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .await?;
+        file.write_all(format!("{line}\n").as_bytes()).await?;
+
+        Ok(())
+    }
+}
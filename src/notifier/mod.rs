@@ -0,0 +1,52 @@
+mod log;
+
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::config;
+
+pub(crate) use log::LogNotifier;
+
+/// Messages sent to users outside of the API
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Notification {
+    /// Token for `/users/password/reset`
+    PasswordReset {
+        token: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// The password was changed or reset, so the user notices if it wasn't them
+    PasswordChanged,
+}
+
+/// Channel that delivers notifications to users, e.g. email or SMS
+#[async_trait]
+pub(crate) trait Notifier: Send + Sync {
+    async fn send(&self, username: &str, notification: &Notification) -> anyhow::Result<()>;
+}
+
+/// The configured notifier
+#[derive(Clone)]
+pub(crate) struct Notifications {
+    notifier: Arc<dyn Notifier>,
+}
+
+impl Notifications {
+    /// Sets up the bundled notifier, which writes notifications to the log and optionally to a file
+    pub fn init() -> Self {
+        Notifications {
+            notifier: Arc::new(LogNotifier::new(config().NOTIFICATIONS_FILE.clone())),
+        }
+    }
+
+    /// Failures are logged and not returned, a notification is never worth failing a request
+    pub async fn send(&self, username: &str, notification: Notification) {
+        if let Err(err) = self.notifier.send(username, &notification).await {
+            tracing::error!("failed to notify {username}: {err}");
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditSource},
+    db::Db,
+    error::AppResult,
+    risk::{RiskDenied, RiskEngine},
+    transaction::{TransactionRequest, TransferOutcome},
+};
+
+use super::{CreatePaymentRequest, PaymentRequest, PaymentRequestOutcome, PaymentRequestStatus};
+
+impl Db {
+    pub async fn create_payment_request(
+        &self,
+        username: &str,
+        payment_request: &CreatePaymentRequest,
+    ) -> sqlx::Result<Uuid> {
+        sqlx::query!(
+            "INSERT INTO payment_requests(requester, payer, amount, note, expires_at)
+            VALUES($1, $2, $3, $4, now() + make_interval(secs => $5)) RETURNING payment_request_id",
+            username,
+            payment_request.payer,
+            payment_request.amount,
+            payment_request.note,
+            payment_request.expires_in_seconds as f64
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map(|record| record.payment_request_id)
+    }
+
+    pub async fn get_payment_request(&self, id: Uuid) -> sqlx::Result<PaymentRequest> {
+        sqlx::query_as!(
+            PaymentRequest,
+            r#"SELECT payment_request_id, requester, payer, amount, note,
+            status AS "status: PaymentRequestStatus", transaction_id, expires_at, created_at, updated_at
+            FROM payment_requests WHERE payment_request_id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_payment_requests_list(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<PaymentRequest>> {
+        sqlx::query_as!(
+            PaymentRequest,
+            r#"SELECT payment_request_id, requester, payer, amount, note,
+            status AS "status: PaymentRequestStatus", transaction_id, expires_at, created_at, updated_at
+            FROM payment_requests WHERE requester = $1 OR payer = $1
+            ORDER BY created_at DESC"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Pays the payment request from `username` inside an already open database transaction.
+    /// Payments above the step-up threshold need `step_up_code` like any other transfer
+    pub async fn accept_payment_request(
+        &self,
+        conn: &mut PgConnection,
+        id: Uuid,
+        username: &str,
+        risk_engine: &RiskEngine,
+        step_up_code: Option<&str>,
+        audit_context: &AuditContext,
+    ) -> AppResult<PaymentRequestOutcome> {
+        let Some(payment_request) = sqlx::query!(
+            r#"SELECT requester, payer, amount, status = 'pending' AND expires_at > now() AS "pending!"
+            FROM payment_requests WHERE payment_request_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Ok(PaymentRequestOutcome::NotFound);
+        };
+
+        if payment_request.payer != username {
+            return Ok(PaymentRequestOutcome::NotAllowed);
+        }
+
+        if !payment_request.pending {
+            return Ok(PaymentRequestOutcome::NotPending);
+        }
+
+        self.require_step_up(
+            &mut *conn,
+            username,
+            payment_request.amount as i64,
+            step_up_code,
+        )
+        .await?;
+
+        let transaction_id = match Self::process_transaction(
+            &mut *conn,
+            username,
+            &TransactionRequest {
+                to_user: payment_request.requester,
+                amount: payment_request.amount,
+            },
+            Some(risk_engine),
+            &AuditSource {
+                actor: Some(username),
+                context: audit_context,
+            },
+        )
+        .await?
+        {
+            TransferOutcome::Executed(transaction_id) => transaction_id,
+            TransferOutcome::InsufficientBalance => {
+                return Ok(PaymentRequestOutcome::InsufficientBalance)
+            }
+            TransferOutcome::ReviewRequired { rule, reason } => {
+                return Err(RiskDenied::review_required(rule, reason).into())
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE payment_requests SET status = 'accepted', transaction_id = $1, updated_at = now()
+            WHERE payment_request_id = $2",
+            transaction_id,
+            id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(PaymentRequestOutcome::Accepted(transaction_id))
+    }
+
+    /// Declines the payment request as its payer or cancels it as its requester
+    pub async fn close_payment_request(
+        &self,
+        id: Uuid,
+        username: &str,
+        status: PaymentRequestStatus,
+    ) -> sqlx::Result<PaymentRequestOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(payment_request) = sqlx::query!(
+            r#"SELECT requester, payer, status = 'pending' AND expires_at > now() AS "pending!"
+            FROM payment_requests WHERE payment_request_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(PaymentRequestOutcome::NotFound);
+        };
+
+        let allowed_user = match status {
+            PaymentRequestStatus::Declined => &payment_request.payer,
+            _ => &payment_request.requester,
+        };
+        if allowed_user != username {
+            return Ok(PaymentRequestOutcome::NotAllowed);
+        }
+
+        if !payment_request.pending {
+            return Ok(PaymentRequestOutcome::NotPending);
+        }
+
+        sqlx::query!(
+            "UPDATE payment_requests SET status = $1, updated_at = now() WHERE payment_request_id = $2",
+            status as PaymentRequestStatus,
+            id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(PaymentRequestOutcome::Closed)
+    }
+
+    /// Marks pending payment requests past their expiry as expired and returns how many there were
+    pub async fn expire_payment_requests(&self) -> sqlx::Result<u64> {
+        sqlx::query!(
+            "UPDATE payment_requests SET status = 'expired', updated_at = now()
+            WHERE status = 'pending' AND expires_at <= now()"
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+    }
+}
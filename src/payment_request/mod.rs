@@ -0,0 +1,368 @@
+mod db;
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    risk::RiskEngine,
+    totp::StepUpCode,
+    utils::UserInfo,
+};
+
+/// How often pending payment requests past their expiry are marked as expired
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", post(create_payment_request))
+        .route("/", get(payment_requests_list))
+        .route("/:id", get(get_payment_request_by_id))
+        .route("/:id/accept", post(accept_payment_request))
+        .route("/:id/decline", post(decline_payment_request))
+        .route("/:id/cancel", post(cancel_payment_request))
+        .with_state(app_state)
+}
+
+/// Marks stale payment requests as expired in the background.
+/// Requests past their expiry can't be accepted even before they are marked.
+pub(crate) fn spawn_expiry_task(db: Db) {
+    tokio::spawn(async move {
+        loop {
+            match db.expire_payment_requests().await {
+                Ok(0) => {}
+                Ok(expired) => tracing::info!("expired {expired} payment requests"),
+                Err(err) => tracing::error!("failed to expire payment requests: {err}"),
+            }
+            tokio::time::sleep(EXPIRY_INTERVAL).await;
+        }
+    });
+}
+
+///Asks another user for money. Returns the payment request id
+#[utoipa::path(
+    post,
+    path = "/payment-requests",
+    tag = "Payment Requests",
+    request_body = CreatePaymentRequest,
+    responses(
+        (status = 201, description = "Payment request successfully created", body = Uuid),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 422, description = "Invalid payment request"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_payment_request(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Json(payment_request): Json<CreatePaymentRequest>,
+) -> AppResult<impl IntoResponse> {
+    payment_request.validate()?;
+
+    if payment_request.payer == username {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "Users can't request money from themselves",
+        )
+            .into_response());
+    }
+
+    let payment_request_id = db
+        .create_payment_request(&username, &payment_request)
+        .await?;
+
+    Ok((http::StatusCode::CREATED, payment_request_id.to_string()).into_response())
+}
+
+///Payment requests sent or received by a User
+#[utoipa::path(
+    get,
+    path = "/payment-requests",
+    tag = "Payment Requests",
+    responses(
+        (status = 200, description = "Payment requests successfully retreived", body = Vec<PaymentRequest>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn payment_requests_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_payment_requests_list(&username).await?).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/payment-requests/{id}",
+    tag = "Payment Requests",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Payment request id")
+    ),
+    responses(
+        (status = 200, description = "Payment request successfully retreived", body = PaymentRequest),
+        (status = 403, description = "User is not authorized to view this payment request"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_payment_request_by_id(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let payment_request = db.get_payment_request(id).await?;
+
+    if (payment_request.requester != username) && (payment_request.payer != username) {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this payment request",
+        )
+            .into_response());
+    }
+
+    Ok(Json(payment_request).into_response())
+}
+
+///Pays a received payment request. Returns the id of the resulting transaction
+#[utoipa::path(
+    post,
+    path = "/payment-requests/{id}/accept",
+    tag = "Payment Requests",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Payment request id"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of paying again"),
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required above the step-up threshold of users with two-factor authentication")
+    ),
+    responses(
+        (status = 200, description = "Payment request successfully paid", body = Uuid),
+        (status = 402, description = "Insufficient balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the payer of this payment request, a fresh TOTP code is required for this amount, a transfer limit would be exceeded or a risk rule refused the transfer"),
+        (status = 404, description = "Payment request not found"),
+        (status = 409, description = "The payment request is not pending anymore"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn accept_payment_request(
+    State(db): State<Db>,
+    State(risk_engine): State<RiskEngine>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    StepUpCode(step_up_code): StepUpCode,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let fingerprint = request_fingerprint(&format!("POST /payment-requests/{id}/accept"), &())?;
+
+    let step_up_db = db.clone();
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let outcome = step_up_db
+                    .accept_payment_request(
+                        conn,
+                        id,
+                        username,
+                        &risk_engine,
+                        step_up_code.as_deref(),
+                        &audit_context,
+                    )
+                    .await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/payment-requests/{id}/decline",
+    tag = "Payment Requests",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Payment request id")
+    ),
+    responses(
+        (status = 200, description = "Payment request successfully declined"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the payer of this payment request"),
+        (status = 404, description = "Payment request not found"),
+        (status = 409, description = "The payment request is not pending anymore"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn decline_payment_request(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let outcome = db
+        .close_payment_request(id, &username, PaymentRequestStatus::Declined)
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+#[utoipa::path(
+    post,
+    path = "/payment-requests/{id}/cancel",
+    tag = "Payment Requests",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Payment request id")
+    ),
+    responses(
+        (status = 200, description = "Payment request successfully cancelled"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not the requester of this payment request"),
+        (status = 404, description = "Payment request not found"),
+        (status = 409, description = "The payment request is not pending anymore"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn cancel_payment_request(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let outcome = db
+        .close_payment_request(id, &username, PaymentRequestStatus::Cancelled)
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+/// Stored as the `payment_request_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "payment_request_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PaymentRequestStatus {
+    Pending,
+    /// Paid by the payer
+    Accepted,
+    /// Refused by the payer
+    Declined,
+    /// Withdrawn by the requester
+    Cancelled,
+    Expired,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct PaymentRequest {
+    pub payment_request_id: Uuid,
+    pub requester: String,
+    pub payer: String,
+    pub amount: i32,
+    pub note: Option<String>,
+    pub status: PaymentRequestStatus,
+    /// The transfer that paid the request once it is accepted
+    pub transaction_id: Option<Uuid>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct CreatePaymentRequest {
+    /// The user asked to pay
+    #[validate(length(min = 4, max = 16))]
+    pub payer: String,
+    #[validate(range(min = 1))]
+    pub amount: i32,
+    #[validate(length(max = 255))]
+    pub note: Option<String>,
+    /// Seconds until the request expires, at most 30 days
+    #[validate(range(min = 60, max = 2592000))]
+    #[serde(default = "default_payment_request_expiry")]
+    pub expires_in_seconds: i64,
+}
+
+fn default_payment_request_expiry() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+pub(crate) enum PaymentRequestOutcome {
+    Accepted(Uuid),
+    Closed,
+    NotFound,
+    /// The user is not the party allowed to perform the action
+    NotAllowed,
+    /// The request was already accepted, declined, cancelled or is expired
+    NotPending,
+    InsufficientBalance,
+}
+
+impl PaymentRequestOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            PaymentRequestOutcome::Accepted(transaction_id) => {
+                (http::StatusCode::OK, transaction_id.to_string())
+            }
+            PaymentRequestOutcome::Closed => (http::StatusCode::OK, String::new()),
+            PaymentRequestOutcome::NotFound => (
+                http::StatusCode::NOT_FOUND,
+                "Payment request not found".to_string(),
+            ),
+            PaymentRequestOutcome::NotAllowed => (
+                http::StatusCode::FORBIDDEN,
+                "User is not allowed to perform this action on the payment request".to_string(),
+            ),
+            PaymentRequestOutcome::NotPending => (
+                http::StatusCode::CONFLICT,
+                "The payment request is not pending anymore".to_string(),
+            ),
+            PaymentRequestOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "Insufficent balance in user account".to_string(),
+            ),
+        }
+    }
+}
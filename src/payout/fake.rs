@@ -0,0 +1,104 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use axum::{async_trait, http::HeaderMap};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+use crate::config::config;
+
+use super::{CallbackRejected, PayoutInstruction, PayoutOutcome, PayoutProvider, PayoutReport};
+
+pub(crate) const PAYOUT_CALLBACK_SECRET_HEADER: &str = "Payout-Callback-Secret";
+
+/// What the fake provider does with every payout it is given
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum FakePayoutBehaviour {
+    Succeed,
+    Fail,
+    /// Never answers the submission
+    Hang,
+}
+
+impl FromStr for FakePayoutBehaviour {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "succeed" => Ok(FakePayoutBehaviour::Succeed),
+            "fail" => Ok(FakePayoutBehaviour::Fail),
+            "hang" => Ok(FakePayoutBehaviour::Hang),
+            _ => Err(anyhow!("unknown fake payout behaviour {value}")),
+        }
+    }
+}
+
+/// In-process payout provider for local development.
+/// Instead of calling back over HTTP it hands its reports straight to the settlement task.
+pub(crate) struct FakePayoutProvider {
+    behaviour: FakePayoutBehaviour,
+    reports: UnboundedSender<PayoutReport>,
+}
+
+impl FakePayoutProvider {
+    pub fn new(behaviour: FakePayoutBehaviour, reports: UnboundedSender<PayoutReport>) -> Self {
+        FakePayoutProvider { behaviour, reports }
+    }
+}
+
+#[async_trait]
+impl PayoutProvider for FakePayoutProvider {
+    async fn submit(&self, payout: &PayoutInstruction) -> anyhow::Result<String> {
+        tracing::info!(
+            "fake payout of {} to {} ({:?})",
+            payout.amount,
+            payout.destination,
+            self.behaviour
+        );
+
+        let (outcome, failure_reason) = match self.behaviour {
+            FakePayoutBehaviour::Succeed => (PayoutOutcome::Completed, None),
+            FakePayoutBehaviour::Fail => (
+                PayoutOutcome::Failed,
+                Some(format!("fake provider rejected {}", payout.destination)),
+            ),
+            FakePayoutBehaviour::Hang => std::future::pending().await,
+        };
+
+        let provider_reference = format!("fake_{}", Uuid::new_v4());
+
+        self.reports.send(PayoutReport {
+            withdrawal_id: payout.withdrawal_id,
+            provider_reference: Some(provider_reference.clone()),
+            outcome,
+            failure_reason,
+        })?;
+
+        Ok(provider_reference)
+    }
+
+    fn parse_callback(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<PayoutReport, CallbackRejected> {
+        let secret = headers.get(PAYOUT_CALLBACK_SECRET_HEADER).ok_or_else(|| {
+            CallbackRejected::Unauthenticated(format!(
+                "missing {PAYOUT_CALLBACK_SECRET_HEADER} header"
+            ))
+        })?;
+
+        // Compare digests so the comparison time doesn't depend on the secret
+        if Sha256::digest(secret.as_bytes())
+            != Sha256::digest(config().PAYOUT_CALLBACK_SECRET.as_bytes())
+        {
+            return Err(CallbackRejected::Unauthenticated(format!(
+                "invalid {PAYOUT_CALLBACK_SECRET_HEADER} header"
+            )));
+        }
+
+        serde_json::from_slice(body)
+            .map_err(|err| CallbackRejected::Malformed(format!("invalid payout report: {err}")))
+    }
+}
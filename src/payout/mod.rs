@@ -0,0 +1,188 @@
+mod fake;
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::State,
+    http::{self, HeaderMap},
+    response::IntoResponse,
+    routing::post,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{mpsc, Notify};
+use uuid::Uuid;
+
+use crate::{app_state::AppState, config::config, db::Db, error::AppResult};
+
+pub(crate) use fake::{FakePayoutBehaviour, FakePayoutProvider};
+
+/// How often pending withdrawals are handed to the provider when nothing wakes the dispatcher
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A withdrawal as it is handed to the payout provider
+pub(crate) struct PayoutInstruction {
+    pub withdrawal_id: Uuid,
+    pub amount: i32,
+    pub destination: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PayoutOutcome {
+    Completed,
+    Failed,
+}
+
+/// Final result of a payout as reported back by the provider
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct PayoutReport {
+    pub withdrawal_id: Uuid,
+    pub provider_reference: Option<String>,
+    pub outcome: PayoutOutcome,
+    pub failure_reason: Option<String>,
+}
+
+/// Why a callback request of the provider was turned away
+#[derive(Error, Debug)]
+pub(crate) enum CallbackRejected {
+    /// The request doesn't carry valid credentials of the provider
+    #[error("{0}")]
+    Unauthenticated(String),
+    /// The request is authenticated but its report can't be read
+    #[error("{0}")]
+    Malformed(String),
+}
+
+/// External service that moves withdrawn money out of the system
+#[async_trait]
+pub(crate) trait PayoutProvider: Send + Sync {
+    /// Hands the payout to the provider and returns the provider's reference for it.
+    /// The outcome is not known yet, the provider reports it later through the callback route.
+    /// The withdrawal id can be submitted more than once and must be used by the provider to
+    /// deduplicate payouts.
+    async fn submit(&self, payout: &PayoutInstruction) -> anyhow::Result<String>;
+
+    /// Authenticates a callback request sent by the provider and parses its report
+    fn parse_callback(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<PayoutReport, CallbackRejected>;
+}
+
+/// The configured payout provider together with the dispatcher that feeds it
+#[derive(Clone)]
+pub(crate) struct Payouts {
+    provider: Arc<dyn PayoutProvider>,
+    dispatch: Arc<Notify>,
+}
+
+impl Payouts {
+    /// Sets up the bundled fake provider and starts the background tasks that submit pending
+    /// withdrawals and apply the provider's reports
+    pub fn init(db: &Db) -> Self {
+        let (reports_sender, mut reports) = mpsc::unbounded_channel();
+
+        let payouts = Payouts {
+            provider: Arc::new(FakePayoutProvider::new(
+                config().FAKE_PAYOUT_BEHAVIOUR,
+                reports_sender,
+            )),
+            dispatch: Arc::new(Notify::new()),
+        };
+
+        let report_db = db.clone();
+        tokio::spawn(async move {
+            while let Some(report) = reports.recv().await {
+                if let Err(err) = report_db.settle_withdrawal(&report).await {
+                    tracing::error!(
+                        "failed to settle withdrawal {}: {err}",
+                        report.withdrawal_id
+                    );
+                }
+            }
+        });
+
+        let dispatcher_db = db.clone();
+        let dispatcher = payouts.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = dispatcher.dispatch_pending(&dispatcher_db).await {
+                    tracing::error!("failed to dispatch pending withdrawals: {err}");
+                }
+                tokio::select! {
+                    _ = dispatcher.dispatch.notified() => {},
+                    _ = tokio::time::sleep(DISPATCH_INTERVAL) => {},
+                }
+            }
+        });
+
+        payouts
+    }
+
+    /// Wakes the dispatcher so a newly created withdrawal is submitted right away
+    pub fn dispatch_soon(&self) {
+        self.dispatch.notify_one();
+    }
+
+    async fn dispatch_pending(&self, db: &Db) -> anyhow::Result<()> {
+        let timeout = Duration::from_secs(config().PAYOUT_SUBMIT_TIMEOUT_SECONDS);
+
+        for payout in db.get_pending_withdrawals().await? {
+            match tokio::time::timeout(timeout, self.provider.submit(&payout)).await {
+                Ok(Ok(provider_reference)) => {
+                    db.mark_withdrawal_processing(payout.withdrawal_id, &provider_reference)
+                        .await?;
+                }
+                Ok(Err(err)) => {
+                    db.settle_withdrawal(&PayoutReport {
+                        withdrawal_id: payout.withdrawal_id,
+                        provider_reference: None,
+                        outcome: PayoutOutcome::Failed,
+                        failure_reason: Some(err.to_string()),
+                    })
+                    .await?;
+                }
+                // The withdrawal stays pending and is submitted again on the next run
+                Err(_) => tracing::warn!(
+                    "payout provider did not answer for withdrawal {}",
+                    payout.withdrawal_id
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/callback", post(payout_callback))
+        .with_state(app_state)
+}
+
+/// Called by the payout provider once a withdrawal succeeded or failed
+async fn payout_callback(
+    State(db): State<Db>,
+    State(payouts): State<Payouts>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> AppResult<impl IntoResponse> {
+    let report = match payouts.provider.parse_callback(&headers, &body) {
+        Ok(report) => report,
+        Err(err @ CallbackRejected::Unauthenticated(_)) => {
+            return Ok((http::StatusCode::UNAUTHORIZED, err.to_string()).into_response())
+        }
+        Err(err @ CallbackRejected::Malformed(_)) => {
+            return Ok((http::StatusCode::BAD_REQUEST, err.to_string()).into_response())
+        }
+    };
+
+    db.settle_withdrawal(&report).await?;
+
+    Ok(http::StatusCode::OK.into_response())
+}
@@ -0,0 +1,192 @@
+use serde_json::json;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry, AuditSource},
+    db::Db,
+    error::AppResult,
+    transaction::{TransactionRequest, TransferOutcome},
+};
+
+use super::{ReviewOutcome, ReviewStatus, TransferReview};
+
+impl Db {
+    /// Queues a transfer held by a risk rule inside the database transaction of the request
+    pub async fn hold_transfer_for_review(
+        conn: &mut PgConnection,
+        username: &str,
+        transaction_request: &TransactionRequest,
+        rule: &str,
+        reason: &str,
+    ) -> sqlx::Result<Uuid> {
+        sqlx::query!(
+            "INSERT INTO transfer_reviews(from_user, to_user, amount, rule, reason)
+            VALUES($1, $2, $3, $4, $5) RETURNING review_id",
+            username,
+            transaction_request.to_user,
+            transaction_request.amount,
+            rule,
+            reason
+        )
+        .fetch_one(conn)
+        .await
+        .map(|record| record.review_id)
+    }
+
+    pub async fn get_transfer_reviews(
+        &self,
+        status: ReviewStatus,
+    ) -> sqlx::Result<Vec<TransferReview>> {
+        sqlx::query_as!(
+            TransferReview,
+            r#"SELECT review_id, from_user, to_user, amount, rule, reason,
+            status AS "status: ReviewStatus", transaction_id, decided_by, decision_note,
+            created_at, decided_at
+            FROM transfer_reviews WHERE status = $1
+            ORDER BY created_at
+            LIMIT 100"#,
+            status as ReviewStatus
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Makes the held transfer with the same checks as any other transfer, except for the risk
+    /// rules. Fails like [`Db::process_transaction`] if an account is locked or a limit is hit
+    pub async fn approve_transfer_review(
+        &self,
+        id: Uuid,
+        decided_by: &str,
+        note: Option<&str>,
+        audit_context: &AuditContext,
+    ) -> AppResult<ReviewOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(review) = sqlx::query!(
+            r#"SELECT from_user, to_user, amount, status AS "status: ReviewStatus"
+            FROM transfer_reviews WHERE review_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(ReviewOutcome::NotFound);
+        };
+
+        if review.status != ReviewStatus::Pending {
+            return Ok(ReviewOutcome::AlreadyDecided);
+        }
+
+        let TransferOutcome::Executed(transaction_id) = Self::process_transaction(
+            &mut transaction,
+            &review.from_user,
+            &TransactionRequest {
+                to_user: review.to_user,
+                amount: review.amount,
+            },
+            None,
+            &AuditSource {
+                actor: Some(&review.from_user),
+                context: audit_context,
+            },
+        )
+        .await?
+        else {
+            return Ok(ReviewOutcome::InsufficientBalance);
+        };
+
+        Self::decide_review(
+            &mut transaction,
+            id,
+            ReviewStatus::Approved,
+            Some(transaction_id),
+            decided_by,
+            note,
+            audit_context,
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(ReviewOutcome::Approved(transaction_id))
+    }
+
+    pub async fn reject_transfer_review(
+        &self,
+        id: Uuid,
+        decided_by: &str,
+        note: Option<&str>,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<ReviewOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(status) = sqlx::query!(
+            r#"SELECT status AS "status: ReviewStatus"
+            FROM transfer_reviews WHERE review_id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .map(|record| record.status) else {
+            return Ok(ReviewOutcome::NotFound);
+        };
+
+        if status != ReviewStatus::Pending {
+            return Ok(ReviewOutcome::AlreadyDecided);
+        }
+
+        Self::decide_review(
+            &mut transaction,
+            id,
+            ReviewStatus::Rejected,
+            None,
+            decided_by,
+            note,
+            audit_context,
+        )
+        .await?;
+
+        transaction.commit().await?;
+        Ok(ReviewOutcome::Rejected)
+    }
+
+    async fn decide_review(
+        conn: &mut PgConnection,
+        id: Uuid,
+        status: ReviewStatus,
+        transaction_id: Option<Uuid>,
+        decided_by: &str,
+        note: Option<&str>,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE transfer_reviews SET status = $2, transaction_id = $3, decided_by = $4,
+            decision_note = $5, decided_at = now()
+            WHERE review_id = $1",
+            id,
+            status as ReviewStatus,
+            transaction_id,
+            decided_by,
+            note
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Self::record_audit(
+            conn,
+            audit_context,
+            AuditEntry {
+                actor: Some(decided_by),
+                action: AuditAction::TransferReview,
+                target: Some(&id.to_string()),
+                before: Some(json!({ "status": ReviewStatus::Pending })),
+                after: Some(json!({
+                    "status": status,
+                    "transaction_id": transaction_id,
+                    "note": note
+                })),
+            },
+        )
+        .await
+    }
+}
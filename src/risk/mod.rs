@@ -0,0 +1,482 @@
+mod db;
+mod rules;
+
+use std::{
+    fs,
+    path::Path as FilePath,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use axum::{
+    async_trait,
+    extract::{Path, Query, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use thiserror::Error;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    config::config,
+    db::Db,
+    error::AppResult,
+    utils::{AdminRole, OperatorRole, RoleInfo, StaffRole},
+};
+
+pub(crate) use rules::RuleConfig;
+
+pub(super) fn get_admin_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/reviews", get(transfer_reviews_list))
+        .route("/reviews/:id/approve", post(approve_transfer))
+        .route("/reviews/:id/reject", post(reject_transfer))
+        .route("/risk-rules", get(risk_rules_list))
+        .route("/risk-rules/reload", post(reload_risk_rules))
+        .with_state(app_state)
+}
+
+///Transfers held for review by a risk rule, oldest first
+#[utoipa::path(
+    get,
+    path = "/admin/reviews",
+    tag = "Admin",
+    params(ReviewListQuery),
+    responses(
+        (status = 200, description = "Reviews successfully retreived", body = Vec<TransferReview>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn transfer_reviews_list(
+    State(db): State<Db>,
+    _: RoleInfo<StaffRole>,
+    Query(query): Query<ReviewListQuery>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(
+        db.get_transfer_reviews(query.status.unwrap_or(ReviewStatus::Pending))
+            .await?,
+    ))
+}
+
+///Executes a held transfer. Status and limits of the accounts are checked again, the risk rules
+///are not. Returns the id of the transaction
+#[utoipa::path(
+    post,
+    path = "/admin/reviews/{id}/approve",
+    tag = "Admin",
+    request_body = ReviewDecision,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Review id")
+    ),
+    responses(
+        (status = 200, description = "Transfer successfully executed", body = Uuid),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 402, description = "The sender doesn't have the balance anymore, the review stays pending"),
+        (status = 403, description = "User is not support or admin, or a transfer limit would be exceeded"),
+        (status = 404, description = "Review not found"),
+        (status = 409, description = "The review was already decided"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn approve_transfer(
+    State(db): State<Db>,
+    RoleInfo {
+        username: operator, ..
+    }: RoleInfo<OperatorRole>,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+    Json(review_decision): Json<ReviewDecision>,
+) -> AppResult<impl IntoResponse> {
+    review_decision.validate()?;
+
+    let outcome = db
+        .approve_transfer_review(
+            id,
+            &operator,
+            review_decision.note.as_deref(),
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Refuses a held transfer, no money is moved
+#[utoipa::path(
+    post,
+    path = "/admin/reviews/{id}/reject",
+    tag = "Admin",
+    request_body = ReviewDecision,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Review id")
+    ),
+    responses(
+        (status = 200, description = "Transfer successfully rejected"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support or admin"),
+        (status = 404, description = "Review not found"),
+        (status = 409, description = "The review was already decided"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn reject_transfer(
+    State(db): State<Db>,
+    RoleInfo {
+        username: operator, ..
+    }: RoleInfo<OperatorRole>,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+    Json(review_decision): Json<ReviewDecision>,
+) -> AppResult<impl IntoResponse> {
+    review_decision.validate()?;
+
+    let outcome = db
+        .reject_transfer_review(
+            id,
+            &operator,
+            review_decision.note.as_deref(),
+            &audit_context,
+        )
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Risk rules transfers are currently checked against
+#[utoipa::path(
+    get,
+    path = "/admin/risk-rules",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Risk rules successfully retreived", body = Vec<RuleConfig>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not support, admin or auditor"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn risk_rules_list(
+    State(risk_engine): State<RiskEngine>,
+    _: RoleInfo<StaffRole>,
+) -> impl IntoResponse {
+    Json(risk_engine.configs())
+}
+
+///Loads the risk rules again from the rules file. The current rules stay in place if the file
+///can't be read
+#[utoipa::path(
+    post,
+    path = "/admin/risk-rules/reload",
+    tag = "Admin",
+    responses(
+        (status = 200, description = "Risk rules successfully reloaded", body = Vec<RuleConfig>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 409, description = "No rules file is configured"),
+        (status = 422, description = "The rules file is invalid"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn reload_risk_rules(
+    State(risk_engine): State<RiskEngine>,
+    _: RoleInfo<AdminRole>,
+) -> impl IntoResponse {
+    let Some(path) = &config().RISK_RULES_FILE else {
+        return (
+            http::StatusCode::CONFLICT,
+            "RISK_RULES_FILE is not set".to_string(),
+        )
+            .into_response();
+    };
+
+    match read_rules_file(path) {
+        Ok(rule_configs) => {
+            risk_engine.replace(rule_configs);
+            Json(risk_engine.configs()).into_response()
+        }
+        Err(err) => (http::StatusCode::UNPROCESSABLE_ENTITY, format!("{err:#}")).into_response(),
+    }
+}
+
+/// Transfers about to be made by one sender, a single transfer or a whole batch
+pub(crate) struct TransferAttempt<'a> {
+    pub from_user: &'a str,
+    /// Recipient and amount of every transfer
+    pub transfers: Vec<(&'a str, i64)>,
+}
+
+impl TransferAttempt<'_> {
+    pub fn total(&self) -> i64 {
+        self.transfers.iter().map(|(_, amount)| amount).sum()
+    }
+
+    /// Amount sent to every distinct recipient, in the order they first appear
+    pub fn amounts_by_recipient(&self) -> Vec<(&str, i64)> {
+        let mut amounts: Vec<(&str, i64)> = Vec::new();
+        for &(to_user, amount) in &self.transfers {
+            match amounts
+                .iter_mut()
+                .find(|(recipient, _)| *recipient == to_user)
+            {
+                Some((_, sum)) => *sum += amount,
+                None => amounts.push((to_user, amount)),
+            }
+        }
+        amounts
+    }
+}
+
+/// What a rule wants to happen with a transfer, with the reason shown to reviewers
+pub(crate) enum Verdict {
+    Allow,
+    Deny(String),
+    Review(String),
+}
+
+/// A check transfers have to pass before they are made. Rules run inside the database
+/// transaction of the transfer while the sender's account is locked, before the money is moved
+#[async_trait]
+pub(crate) trait RiskRule: Send + Sync {
+    async fn evaluate(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> sqlx::Result<Verdict>;
+}
+
+/// Outcome of all rules for a transfer. A denial wins over a review
+pub(crate) enum RiskDecision {
+    Allow,
+    Deny { rule: String, reason: String },
+    Review { rule: String, reason: String },
+}
+
+/// A transfer was refused by a risk rule
+#[derive(Error, Debug)]
+#[error("The transfer was refused by the {rule} risk rule: {reason}")]
+pub(crate) struct RiskDenied {
+    pub rule: String,
+    pub reason: String,
+}
+
+impl RiskDenied {
+    /// Refuses a transfer a rule wants reviewed on a path that can't hold it for a review
+    pub fn review_required(rule: String, reason: String) -> Self {
+        RiskDenied {
+            rule,
+            reason: format!(
+                "{reason}. Transfers needing a review can only be sent with POST /transactions"
+            ),
+        }
+    }
+}
+
+struct LoadedRule {
+    config: RuleConfig,
+    rule: Box<dyn RiskRule>,
+}
+
+/// The risk rules in force. They are read from the rules file at startup and can be reloaded
+/// without a restart
+#[derive(Clone)]
+pub(crate) struct RiskEngine {
+    rules: Arc<RwLock<Arc<Vec<LoadedRule>>>>,
+}
+
+impl RiskEngine {
+    /// Without a rules file every transfer is allowed
+    pub fn init() -> Self {
+        let rule_configs = match &config().RISK_RULES_FILE {
+            Some(path) => {
+                read_rules_file(path).expect("RISK_RULES_FILE must be a valid rules file")
+            }
+            None => Vec::new(),
+        };
+
+        let risk_engine = RiskEngine {
+            rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+        };
+        risk_engine.replace(rule_configs);
+        risk_engine
+    }
+
+    pub async fn evaluate(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> sqlx::Result<RiskDecision> {
+        // A snapshot, so a reload doesn't change the rules halfway through
+        let rules = self.rules.read().expect("risk rules lock poisoned").clone();
+
+        let mut decision = RiskDecision::Allow;
+        for loaded_rule in rules.iter() {
+            let rule = loaded_rule.config.name();
+            match loaded_rule.rule.evaluate(&mut *conn, transfer).await? {
+                Verdict::Allow => {}
+                Verdict::Deny(reason) => return Ok(RiskDecision::Deny { rule, reason }),
+                Verdict::Review(reason) => {
+                    if matches!(decision, RiskDecision::Allow) {
+                        decision = RiskDecision::Review { rule, reason };
+                    }
+                }
+            }
+        }
+
+        Ok(decision)
+    }
+
+    /// Evaluates transfers that can't be held for a review, a rule asking for one refuses them
+    pub async fn enforce(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> AppResult<()> {
+        match self.evaluate(conn, transfer).await? {
+            RiskDecision::Allow => Ok(()),
+            RiskDecision::Deny { rule, reason } => Err(RiskDenied { rule, reason }.into()),
+            RiskDecision::Review { rule, reason } => {
+                Err(RiskDenied::review_required(rule, reason).into())
+            }
+        }
+    }
+
+    fn replace(&self, rule_configs: Vec<RuleConfig>) {
+        let rules = rule_configs
+            .into_iter()
+            .map(|config| LoadedRule {
+                rule: config.build(),
+                config,
+            })
+            .collect();
+
+        *self.rules.write().expect("risk rules lock poisoned") = Arc::new(rules);
+    }
+
+    fn configs(&self) -> Vec<RuleConfig> {
+        self.rules
+            .read()
+            .expect("risk rules lock poisoned")
+            .iter()
+            .map(|loaded_rule| loaded_rule.config.clone())
+            .collect()
+    }
+}
+
+fn read_rules_file(path: &FilePath) -> anyhow::Result<Vec<RuleConfig>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read the rules file {}", path.display()))?;
+
+    let rules_file: RulesFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid rules file {}", path.display()))?;
+
+    Ok(rules_file.rules)
+}
+
+/// Contents of the rules file
+#[derive(Deserialize)]
+struct RulesFile {
+    rules: Vec<RuleConfig>,
+}
+
+/// What a rule does with the transfers it matches
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RuleAction {
+    Deny,
+    Review,
+}
+
+/// Stored as the `transfer_review_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "transfer_review_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReviewStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TransferReview {
+    pub review_id: Uuid,
+    pub from_user: String,
+    pub to_user: String,
+    pub amount: i32,
+    /// Rule that held the transfer
+    pub rule: String,
+    pub reason: String,
+    pub status: ReviewStatus,
+    /// The executed transfer once approved
+    pub transaction_id: Option<Uuid>,
+    pub decided_by: Option<String>,
+    pub decision_note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ReviewListQuery {
+    /// Pending reviews by default
+    pub status: Option<ReviewStatus>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct ReviewDecision {
+    #[validate(length(min = 1, max = 500))]
+    pub note: Option<String>,
+}
+
+pub(crate) enum ReviewOutcome {
+    Approved(Uuid),
+    Rejected,
+    NotFound,
+    AlreadyDecided,
+    InsufficientBalance,
+}
+
+impl ReviewOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            ReviewOutcome::Approved(transaction_id) => {
+                (http::StatusCode::OK, transaction_id.to_string())
+            }
+            ReviewOutcome::Rejected => (http::StatusCode::OK, String::new()),
+            ReviewOutcome::NotFound => {
+                (http::StatusCode::NOT_FOUND, "Review not found".to_string())
+            }
+            ReviewOutcome::AlreadyDecided => (
+                http::StatusCode::CONFLICT,
+                "The review was already decided".to_string(),
+            ),
+            ReviewOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "Insufficent balance in the sender's account".to_string(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+use super::{RiskRule, RuleAction, TransferAttempt, Verdict};
+
+/// A rule of the rules file
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum RuleConfig {
+    /// First transfers to a recipient the sender never paid before, of at least `min_amount` together
+    NewCounterparty { min_amount: i64, action: RuleAction },
+    /// Transfers to more than `max_recipients` different recipients within `window_seconds`
+    ManyRecipients {
+        max_recipients: i64,
+        window_seconds: i64,
+        action: RuleAction,
+    },
+    /// An account younger than `max_account_age_seconds` sending at least
+    /// `min_balance_percent` of its balance at once
+    NewAccountDrain {
+        max_account_age_seconds: i64,
+        min_balance_percent: i64,
+        action: RuleAction,
+    },
+}
+
+impl RuleConfig {
+    pub(crate) fn name(&self) -> String {
+        match self {
+            RuleConfig::NewCounterparty { .. } => "new_counterparty",
+            RuleConfig::ManyRecipients { .. } => "many_recipients",
+            RuleConfig::NewAccountDrain { .. } => "new_account_drain",
+        }
+        .to_string()
+    }
+
+    pub(super) fn build(&self) -> Box<dyn RiskRule> {
+        match *self {
+            RuleConfig::NewCounterparty { min_amount, action } => {
+                Box::new(NewCounterparty { min_amount, action })
+            }
+            RuleConfig::ManyRecipients {
+                max_recipients,
+                window_seconds,
+                action,
+            } => Box::new(ManyRecipients {
+                max_recipients,
+                window_seconds,
+                action,
+            }),
+            RuleConfig::NewAccountDrain {
+                max_account_age_seconds,
+                min_balance_percent,
+                action,
+            } => Box::new(NewAccountDrain {
+                max_account_age_seconds,
+                min_balance_percent,
+                action,
+            }),
+        }
+    }
+}
+
+fn verdict(action: RuleAction, reason: String) -> Verdict {
+    match action {
+        RuleAction::Deny => Verdict::Deny(reason),
+        RuleAction::Review => Verdict::Review(reason),
+    }
+}
+
+struct NewCounterparty {
+    min_amount: i64,
+    action: RuleAction,
+}
+
+#[async_trait]
+impl RiskRule for NewCounterparty {
+    async fn evaluate(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> sqlx::Result<Verdict> {
+        let large: Vec<(&str, i64)> = transfer
+            .amounts_by_recipient()
+            .into_iter()
+            .filter(|(_, amount)| *amount >= self.min_amount)
+            .collect();
+        if large.is_empty() {
+            return Ok(Verdict::Allow);
+        }
+
+        let recipients: Vec<String> = large
+            .iter()
+            .map(|(to_user, _)| to_user.to_string())
+            .collect();
+        let paid_before = sqlx::query!(
+            r#"SELECT DISTINCT to_user FROM transactions
+            WHERE from_user = $1 AND to_user = ANY($2) AND kind = 'transfer'"#,
+            transfer.from_user,
+            &recipients
+        )
+        .fetch_all(conn)
+        .await?;
+
+        let Some((to_user, amount)) = large
+            .into_iter()
+            .find(|(to_user, _)| !paid_before.iter().any(|record| record.to_user == *to_user))
+        else {
+            return Ok(Verdict::Allow);
+        };
+
+        Ok(verdict(
+            self.action,
+            format!(
+                "First transfer to {to_user} is {amount}, at least {}",
+                self.min_amount
+            ),
+        ))
+    }
+}
+
+struct ManyRecipients {
+    max_recipients: i64,
+    window_seconds: i64,
+    action: RuleAction,
+}
+
+#[async_trait]
+impl RiskRule for ManyRecipients {
+    async fn evaluate(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> sqlx::Result<Verdict> {
+        let recipients: Vec<String> = transfer
+            .transfers
+            .iter()
+            .map(|(to_user, _)| to_user.to_string())
+            .collect();
+        let recipients = sqlx::query!(
+            r#"SELECT COUNT(DISTINCT to_user) AS "recipients!" FROM (
+                SELECT to_user FROM transactions
+                WHERE from_user = $1 AND kind = 'transfer'
+                AND created_at > now() - make_interval(secs => $3)
+                UNION ALL
+                SELECT unnest($2::TEXT[])
+            ) recent"#,
+            transfer.from_user,
+            &recipients,
+            self.window_seconds as f64
+        )
+        .fetch_one(conn)
+        .await?
+        .recipients;
+
+        if recipients <= self.max_recipients {
+            return Ok(Verdict::Allow);
+        }
+
+        Ok(verdict(
+            self.action,
+            format!(
+                "{recipients} different recipients within {} seconds, at most {} allowed",
+                self.window_seconds, self.max_recipients
+            ),
+        ))
+    }
+}
+
+struct NewAccountDrain {
+    max_account_age_seconds: i64,
+    min_balance_percent: i64,
+    action: RuleAction,
+}
+
+#[async_trait]
+impl RiskRule for NewAccountDrain {
+    async fn evaluate(
+        &self,
+        conn: &mut PgConnection,
+        transfer: &TransferAttempt<'_>,
+    ) -> sqlx::Result<Verdict> {
+        let Some(account) = sqlx::query!(
+            r#"SELECT ledger_accounts.balance,
+            user_credentials.created_at > now() - make_interval(secs => $2) AS "new_account!"
+            FROM user_credentials JOIN ledger_accounts USING (username)
+            WHERE user_credentials.username = $1"#,
+            transfer.from_user,
+            self.max_account_age_seconds as f64
+        )
+        .fetch_optional(conn)
+        .await?
+        else {
+            return Ok(Verdict::Allow);
+        };
+
+        let amount = transfer.total();
+        if !account.new_account
+            || account.balance <= 0
+            || amount * 100 < account.balance * self.min_balance_percent
+        {
+            return Ok(Verdict::Allow);
+        }
+
+        Ok(verdict(
+            self.action,
+            format!(
+                "Account younger than {} seconds sends {} of its balance of {}",
+                self.max_account_age_seconds, amount, account.balance
+            ),
+        ))
+    }
+}
@@ -0,0 +1,259 @@
+use sqlx::Connection;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditContext, AuditSource},
+    config::config,
+    db::Db,
+    error::{AppError, AppResult},
+    risk::{RiskDenied, RiskEngine},
+    transaction::{TransactionRequest, TransferOutcome},
+};
+
+use super::{
+    Frequency, InsufficientBalancePolicy, RunOutcome, ScheduledTransfer, ScheduledTransferRequest,
+    ScheduledTransferRun, ScheduledTransferStatus,
+};
+
+impl Db {
+    /// The runs happen without the user, so the step-up for their amount is done up front
+    pub async fn create_scheduled_transfer(
+        &self,
+        username: &str,
+        schedule_request: &ScheduledTransferRequest,
+        step_up_code: Option<&str>,
+    ) -> AppResult<Uuid> {
+        let mut transaction = self.pool.begin().await?;
+
+        self.require_step_up(
+            &mut transaction,
+            username,
+            schedule_request.transfer.amount as i64,
+            step_up_code,
+        )
+        .await?;
+
+        let schedule_id = sqlx::query!(
+            "INSERT INTO scheduled_transfers(from_user, to_user, amount, frequency, start_at, end_at,
+            max_runs, on_insufficient_balance, next_run_at)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $5) RETURNING schedule_id",
+            username,
+            schedule_request.transfer.to_user,
+            schedule_request.transfer.amount,
+            schedule_request.frequency as Frequency,
+            schedule_request.start_at,
+            schedule_request.end_at,
+            schedule_request.max_runs,
+            schedule_request.on_insufficient_balance as InsufficientBalancePolicy
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .schedule_id;
+
+        transaction.commit().await?;
+        Ok(schedule_id)
+    }
+
+    pub async fn get_scheduled_transfer(&self, id: Uuid) -> sqlx::Result<ScheduledTransfer> {
+        sqlx::query_as!(
+            ScheduledTransfer,
+            r#"SELECT schedule_id, from_user, to_user, amount, frequency AS "frequency: Frequency",
+            start_at, end_at, max_runs,
+            on_insufficient_balance AS "on_insufficient_balance: InsufficientBalancePolicy",
+            status AS "status: ScheduledTransferStatus", completed_runs, next_run_at, created_at, updated_at
+            FROM scheduled_transfers WHERE schedule_id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_scheduled_transfers_list(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<ScheduledTransfer>> {
+        sqlx::query_as!(
+            ScheduledTransfer,
+            r#"SELECT schedule_id, from_user, to_user, amount, frequency AS "frequency: Frequency",
+            start_at, end_at, max_runs,
+            on_insufficient_balance AS "on_insufficient_balance: InsufficientBalancePolicy",
+            status AS "status: ScheduledTransferStatus", completed_runs, next_run_at, created_at, updated_at
+            FROM scheduled_transfers WHERE from_user = $1"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_scheduled_transfer_runs(
+        &self,
+        id: Uuid,
+    ) -> sqlx::Result<Vec<ScheduledTransferRun>> {
+        sqlx::query_as!(
+            ScheduledTransferRun,
+            r#"SELECT run_id, occurrence, outcome AS "outcome: RunOutcome", transaction_id, created_at
+            FROM scheduled_transfer_runs WHERE schedule_id = $1 ORDER BY created_at"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Returns false if the scheduled transfer already completed or was cancelled
+    pub async fn cancel_scheduled_transfer(&self, id: Uuid) -> sqlx::Result<bool> {
+        sqlx::query!(
+            "UPDATE scheduled_transfers SET status = 'cancelled', updated_at = now()
+            WHERE schedule_id = $1 AND status = 'active'",
+            id
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    /// Runs every scheduled transfer that is due and returns how many runs there were
+    pub async fn run_due_scheduled_transfers(&self, risk_engine: &RiskEngine) -> AppResult<u64> {
+        let mut runs = 0;
+        while self.run_next_scheduled_transfer(risk_engine).await? {
+            runs += 1;
+        }
+        Ok(runs)
+    }
+
+    /// Runs the most overdue scheduled transfer in its own database transaction.
+    /// A transfer that fails is rolled back on its own and recorded as a run, so the schedule moves
+    /// on and doesn't hold up the ones due after it. Returns false if no scheduled transfer is due.
+    async fn run_next_scheduled_transfer(&self, risk_engine: &RiskEngine) -> AppResult<bool> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(schedule) = sqlx::query!(
+            r#"SELECT schedule_id, from_user, to_user, amount, frequency AS "frequency: Frequency",
+            start_at, end_at, max_runs,
+            on_insufficient_balance AS "on_insufficient_balance: InsufficientBalancePolicy",
+            completed_runs, attempts
+            FROM scheduled_transfers WHERE status = 'active' AND next_run_at <= now()
+            ORDER BY next_run_at LIMIT 1 FOR UPDATE SKIP LOCKED"#
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        // Same transfer logic as POST /transactions, except that nobody is there to wait for a review
+        let mut savepoint = Connection::begin(&mut *transaction).await?;
+        let transfer_result = Self::process_transaction(
+            &mut savepoint,
+            &schedule.from_user,
+            &TransactionRequest {
+                to_user: schedule.to_user,
+                amount: schedule.amount,
+            },
+            Some(risk_engine),
+            &AuditSource {
+                actor: None,
+                context: &AuditContext {
+                    request_id: None,
+                    ip_address: None,
+                },
+            },
+        )
+        .await;
+        match transfer_result {
+            Ok(TransferOutcome::Executed(_)) => savepoint.commit().await?,
+            _ => savepoint.rollback().await?,
+        }
+
+        let transfer_outcome = match transfer_result {
+            Ok(TransferOutcome::ReviewRequired { rule, reason }) => {
+                Err(AppError::from(RiskDenied::review_required(rule, reason)))
+            }
+            transfer_result => transfer_result,
+        };
+
+        let outcome = match &transfer_outcome {
+            Ok(TransferOutcome::Executed(_)) => RunOutcome::Succeeded,
+            // Locked accounts, exhausted limits and refusing risk rules give up the occurrence,
+            // retrying would only delay the next ones
+            Err(
+                err @ (AppError::AccountLocked(_)
+                | AppError::LimitExceeded(_)
+                | AppError::RiskDenied(_)),
+            ) => {
+                tracing::info!("blocked scheduled transfer {}: {err}", schedule.schedule_id);
+                RunOutcome::Blocked
+            }
+            Err(err) => {
+                tracing::error!("failed scheduled transfer {}: {err}", schedule.schedule_id);
+                RunOutcome::Failed
+            }
+            Ok(_)
+                if schedule.on_insufficient_balance == InsufficientBalancePolicy::Retry
+                    && schedule.attempts < config().SCHEDULED_TRANSFER_MAX_RETRIES =>
+            {
+                RunOutcome::Retrying
+            }
+            Ok(_) => RunOutcome::Skipped,
+        };
+        let transaction_id = match transfer_outcome {
+            Ok(TransferOutcome::Executed(transaction_id)) => Some(transaction_id),
+            _ => None,
+        };
+
+        sqlx::query!(
+            "INSERT INTO scheduled_transfer_runs(schedule_id, occurrence, outcome, transaction_id)
+            VALUES($1, $2, $3, $4)",
+            schedule.schedule_id,
+            schedule.completed_runs,
+            outcome as RunOutcome,
+            transaction_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        if outcome == RunOutcome::Retrying {
+            sqlx::query!(
+                "UPDATE scheduled_transfers SET attempts = attempts + 1,
+                next_run_at = now() + make_interval(secs => $1), updated_at = now()
+                WHERE schedule_id = $2",
+                config().SCHEDULED_TRANSFER_RETRY_SECONDS as f64,
+                schedule.schedule_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        } else {
+            let completed_runs = schedule.completed_runs + 1;
+            let next_run_at = schedule
+                .frequency
+                .occurrence_at(schedule.start_at, completed_runs)
+                .filter(|next_run_at| match schedule.end_at {
+                    Some(end_at) => *next_run_at <= end_at,
+                    None => true,
+                })
+                .filter(|_| match schedule.max_runs {
+                    Some(max_runs) => completed_runs < max_runs,
+                    None => true,
+                });
+
+            let status = match next_run_at {
+                Some(_) => ScheduledTransferStatus::Active,
+                None => ScheduledTransferStatus::Completed,
+            };
+
+            sqlx::query!(
+                "UPDATE scheduled_transfers SET completed_runs = $1, attempts = 0,
+                next_run_at = COALESCE($2, next_run_at), status = $3, updated_at = now()
+                WHERE schedule_id = $4",
+                completed_runs,
+                next_run_at,
+                status as ScheduledTransferStatus,
+                schedule.schedule_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,351 @@
+mod db;
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Months, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState, db::Db, error::AppResult, risk::RiskEngine, totp::StepUpCode,
+    transaction::TransactionRequest, utils::UserInfo,
+};
+
+/// How often the scheduler looks for due transfers
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", post(create_scheduled_transfer))
+        .route("/", get(scheduled_transfers_list))
+        .route(
+            "/:id",
+            get(get_scheduled_transfer_by_id).delete(cancel_scheduled_transfer),
+        )
+        .route("/:id/runs", get(scheduled_transfer_runs))
+        .with_state(app_state)
+}
+
+/// Executes due scheduled transfers in the background
+pub(crate) fn spawn_scheduler(db: Db, risk_engine: RiskEngine) {
+    tokio::spawn(async move {
+        loop {
+            match db.run_due_scheduled_transfers(&risk_engine).await {
+                Ok(0) => {}
+                Ok(runs) => tracing::info!("executed {runs} scheduled transfers"),
+                Err(err) => tracing::error!("failed to execute scheduled transfers: {err}"),
+            }
+            tokio::time::sleep(SCHEDULER_INTERVAL).await;
+        }
+    });
+}
+
+///Schedules a one-off transfer in the future or a recurring transfer. Returns the schedule id
+#[utoipa::path(
+    post,
+    path = "/scheduled-transfers",
+    tag = "Scheduled Transfers",
+    request_body = ScheduledTransferRequest,
+    params(
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required when the amount of a run is above the step-up threshold of users with two-factor authentication")
+    ),
+    responses(
+        (status = 201, description = "Transfer successfully scheduled", body = Uuid),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "A fresh TOTP code is required for this amount"),
+        (status = 422, description = "Invalid schedule"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_scheduled_transfer(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    StepUpCode(step_up_code): StepUpCode,
+    Json(schedule_request): Json<ScheduledTransferRequest>,
+) -> AppResult<impl IntoResponse> {
+    schedule_request.validate()?;
+
+    if schedule_request.start_at < Utc::now() {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "start_at must not be in the past",
+        )
+            .into_response());
+    }
+
+    if schedule_request
+        .end_at
+        .is_some_and(|end_at| end_at < schedule_request.start_at)
+    {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "end_at must not be before start_at",
+        )
+            .into_response());
+    }
+
+    let schedule_id = db
+        .create_scheduled_transfer(&username, &schedule_request, step_up_code.as_deref())
+        .await?;
+
+    Ok((http::StatusCode::CREATED, schedule_id.to_string()).into_response())
+}
+
+///Scheduled transfers created by a User
+#[utoipa::path(
+    get,
+    path = "/scheduled-transfers",
+    tag = "Scheduled Transfers",
+    responses(
+        (status = 200, description = "Scheduled transfers successfully retreived", body = Vec<ScheduledTransfer>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn scheduled_transfers_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_scheduled_transfers_list(&username).await?).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/scheduled-transfers/{id}",
+    tag = "Scheduled Transfers",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Schedule id")
+    ),
+    responses(
+        (status = 200, description = "Scheduled transfer successfully retreived", body = ScheduledTransfer),
+        (status = 403, description = "User is not authorized to view this scheduled transfer"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_scheduled_transfer_by_id(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let scheduled_transfer = db.get_scheduled_transfer(id).await?;
+
+    if scheduled_transfer.from_user != username {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this scheduled transfer",
+        )
+            .into_response());
+    }
+
+    Ok(Json(scheduled_transfer).into_response())
+}
+
+///Outcome of every execution of a scheduled transfer
+#[utoipa::path(
+    get,
+    path = "/scheduled-transfers/{id}/runs",
+    tag = "Scheduled Transfers",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Schedule id")
+    ),
+    responses(
+        (status = 200, description = "Runs successfully retreived", body = Vec<ScheduledTransferRun>),
+        (status = 403, description = "User is not authorized to view this scheduled transfer"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn scheduled_transfer_runs(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if db.get_scheduled_transfer(id).await?.from_user != username {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this scheduled transfer",
+        )
+            .into_response());
+    }
+
+    Ok(Json(db.get_scheduled_transfer_runs(id).await?).into_response())
+}
+
+///Stops a scheduled transfer from running again
+#[utoipa::path(
+    delete,
+    path = "/scheduled-transfers/{id}",
+    tag = "Scheduled Transfers",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Schedule id")
+    ),
+    responses(
+        (status = 200, description = "Scheduled transfer successfully cancelled"),
+        (status = 403, description = "User is not authorized to cancel this scheduled transfer"),
+        (status = 409, description = "The scheduled transfer is not active anymore"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn cancel_scheduled_transfer(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let scheduled_transfer = db.get_scheduled_transfer(id).await?;
+
+    if scheduled_transfer.from_user != username {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to cancel this scheduled transfer",
+        )
+            .into_response());
+    }
+
+    if !db.cancel_scheduled_transfer(id).await? {
+        return Ok((
+            http::StatusCode::CONFLICT,
+            "The scheduled transfer is not active anymore",
+        )
+            .into_response());
+    }
+
+    Ok(http::StatusCode::OK.into_response())
+}
+
+/// Stored as the `transfer_frequency` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "transfer_frequency", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Frequency {
+    Once,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Frequency {
+    /// When the given occurrence of a schedule is due, None if there is no such occurrence
+    fn occurrence_at(self, start_at: DateTime<Utc>, occurrence: i32) -> Option<DateTime<Utc>> {
+        match self {
+            Frequency::Once => (occurrence == 0).then_some(start_at),
+            Frequency::Daily => Some(start_at + chrono::Duration::days(occurrence.into())),
+            Frequency::Weekly => Some(start_at + chrono::Duration::weeks(occurrence.into())),
+            Frequency::Monthly => start_at.checked_add_months(Months::new(occurrence as u32)),
+        }
+    }
+}
+
+/// What happens to an occurrence the payer can't afford, stored as the
+/// `insufficient_balance_policy` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "insufficient_balance_policy", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InsufficientBalancePolicy {
+    /// Try again later, up to `SCHEDULED_TRANSFER_MAX_RETRIES` times before skipping the occurrence
+    Retry,
+    /// Skip the occurrence right away
+    Skip,
+}
+
+/// Stored as the `scheduled_transfer_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "scheduled_transfer_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ScheduledTransferStatus {
+    Active,
+    /// Every occurrence ran
+    Completed,
+    Cancelled,
+}
+
+/// Stored as the `scheduled_run_outcome` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "scheduled_run_outcome", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RunOutcome {
+    Succeeded,
+    /// Insufficient balance, the occurrence is attempted again later
+    Retrying,
+    /// Insufficient balance, the occurrence was given up
+    Skipped,
+    /// The sender or recipient account is locked, the transfer is over a limit of the sender or
+    /// a risk rule refused it, the occurrence was given up
+    Blocked,
+    /// The transfer failed with an unexpected error, the occurrence was given up
+    Failed,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct ScheduledTransfer {
+    pub schedule_id: Uuid,
+    pub from_user: String,
+    pub to_user: String,
+    pub amount: i32,
+    pub frequency: Frequency,
+    pub start_at: DateTime<Utc>,
+    pub end_at: Option<DateTime<Utc>>,
+    pub max_runs: Option<i32>,
+    pub on_insufficient_balance: InsufficientBalancePolicy,
+    pub status: ScheduledTransferStatus,
+    pub completed_runs: i32,
+    pub next_run_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct ScheduledTransferRun {
+    pub run_id: Uuid,
+    pub occurrence: i32,
+    pub outcome: RunOutcome,
+    pub transaction_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct ScheduledTransferRequest {
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub transfer: TransactionRequest,
+    pub frequency: Frequency,
+    /// When the first transfer runs
+    pub start_at: DateTime<Utc>,
+    /// No transfer runs after this time
+    pub end_at: Option<DateTime<Utc>>,
+    /// Number of transfers after which the schedule completes
+    #[validate(range(min = 1))]
+    pub max_runs: Option<i32>,
+    pub on_insufficient_balance: InsufficientBalancePolicy,
+}
@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use sqlx::PgConnection;
+
+use crate::db::Db;
+
+use super::StatementLine;
+
+impl Db {
+    /// Balance of the user's account right before `time`
+    pub async fn get_balance_before(
+        conn: &mut PgConnection,
+        username: &str,
+        time: DateTime<Utc>,
+    ) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE((
+                SELECT postings.balance_after FROM postings
+                JOIN ledger_accounts USING (account_id)
+                WHERE ledger_accounts.username = $1 AND postings.created_at < $2
+                ORDER BY postings.created_at DESC, postings.posting_id DESC
+                LIMIT 1
+            ), 0) AS "balance!""#,
+            username,
+            time
+        )
+        .fetch_one(&mut *conn)
+        .await
+    }
+
+    /// Postings of the user's account in `[from, to)` in the order they were applied,
+    /// read row by row instead of loading the whole period
+    pub fn get_statement_lines<'a>(
+        conn: &'a mut PgConnection,
+        username: &'a str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BoxStream<'a, sqlx::Result<StatementLine>> {
+        sqlx::query_as!(
+            StatementLine,
+            r#"SELECT postings.posting_id, postings.created_at, journal_entries.kind,
+            journal_entries.transaction_id, journal_entries.withdrawal_id,
+            CASE WHEN transactions.from_user = $1 THEN transactions.to_user ELSE transactions.from_user END AS counterparty,
+            postings.amount, postings.balance_after AS balance
+            FROM postings
+            JOIN ledger_accounts USING (account_id)
+            JOIN journal_entries USING (entry_id)
+            LEFT JOIN transactions ON transactions.transaction_id = journal_entries.transaction_id
+            WHERE ledger_accounts.username = $1 AND postings.created_at >= $2 AND postings.created_at < $3
+            ORDER BY postings.created_at, postings.posting_id"#,
+            username,
+            from,
+            to
+        )
+        .fetch(conn)
+    }
+}
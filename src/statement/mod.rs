@@ -0,0 +1,353 @@
+mod db;
+
+use std::fmt::Write;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{app_state::AppState, db::Db, error::AppResult, utils::UserInfo};
+
+/// Rendered lines are sent to the client in chunks of about this many bytes
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Amounts carry no currency in this system, OFX files use the ISO code for "no currency"
+const OFX_CURRENCY: &str = "XXX";
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(statement))
+        .with_state(app_state)
+}
+
+///Statement of the logged in user's account for a period.
+///Every movement of the account is listed with the balance right after it, between the opening
+///balance at `from` and the closing balance at `to`. The file is streamed while it is read.
+#[utoipa::path(
+    get,
+    path = "/statements",
+    tag = "Account Balance Management",
+    params(StatementQuery),
+    responses(
+        (status = 200, description = "Statement successfully generated", content_type = ["text/csv", "application/x-ndjson", "application/x-ofx"]),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 422, description = "Invalid period or format"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn statement(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Query(query): Query<StatementQuery>,
+) -> AppResult<impl IntoResponse> {
+    if query.from >= query.to {
+        return Ok((
+            http::StatusCode::UNPROCESSABLE_ENTITY,
+            "from must be before to",
+        )
+            .into_response());
+    }
+
+    let format = query.format.unwrap_or_default();
+    let filename = format!(
+        "statement-{}-{}.{}",
+        query.from.format("%Y%m%d"),
+        query.to.format("%Y%m%d"),
+        format.extension()
+    );
+
+    let (sender, receiver) = mpsc::channel(8);
+    tokio::spawn(async move {
+        if let Err(err) = write_statement(&db, &username, &query, format, &sender).await {
+            tracing::error!("failed to write statement: {err}");
+            // Aborts the response so the client doesn't take a truncated file for a complete one
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                format.content_type().to_string(),
+            ),
+            (
+                http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Renders the statement into chunks. Everything is read from one snapshot of the database,
+/// so the balances always add up even while new transactions are written
+async fn write_statement(
+    db: &Db,
+    username: &str,
+    query: &StatementQuery,
+    format: StatementFormat,
+    sender: &mpsc::Sender<anyhow::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let mut tx = db.pool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+
+    let opening_balance = Db::get_balance_before(&mut tx, username, query.from).await?;
+
+    let mut buffer = String::new();
+    format.write_opening(&mut buffer, username, query, opening_balance)?;
+
+    let mut balance = opening_balance;
+    {
+        let mut lines = Db::get_statement_lines(&mut tx, username, query.from, query.to);
+        while let Some(line) = lines.try_next().await? {
+            format.write_line(&mut buffer, &line)?;
+            balance = line.balance;
+
+            // A failed send means the client went away
+            if buffer.len() >= CHUNK_SIZE
+                && sender
+                    .send(Ok(std::mem::take(&mut buffer).into()))
+                    .await
+                    .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    format.write_closing(&mut buffer, query, balance)?;
+    let _ = sender.send(Ok(buffer.into())).await;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct StatementQuery {
+    /// Start of the period, inclusive
+    pub from: DateTime<Utc>,
+    /// End of the period, exclusive
+    pub to: DateTime<Utc>,
+    /// csv by default
+    pub format: Option<StatementFormat>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StatementFormat {
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// OFX 2.2, understood by most accounting software
+    Ofx,
+}
+
+/// A movement of the user's account
+#[derive(Serialize)]
+pub(crate) struct StatementLine {
+    pub posting_id: i64,
+    pub created_at: DateTime<Utc>,
+    /// The journal entry kind, e.g. transfer, deposit or withdrawal_reserve
+    pub kind: String,
+    pub transaction_id: Option<Uuid>,
+    pub withdrawal_id: Option<Uuid>,
+    /// The other user of a transaction
+    pub counterparty: Option<String>,
+    /// Positive when money came in
+    pub amount: i64,
+    /// Balance of the account right after this movement
+    pub balance: i64,
+}
+
+impl StatementFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "csv",
+            StatementFormat::Ndjson => "ndjson",
+            StatementFormat::Ofx => "ofx",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "text/csv",
+            StatementFormat::Ndjson => "application/x-ndjson",
+            StatementFormat::Ofx => "application/x-ofx",
+        }
+    }
+
+    fn write_opening(
+        self,
+        buffer: &mut String,
+        username: &str,
+        query: &StatementQuery,
+        opening_balance: i64,
+    ) -> anyhow::Result<()> {
+        match self {
+            StatementFormat::Csv => {
+                writeln!(
+                    buffer,
+                    "date,kind,transaction_id,withdrawal_id,counterparty,amount,balance"
+                )?;
+                writeln!(
+                    buffer,
+                    "{},opening_balance,,,,,{opening_balance}",
+                    timestamp(query.from)
+                )?;
+            }
+            StatementFormat::Ndjson => {
+                writeln!(
+                    buffer,
+                    "{}",
+                    json!({ "type": "opening_balance", "date": query.from, "balance": opening_balance })
+                )?;
+            }
+            StatementFormat::Ofx => {
+                write!(
+                    buffer,
+                    concat!(
+                        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                        "<?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n",
+                        "<OFX>\n<BANKMSGSRSV1>\n<STMTTRNRS>\n<TRNUID>0</TRNUID>\n",
+                        "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n",
+                        "<STMTRS>\n<CURDEF>{}</CURDEF>\n",
+                        "<BANKACCTFROM><BANKID>simple-payment-system</BANKID><ACCTID>{}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n",
+                        "<BANKTRANLIST>\n<DTSTART>{}</DTSTART>\n<DTEND>{}</DTEND>\n",
+                    ),
+                    OFX_CURRENCY,
+                    xml_escape(username),
+                    ofx_date(query.from),
+                    ofx_date(query.to),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_line(self, buffer: &mut String, line: &StatementLine) -> anyhow::Result<()> {
+        match self {
+            StatementFormat::Csv => {
+                writeln!(
+                    buffer,
+                    "{},{},{},{},{},{},{}",
+                    timestamp(line.created_at),
+                    line.kind,
+                    line.transaction_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                    line.withdrawal_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                    csv_field(line.counterparty.as_deref().unwrap_or_default()),
+                    line.amount,
+                    line.balance
+                )?;
+            }
+            StatementFormat::Ndjson => {
+                let mut value = serde_json::to_value(line)?;
+                value["type"] = json!("entry");
+                writeln!(buffer, "{value}")?;
+            }
+            StatementFormat::Ofx => {
+                writeln!(
+                    buffer,
+                    "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT><FITID>{}</FITID><NAME>{}</NAME><MEMO>{}</MEMO></STMTTRN>",
+                    match line.amount > 0 {
+                        true => "CREDIT",
+                        false => "DEBIT",
+                    },
+                    ofx_date(line.created_at),
+                    line.amount,
+                    line.posting_id,
+                    xml_escape(line.counterparty.as_deref().unwrap_or(&line.kind)),
+                    line.kind
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_closing(
+        self,
+        buffer: &mut String,
+        query: &StatementQuery,
+        closing_balance: i64,
+    ) -> anyhow::Result<()> {
+        match self {
+            StatementFormat::Csv => {
+                writeln!(
+                    buffer,
+                    "{},closing_balance,,,,,{closing_balance}",
+                    timestamp(query.to)
+                )?;
+            }
+            StatementFormat::Ndjson => {
+                writeln!(
+                    buffer,
+                    "{}",
+                    json!({ "type": "closing_balance", "date": query.to, "balance": closing_balance })
+                )?;
+            }
+            StatementFormat::Ofx => {
+                write!(
+                    buffer,
+                    concat!(
+                        "</BANKTRANLIST>\n",
+                        "<LEDGERBAL><BALAMT>{}</BALAMT><DTASOF>{}</DTASOF></LEDGERBAL>\n",
+                        "</STMTRS>\n</STMTTRNRS>\n</BANKMSGSRSV1>\n</OFX>\n",
+                    ),
+                    closing_balance,
+                    ofx_date(query.to),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn ofx_date(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%d%H%M%S.%3f[0:GMT]").to_string()
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,479 @@
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::PgConnection;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    config::config,
+    db::Db,
+    error::{AppError, AppResult},
+    user::Role,
+};
+
+use super::{
+    hash_recovery_code, matching_step, ChallengeOutcome, SecondFactor, TotpOutcome, TotpStatus,
+    CODE_LOCKOUT_SECONDS, MAX_CHALLENGE_ATTEMPTS, MAX_CODE_FAILURES,
+};
+
+impl Db {
+    /// Stores a new secret waiting for confirmation, replacing an unconfirmed one.
+    /// Returns false if the user already has a confirmed second factor
+    pub async fn start_totp_enrollment(&self, username: &str, secret: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO user_totp(username, secret) VALUES($1, $2)
+            ON CONFLICT (username) DO UPDATE SET secret = EXCLUDED.secret, last_used_step = 0,
+            created_at = now()
+            WHERE user_totp.confirmed_at IS NULL",
+            username,
+            secret
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Enables the second factor once the user proves their authenticator produces valid codes,
+    /// together with a first set of recovery codes
+    pub async fn confirm_totp(
+        &self,
+        username: &str,
+        code: &str,
+        recovery_code_hashes: &[String],
+        audit_context: &AuditContext,
+    ) -> AppResult<TotpOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(totp) = sqlx::query!(
+            r#"SELECT secret, last_used_step, confirmed_at IS NOT NULL AS "confirmed!"
+            FROM user_totp WHERE username = $1 FOR UPDATE"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(TotpOutcome::NotEnabled);
+        };
+
+        if totp.confirmed {
+            return Ok(TotpOutcome::AlreadyEnabled);
+        }
+
+        Self::ensure_code_checks_allowed(&mut transaction, username).await?;
+        let step = matching_step(&totp.secret, code, totp.last_used_step);
+        self.count_code_check(&mut transaction, username, step.is_some())
+            .await?;
+        let Some(step) = step else {
+            return Ok(TotpOutcome::InvalidCode);
+        };
+
+        sqlx::query!(
+            "UPDATE user_totp SET confirmed_at = now(), last_used_step = $2 WHERE username = $1",
+            username,
+            step
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::replace_recovery_codes(&mut transaction, username, recovery_code_hashes).await?;
+        Self::audit_two_factor_change(&mut transaction, username, true, audit_context).await?;
+
+        transaction.commit().await?;
+        Ok(TotpOutcome::Done)
+    }
+
+    pub async fn get_totp_status(&self, username: &str) -> sqlx::Result<TotpStatus> {
+        sqlx::query_as!(
+            TotpStatus,
+            r#"SELECT EXISTS(
+                SELECT 1 FROM user_totp WHERE username = $1 AND confirmed_at IS NOT NULL
+            ) AS "enabled!",
+            (SELECT COALESCE(step_up_threshold, $2) FROM user_totp
+                WHERE username = $1 AND confirmed_at IS NOT NULL) AS step_up_threshold,
+            (SELECT COUNT(*) FROM totp_recovery_codes
+                WHERE username = $1 AND used_at IS NULL) AS "recovery_codes_left!""#,
+            username,
+            config().STEP_UP_THRESHOLD
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn is_totp_enabled(&self, username: &str) -> sqlx::Result<bool> {
+        Self::is_totp_confirmed(&mut *self.pool.acquire().await?, username).await
+    }
+
+    /// Replaces the recovery codes of the user after checking a current code
+    pub async fn regenerate_recovery_codes(
+        &self,
+        username: &str,
+        code: &str,
+        recovery_code_hashes: &[String],
+    ) -> AppResult<TotpOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        if !Self::is_totp_confirmed(&mut transaction, username).await? {
+            return Ok(TotpOutcome::NotEnabled);
+        }
+        if !self
+            .verify_totp_code(&mut transaction, username, code)
+            .await?
+        {
+            return Ok(TotpOutcome::InvalidCode);
+        }
+
+        Self::replace_recovery_codes(&mut transaction, username, recovery_code_hashes).await?;
+
+        transaction.commit().await?;
+        Ok(TotpOutcome::Done)
+    }
+
+    /// Changes the amount above which transfers need a fresh code after checking a current code.
+    /// None goes back to the configured default
+    pub async fn set_step_up_threshold(
+        &self,
+        username: &str,
+        code: &str,
+        threshold: Option<i32>,
+    ) -> AppResult<TotpOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        if !Self::is_totp_confirmed(&mut transaction, username).await? {
+            return Ok(TotpOutcome::NotEnabled);
+        }
+        if !self
+            .verify_totp_code(&mut transaction, username, code)
+            .await?
+        {
+            return Ok(TotpOutcome::InvalidCode);
+        }
+
+        sqlx::query!(
+            "UPDATE user_totp SET step_up_threshold = $2 WHERE username = $1",
+            username,
+            threshold
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(TotpOutcome::Done)
+    }
+
+    /// Removes the second factor and the recovery codes after checking a code or a recovery code
+    pub async fn disable_totp(
+        &self,
+        username: &str,
+        second_factor: &SecondFactor,
+        audit_context: &AuditContext,
+    ) -> AppResult<TotpOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        if !Self::is_totp_confirmed(&mut transaction, username).await? {
+            return Ok(TotpOutcome::NotEnabled);
+        }
+        if !self
+            .verify_second_factor(&mut transaction, username, second_factor)
+            .await?
+        {
+            return Ok(TotpOutcome::InvalidCode);
+        }
+
+        sqlx::query!(
+            "DELETE FROM totp_recovery_codes WHERE username = $1",
+            username
+        )
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query!("DELETE FROM user_totp WHERE username = $1", username)
+            .execute(&mut *transaction)
+            .await?;
+        Self::audit_two_factor_change(&mut transaction, username, false, audit_context).await?;
+
+        transaction.commit().await?;
+        Ok(TotpOutcome::Done)
+    }
+
+    /// Fails with [`AppError::StepUpRequired`] if the user has a second factor and `amount` is
+    /// above their threshold, unless `code` is a fresh TOTP code. Runs inside the database
+    /// transaction of the transfer, so a failed transfer doesn't use up the code.
+    pub async fn require_step_up(
+        &self,
+        conn: &mut PgConnection,
+        username: &str,
+        amount: i64,
+        code: Option<&str>,
+    ) -> AppResult<()> {
+        let Some(threshold) = sqlx::query!(
+            r#"SELECT COALESCE(step_up_threshold, $2) AS "threshold!" FROM user_totp
+            WHERE username = $1 AND confirmed_at IS NOT NULL"#,
+            username,
+            config().STEP_UP_THRESHOLD
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|record| record.threshold) else {
+            return Ok(());
+        };
+
+        if amount <= threshold as i64 {
+            return Ok(());
+        }
+
+        match code {
+            Some(code) if self.verify_totp_code(&mut *conn, username, code).await? => Ok(()),
+            _ => Err(AppError::StepUpRequired),
+        }
+    }
+
+    pub async fn create_login_challenge(
+        &self,
+        username: &str,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO login_challenges(username, token_hash, expires_at) VALUES($1, $2, $3)",
+            username,
+            token_hash,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Completes a pending login with the second factor. Every attempt counts, also failed ones,
+    /// and a challenge can't be completed anymore after too many attempts
+    pub async fn complete_login_challenge(
+        &self,
+        token_hash: &str,
+        second_factor: &SecondFactor,
+    ) -> AppResult<ChallengeOutcome> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(challenge) = sqlx::query!(
+            r#"UPDATE login_challenges SET attempts = attempts + 1
+            WHERE token_hash = $1 AND completed_at IS NULL AND expires_at > now() AND attempts < $2
+            RETURNING challenge_id, username"#,
+            token_hash,
+            MAX_CHALLENGE_ATTEMPTS
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(ChallengeOutcome::Invalid);
+        };
+
+        if !self
+            .verify_second_factor(&mut transaction, &challenge.username, second_factor)
+            .await?
+        {
+            // The counted attempt is kept
+            transaction.commit().await?;
+            return Ok(ChallengeOutcome::InvalidCode);
+        }
+
+        sqlx::query!(
+            "UPDATE login_challenges SET completed_at = now() WHERE challenge_id = $1",
+            challenge.challenge_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        let role = sqlx::query!(
+            r#"SELECT role AS "role: Role" FROM user_credentials WHERE username = $1"#,
+            challenge.username
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .role;
+
+        transaction.commit().await?;
+        Ok(ChallengeOutcome::Completed {
+            username: challenge.username,
+            role,
+        })
+    }
+
+    /// Checks a code or a single use recovery code of a confirmed second factor and remembers the
+    /// time step of a code, so the same code can't be used again. Fails with
+    /// [`AppError::TooManyCodeAttempts`] while the code checks of the user are locked
+    async fn verify_second_factor(
+        &self,
+        conn: &mut PgConnection,
+        username: &str,
+        second_factor: &SecondFactor,
+    ) -> AppResult<bool> {
+        // Locking the second factor serializes the checks of a user, so concurrent guesses see
+        // the failures counted before them
+        let Some(totp) = sqlx::query!(
+            "SELECT secret, last_used_step FROM user_totp
+            WHERE username = $1 AND confirmed_at IS NOT NULL FOR UPDATE",
+            username
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        Self::ensure_code_checks_allowed(&mut *conn, username).await?;
+
+        let valid = match second_factor {
+            SecondFactor {
+                code: Some(code), ..
+            } => match matching_step(&totp.secret, code, totp.last_used_step) {
+                Some(step) => {
+                    sqlx::query!(
+                        "UPDATE user_totp SET last_used_step = $2 WHERE username = $1",
+                        username,
+                        step
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+                    true
+                }
+                None => false,
+            },
+            SecondFactor {
+                recovery_code: Some(recovery_code),
+                ..
+            } => {
+                sqlx::query!(
+                    "UPDATE totp_recovery_codes SET used_at = now()
+                    WHERE username = $1 AND code_hash = $2 AND used_at IS NULL",
+                    username,
+                    hash_recovery_code(recovery_code)
+                )
+                .execute(&mut *conn)
+                .await?
+                .rows_affected()
+                    == 1
+            }
+            _ => false,
+        };
+
+        self.count_code_check(&mut *conn, username, valid).await?;
+        Ok(valid)
+    }
+
+    async fn verify_totp_code(
+        &self,
+        conn: &mut PgConnection,
+        username: &str,
+        code: &str,
+    ) -> AppResult<bool> {
+        let second_factor = SecondFactor {
+            code: Some(code.to_string()),
+            recovery_code: None,
+        };
+        self.verify_second_factor(conn, username, &second_factor)
+            .await
+    }
+
+    async fn ensure_code_checks_allowed(conn: &mut PgConnection, username: &str) -> AppResult<()> {
+        let locked_until = sqlx::query!(
+            "SELECT locked_until FROM totp_failures WHERE username = $1 AND locked_until > now()",
+            username
+        )
+        .fetch_optional(conn)
+        .await?
+        .and_then(|record| record.locked_until);
+
+        match locked_until {
+            Some(retry_at) => Err(AppError::TooManyCodeAttempts { retry_at }),
+            None => Ok(()),
+        }
+    }
+
+    /// A valid code resets the failures inside the database transaction of the request. A failure
+    /// is written on its own connection, so it counts even if the request is rolled back.
+    /// Too many failures in a row lock the code checks of the user for a while
+    async fn count_code_check(
+        &self,
+        conn: &mut PgConnection,
+        username: &str,
+        valid: bool,
+    ) -> sqlx::Result<()> {
+        if valid {
+            sqlx::query!("DELETE FROM totp_failures WHERE username = $1", username)
+                .execute(conn)
+                .await?;
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "INSERT INTO totp_failures(username, failed_attempts) VALUES($1, 1)
+            ON CONFLICT (username) DO UPDATE SET
+            failed_attempts = CASE WHEN totp_failures.failed_attempts + 1 >= $2 THEN 0
+                ELSE totp_failures.failed_attempts + 1 END,
+            locked_until = CASE WHEN totp_failures.failed_attempts + 1 >= $2
+                THEN now() + make_interval(secs => $3) ELSE totp_failures.locked_until END",
+            username,
+            MAX_CODE_FAILURES,
+            CODE_LOCKOUT_SECONDS as f64
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_totp_confirmed(conn: &mut PgConnection, username: &str) -> sqlx::Result<bool> {
+        sqlx::query!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM user_totp WHERE username = $1 AND confirmed_at IS NOT NULL
+            ) AS "confirmed!""#,
+            username
+        )
+        .fetch_one(conn)
+        .await
+        .map(|record| record.confirmed)
+    }
+
+    async fn replace_recovery_codes(
+        conn: &mut PgConnection,
+        username: &str,
+        recovery_code_hashes: &[String],
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "DELETE FROM totp_recovery_codes WHERE username = $1",
+            username
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO totp_recovery_codes(username, code_hash)
+            SELECT $1, * FROM UNNEST($2::text[])",
+            username,
+            recovery_code_hashes
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn audit_two_factor_change(
+        conn: &mut PgConnection,
+        username: &str,
+        enabled: bool,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<()> {
+        Self::record_audit(
+            conn,
+            audit_context,
+            AuditEntry {
+                actor: Some(username),
+                action: AuditAction::TwoFactorChange,
+                target: Some(username),
+                before: Some(json!({ "enabled": !enabled })),
+                after: Some(json!({ "enabled": enabled })),
+            },
+        )
+        .await
+    }
+}
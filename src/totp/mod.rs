@@ -0,0 +1,434 @@
+mod db;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::{self, request::Parts},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState, audit::AuditContext, config::config, db::Db, error::AppResult, user::Role,
+    utils::SessionInfo,
+};
+
+/// Header carrying the fresh code that transfers and other payments above the step-up threshold need
+pub(crate) const TOTP_CODE_HEADER: &str = "X-TOTP-Code";
+/// Attempts to complete a login challenge before it has to be started over
+pub(crate) const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+/// Invalid codes in a row, over every kind of code check, before the checks are locked
+const MAX_CODE_FAILURES: i32 = 10;
+const CODE_LOCKOUT_SECONDS: i64 = 15 * 60;
+
+/// RFC 6238 defaults, the only parameters most authenticator apps support
+const PERIOD_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the periods right before and after the current one are accepted for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/2fa", get(totp_status))
+        .route("/2fa", delete(disable_totp))
+        .route("/2fa/enroll", post(enroll_totp))
+        .route("/2fa/confirm", post(confirm_totp))
+        .route("/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/2fa/step-up", put(set_step_up_threshold))
+        .with_state(app_state)
+}
+
+/// Value of the optional `X-TOTP-Code` header
+pub(crate) struct StepUpCode(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for StepUpCode {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(TOTP_CODE_HEADER) else {
+            return Ok(StepUpCode(None));
+        };
+
+        match value.to_str() {
+            Ok(code) => Ok(StepUpCode(Some(code.to_string()))),
+            Err(_) => Err((
+                http::StatusCode::BAD_REQUEST,
+                "X-TOTP-Code must be a code of the authenticator app",
+            )
+                .into_response()),
+        }
+    }
+}
+
+///Whether the logged in user has a second factor and how many recovery codes are left
+#[utoipa::path(
+    get,
+    path = "/users/2fa",
+    tag = "Two-Factor Authentication",
+    responses(
+        (status = 200, description = "Status successfully retreived", body = TotpStatus),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn totp_status(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_totp_status(&username).await?))
+}
+
+///Generates a new TOTP secret for the logged in user. The second factor is only used once it is
+///confirmed with a code of the authenticator app
+#[utoipa::path(
+    post,
+    path = "/users/2fa/enroll",
+    tag = "Two-Factor Authentication",
+    responses(
+        (status = 200, description = "Secret successfully generated", body = TotpEnrollment),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 409, description = "Two-factor authentication is already enabled"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn enroll_totp(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+) -> AppResult<impl IntoResponse> {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    let secret = BASE32_NOPAD.encode(&secret);
+
+    if !db.start_totp_enrollment(&username, &secret).await? {
+        return Ok(TotpOutcome::AlreadyEnabled
+            .into_response_parts()
+            .into_response());
+    }
+
+    let otpauth_uri = otpauth_uri(&username, &secret);
+    Ok(Json(TotpEnrollment {
+        secret,
+        otpauth_uri,
+    })
+    .into_response())
+}
+
+///Enables two-factor authentication with a first code of the authenticator app.
+///Returns the recovery codes, which are only shown once
+#[utoipa::path(
+    post,
+    path = "/users/2fa/confirm",
+    tag = "Two-Factor Authentication",
+    request_body = CodeRequest,
+    responses(
+        (status = 200, description = "Two-factor authentication successfully enabled", body = RecoveryCodes),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 404, description = "No pending enrollment"),
+        (status = 409, description = "Two-factor authentication is already enabled"),
+        (status = 422, description = "Invalid code"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn confirm_totp(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    audit_context: AuditContext,
+    Json(code_request): Json<CodeRequest>,
+) -> AppResult<impl IntoResponse> {
+    let (recovery_codes, recovery_code_hashes) = generate_recovery_codes();
+
+    match db
+        .confirm_totp(
+            &username,
+            &code_request.code,
+            &recovery_code_hashes,
+            &audit_context,
+        )
+        .await?
+    {
+        TotpOutcome::Done => Ok(Json(RecoveryCodes { recovery_codes }).into_response()),
+        outcome => Ok(outcome.into_response_parts().into_response()),
+    }
+}
+
+///Replaces the recovery codes of the logged in user, the old ones stop working
+#[utoipa::path(
+    post,
+    path = "/users/2fa/recovery-codes",
+    tag = "Two-Factor Authentication",
+    request_body = CodeRequest,
+    responses(
+        (status = 200, description = "Recovery codes successfully replaced", body = RecoveryCodes),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 404, description = "Two-factor authentication is not enabled"),
+        (status = 422, description = "Invalid code"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn regenerate_recovery_codes(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    Json(code_request): Json<CodeRequest>,
+) -> AppResult<impl IntoResponse> {
+    let (recovery_codes, recovery_code_hashes) = generate_recovery_codes();
+
+    match db
+        .regenerate_recovery_codes(&username, &code_request.code, &recovery_code_hashes)
+        .await?
+    {
+        TotpOutcome::Done => Ok(Json(RecoveryCodes { recovery_codes }).into_response()),
+        outcome => Ok(outcome.into_response_parts().into_response()),
+    }
+}
+
+///Sets the amount above which transfers of the logged in user need a fresh code in the
+///`X-TOTP-Code` header
+#[utoipa::path(
+    put,
+    path = "/users/2fa/step-up",
+    tag = "Two-Factor Authentication",
+    request_body = StepUpRequest,
+    responses(
+        (status = 200, description = "Threshold successfully changed"),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 404, description = "Two-factor authentication is not enabled"),
+        (status = 422, description = "Invalid code or threshold"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn set_step_up_threshold(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    Json(step_up_request): Json<StepUpRequest>,
+) -> AppResult<impl IntoResponse> {
+    step_up_request.validate()?;
+
+    let outcome = db
+        .set_step_up_threshold(&username, &step_up_request.code, step_up_request.threshold)
+        .await?;
+
+    Ok(outcome.into_response_parts())
+}
+
+///Disables two-factor authentication with a code or a recovery code
+#[utoipa::path(
+    delete,
+    path = "/users/2fa",
+    tag = "Two-Factor Authentication",
+    request_body = SecondFactor,
+    responses(
+        (status = 200, description = "Two-factor authentication successfully disabled"),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 404, description = "Two-factor authentication is not enabled"),
+        (status = 422, description = "Invalid code"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn disable_totp(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+    audit_context: AuditContext,
+    Json(second_factor): Json<SecondFactor>,
+) -> AppResult<impl IntoResponse> {
+    Ok(db
+        .disable_totp(&username, &second_factor, &audit_context)
+        .await?
+        .into_response_parts())
+}
+
+fn otpauth_uri(username: &str, secret: &str) -> String {
+    let issuer = &config().TOTP_ISSUER;
+    // Authenticator apps show a `+` in the label literally, spaces have to be `%20`
+    let encode = |value: &str| {
+        url::form_urlencoded::byte_serialize(value.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20")
+    };
+
+    format!(
+        "otpauth://totp/{}:{}?secret={secret}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={PERIOD_SECONDS}",
+        encode(issuer),
+        encode(username),
+        encode(issuer)
+    )
+}
+
+/// RFC 4226 code of the given time step
+fn totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Returns the time step the code belongs to if it is valid right now and newer than the last
+/// accepted code, so every code works only once
+fn matching_step(secret: &str, code: &str, last_used_step: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let current_step = Utc::now().timestamp() / PERIOD_SECONDS;
+
+    (current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS)
+        .filter(|step| *step > last_used_step)
+        .find(|step| totp_code(&secret, *step) == code.trim())
+}
+
+/// Returns the recovery codes to show to the user and the digests to store
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            OsRng.fill_bytes(&mut bytes);
+            let encoded = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            let code = format!(
+                "{}-{}-{}-{}",
+                &encoded[..4],
+                &encoded[4..8],
+                &encoded[8..12],
+                &encoded[12..]
+            );
+            let hash = hash_recovery_code(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+/// Recovery codes are stored as SHA-256 digests, ignoring case and dashes
+fn hash_recovery_code(recovery_code: &str) -> String {
+    let normalized: String = recovery_code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TotpEnrollment {
+    /// Base32 secret for manual entry in the authenticator app
+    pub secret: String,
+    /// Same secret as a URI, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TotpStatus {
+    pub enabled: bool,
+    /// Transfers above this amount need a fresh code, missing while disabled
+    pub step_up_threshold: Option<i32>,
+    pub recovery_codes_left: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RecoveryCodes {
+    /// Single use codes for when the authenticator app is lost, only shown once
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CodeRequest {
+    /// Current code of the authenticator app
+    pub code: String,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub(crate) struct StepUpRequest {
+    /// Back to the default threshold when left out
+    #[validate(range(min = 0))]
+    pub threshold: Option<i32>,
+    /// Current code of the authenticator app
+    pub code: String,
+}
+
+/// A code of the authenticator app or one of the recovery codes
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct SecondFactor {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+pub(crate) enum TotpOutcome {
+    Done,
+    NotEnabled,
+    AlreadyEnabled,
+    InvalidCode,
+}
+
+impl TotpOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            TotpOutcome::Done => (http::StatusCode::OK, String::new()),
+            TotpOutcome::NotEnabled => (
+                http::StatusCode::NOT_FOUND,
+                "Two-factor authentication is not enabled".to_string(),
+            ),
+            TotpOutcome::AlreadyEnabled => (
+                http::StatusCode::CONFLICT,
+                "Two-factor authentication is already enabled".to_string(),
+            ),
+            TotpOutcome::InvalidCode => (
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid code".to_string(),
+            ),
+        }
+    }
+}
+
+pub(crate) enum ChallengeOutcome {
+    Completed {
+        username: String,
+        role: Role,
+    },
+    /// The code was wrong, the challenge can be tried again
+    InvalidCode,
+    /// Unknown, expired, completed or too many attempts
+    Invalid,
+}
@@ -1,90 +1,537 @@
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::PgConnection;
 use uuid::Uuid;
 
-use crate::db::Db;
+use crate::{
+    audit::{AuditAction, AuditEntry, AuditSource},
+    db::Db,
+    error::AppResult,
+    fee::FeeOperation,
+    ledger::{EntryReference, LedgerAccount, Movement},
+    risk::{RiskDecision, RiskDenied, RiskEngine, TransferAttempt},
+};
 
-use super::{Transaction, TransactionRequest};
+use super::{
+    BatchItemError, BatchItemResult, BatchMode, BatchResponse, Direction, RefundOutcome, SortOrder,
+    Transaction, TransactionKind, TransactionListQuery, TransactionRequest, TransferOutcome,
+};
 
 impl Db {
-    // Scope for optimization :  Instead of running 5 queries, a single sql function can be written and called from application.
-    /// If the transaction fails to insufficent balance this method returns false
+    /// Moves the money inside an already open database transaction and returns the id of the new transaction.
+    /// The sender pays the transfer fee on top of the amount.
+    /// Nothing is written if the balance is insufficient or a risk rule wants the transfer reviewed.
+    /// Fails with [`AppError::AccountLocked`](crate::error::AppError::AccountLocked) if the status of
+    /// either account doesn't allow the transfer, with
+    /// [`AppError::LimitExceeded`](crate::error::AppError::LimitExceeded) if it goes over a limit of the sender
+    /// and with [`AppError::RiskDenied`](crate::error::AppError::RiskDenied) if a risk rule refuses it.
+    /// The risk rules are skipped without a `risk_engine`, which is only done for approved reviews.
     pub async fn process_transaction(
-        &self,
+        conn: &mut PgConnection,
         username: &str,
-        transaction_request: TransactionRequest,
-    ) -> sqlx::Result<bool> {
-        let mut transaction = self.pool.begin().await?;
-
+        transaction_request: &TransactionRequest,
+        risk_engine: Option<&RiskEngine>,
+        source: &AuditSource<'_>,
+    ) -> AppResult<TransferOutcome> {
         //lock the rows
-        sqlx::query!(
-            "SELECT balance FROM user_credentials WHERE username IN ($1, $2) FOR UPDATE;",
-            username,
-            transaction_request.to_user
+        let accounts = Self::lock_user_accounts(
+            &mut *conn,
+            &[username, transaction_request.to_user.as_str()],
         )
-        .fetch_all(&mut *transaction)
         .await?;
 
-        let balance = sqlx::query!(
-            "SELECT balance FROM user_credentials WHERE username = $1",
-            username
+        let find_account = |username: &str| {
+            accounts
+                .iter()
+                .find(|account| account.username == username)
+                .ok_or(sqlx::Error::RowNotFound)
+        };
+        let from_account = find_account(username)?;
+        let to_account = find_account(&transaction_request.to_user)?;
+
+        from_account.ensure_allows(Movement::Send)?;
+        to_account.ensure_allows(Movement::Receive)?;
+
+        Self::get_limit_usage(&mut *conn, username)
+            .await?
+            .check_transfers(&[transaction_request.amount as i64])?;
+
+        let fee = Self::get_fee_plan(&mut *conn, username, FeeOperation::Transfer)
+            .await?
+            .fee(transaction_request.amount);
+
+        // check if balance is sufficient
+        if from_account.available < transaction_request.amount as i64 + fee as i64 {
+            return Ok(TransferOutcome::InsufficientBalance);
+        }
+
+        // The sender is locked, so the rules see every earlier transfer of a concurrent request
+        if let Some(risk_engine) = risk_engine {
+            let transfer = TransferAttempt {
+                from_user: username,
+                transfers: vec![(
+                    &transaction_request.to_user,
+                    transaction_request.amount as i64,
+                )],
+            };
+            match risk_engine.evaluate(&mut *conn, &transfer).await? {
+                RiskDecision::Allow => {}
+                RiskDecision::Deny { rule, reason } => {
+                    return Err(RiskDenied { rule, reason }.into())
+                }
+                RiskDecision::Review { rule, reason } => {
+                    return Ok(TransferOutcome::ReviewRequired { rule, reason })
+                }
+            }
+        }
+
+        let transaction_id = Self::record_transfer(
+            &mut *conn,
+            from_account,
+            to_account,
+            transaction_request.amount,
+            fee,
+            TransactionKind::Transfer,
+            None,
+            source,
+        )
+        .await?;
+
+        Ok(TransferOutcome::Executed(transaction_id))
+    }
+
+    /// Executes several transfers from `username` inside an already open database transaction.
+    /// The sender and all recipients are locked up front so the outcome of every transfer is
+    /// known before anything is written. In atomic mode nothing is written if any transfer fails.
+    /// A locked sender fails the whole batch, a locked recipient only fails its transfers.
+    /// The risk rules see the transfers to be executed together, a batch can't be held for a review.
+    pub async fn process_batch(
+        conn: &mut PgConnection,
+        username: &str,
+        transaction_requests: &[TransactionRequest],
+        mode: BatchMode,
+        risk_engine: &RiskEngine,
+        source: &AuditSource<'_>,
+    ) -> AppResult<BatchResponse> {
+        let mut usernames = vec![username];
+        usernames.extend(
+            transaction_requests
+                .iter()
+                .map(|transaction_request| transaction_request.to_user.as_str()),
+        );
+
+        // lock_user_accounts locks in account id order, which keeps concurrent batches from deadlocking
+        let accounts = Self::lock_user_accounts(&mut *conn, &usernames).await?;
+
+        let find_account =
+            |username: &str| accounts.iter().find(|account| account.username == username);
+        let from_account = find_account(username).ok_or(sqlx::Error::RowNotFound)?;
+        from_account.ensure_allows(Movement::Send)?;
+
+        let fee_plan = Self::get_fee_plan(&mut *conn, username, FeeOperation::Transfer).await?;
+
+        let mut available = from_account.available;
+        let mut items: Vec<BatchItemResult> = transaction_requests
+            .iter()
+            .enumerate()
+            .map(|(index, transaction_request)| {
+                let error = match find_account(&transaction_request.to_user) {
+                    None => Some(BatchItemError::UnknownRecipient),
+                    Some(to_account) if to_account.ensure_allows(Movement::Receive).is_err() => {
+                        Some(BatchItemError::RecipientLocked)
+                    }
+                    Some(_) => {
+                        let total = transaction_request.amount as i64
+                            + fee_plan.fee(transaction_request.amount) as i64;
+                        if available < total {
+                            Some(BatchItemError::InsufficientBalance)
+                        } else {
+                            available -= total;
+                            None
+                        }
+                    }
+                };
+
+                BatchItemResult {
+                    index,
+                    transaction_id: None,
+                    error,
+                }
+            })
+            .collect();
+
+        let failed = items.iter().any(|item| item.error.is_some());
+        if failed && mode == BatchMode::Atomic {
+            return Ok(BatchResponse {
+                executed: false,
+                items,
+            });
+        }
+
+        // Limits and risk rules apply to the batch as a whole, like a series of single transfers
+        let transfers: Vec<(&str, i64)> = items
+            .iter()
+            .zip(transaction_requests)
+            .filter(|(item, _)| item.error.is_none())
+            .map(|(_, transaction_request)| {
+                (
+                    transaction_request.to_user.as_str(),
+                    transaction_request.amount as i64,
+                )
+            })
+            .collect();
+        let amounts: Vec<i64> = transfers.iter().map(|(_, amount)| *amount).collect();
+        Self::get_limit_usage(&mut *conn, username)
+            .await?
+            .check_transfers(&amounts)?;
+
+        risk_engine
+            .enforce(
+                &mut *conn,
+                &TransferAttempt {
+                    from_user: username,
+                    transfers,
+                },
+            )
+            .await?;
+
+        for (item, transaction_request) in items.iter_mut().zip(transaction_requests) {
+            if item.error.is_some() {
+                continue;
+            }
+
+            let to_account =
+                find_account(&transaction_request.to_user).ok_or(sqlx::Error::RowNotFound)?;
+
+            let transaction_id = Self::record_transfer(
+                &mut *conn,
+                from_account,
+                to_account,
+                transaction_request.amount,
+                fee_plan.fee(transaction_request.amount),
+                TransactionKind::Transfer,
+                None,
+                source,
+            )
+            .await?;
+
+            item.transaction_id = Some(transaction_id);
+        }
+
+        Ok(BatchResponse {
+            executed: true,
+            items,
+        })
+    }
+
+    /// Gives money of a transfer back to its sender inside an already open database transaction,
+    /// as a new transaction of the given kind linked to the original one.
+    /// Without an amount everything that was not given back yet is refunded. Refunds carry no fee
+    /// and the fee of the original transfer is not given back.
+    /// `initiator` is the user asking for the refund, who must be the recipient of the transfer.
+    /// It is None for reversals done by an admin.
+    pub async fn refund_transaction(
+        conn: &mut PgConnection,
+        original_transaction_id: Uuid,
+        kind: TransactionKind,
+        initiator: Option<&str>,
+        amount: Option<i32>,
+        source: &AuditSource<'_>,
+    ) -> AppResult<RefundOutcome> {
+        // Locking the original transfer serializes concurrent refunds of it
+        let Some(original) = sqlx::query!(
+            r#"SELECT from_user, to_user, amount, kind AS "kind: TransactionKind"
+            FROM transactions WHERE transaction_id = $1 FOR UPDATE"#,
+            original_transaction_id
         )
-        .fetch_one(&mut *transaction)
+        .fetch_optional(&mut *conn)
         .await?
-        .balance;
+        else {
+            return Ok(RefundOutcome::NotFound);
+        };
 
-        // check if balance is sufficient
-        if balance < transaction_request.amount as i64 {
-            transaction.rollback().await?;
-            return Ok(false);
+        if initiator.is_some_and(|initiator| initiator != original.to_user) {
+            return Ok(RefundOutcome::NotRecipient);
         }
 
-        sqlx::query!(
-            "UPDATE user_credentials SET balance = balance - $1 WHERE username = $2 ",
-            transaction_request.amount as i64,
-            username
+        if original.kind != TransactionKind::Transfer {
+            return Ok(RefundOutcome::NotRefundable);
+        }
+
+        let refunded = sqlx::query!(
+            r#"SELECT COALESCE(SUM(amount), 0)::INT AS "refunded!" FROM transactions
+            WHERE original_transaction_id = $1"#,
+            original_transaction_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .refunded;
+
+        let refundable = original.amount - refunded;
+        let amount = amount.unwrap_or(refundable);
+        if amount <= 0 || amount > refundable {
+            return Ok(RefundOutcome::ExceedsRefundable { refundable });
+        }
+
+        let accounts = Self::lock_user_accounts(
+            &mut *conn,
+            &[original.to_user.as_str(), original.from_user.as_str()],
         )
-        .execute(&mut *transaction)
         .await?;
 
-        sqlx::query!(
-            "UPDATE user_credentials SET balance = balance + $1 WHERE username = $2 ",
-            transaction_request.amount as i64,
-            transaction_request.to_user
+        let find_account = |username: &str| {
+            accounts
+                .iter()
+                .find(|account| account.username == username)
+                .ok_or(sqlx::Error::RowNotFound)
+        };
+        let recipient_account = find_account(&original.to_user)?;
+        let sender_account = find_account(&original.from_user)?;
+
+        // Reversals are corrections made by an admin and go through regardless of the status
+        if initiator.is_some() {
+            recipient_account.ensure_allows(Movement::Send)?;
+            sender_account.ensure_allows(Movement::Receive)?;
+        }
+
+        if recipient_account.available < amount as i64 {
+            return Ok(RefundOutcome::InsufficientBalance);
+        }
+
+        let transaction_id = Self::record_transfer(
+            &mut *conn,
+            recipient_account,
+            sender_account,
+            amount,
+            0,
+            kind,
+            Some(original_transaction_id),
+            source,
         )
-        .execute(&mut *transaction)
         .await?;
 
-        sqlx::query!(
-            "INSERT INTO transactions(from_user, to_user, amount) VALUES($1, $2, $3)",
-            username,
-            transaction_request.to_user,
-            transaction_request.amount
+        Ok(RefundOutcome::Refunded(transaction_id))
+    }
+
+    /// Writes a transaction between two locked accounts together with its journal entry, the
+    /// entry charging `fee` to the sender, the webhook events of both parties and the audit record,
+    /// and returns the id of the transaction
+    #[allow(clippy::too_many_arguments)]
+    pub async fn record_transfer(
+        conn: &mut PgConnection,
+        from_account: &LedgerAccount,
+        to_account: &LedgerAccount,
+        amount: i32,
+        fee: i32,
+        kind: TransactionKind,
+        original_transaction_id: Option<Uuid>,
+        source: &AuditSource<'_>,
+    ) -> sqlx::Result<Uuid> {
+        let transaction = sqlx::query_as!(
+            Transaction,
+            r#"INSERT INTO transactions(from_user, to_user, amount, fee, kind, original_transaction_id)
+            VALUES($1, $2, $3, $4, $5, $6)
+            RETURNING transaction_id, from_user, to_user, amount, fee,
+            kind AS "kind: TransactionKind", original_transaction_id, created_at"#,
+            from_account.username,
+            to_account.username,
+            amount,
+            fee,
+            kind as TransactionKind,
+            original_transaction_id
         )
-        .execute(&mut *transaction)
+        .fetch_one(&mut *conn)
         .await?;
 
-        transaction.commit().await?;
+        Self::post_journal_entry(
+            &mut *conn,
+            kind.entry_kind(),
+            EntryReference::Transaction(transaction.transaction_id),
+            &[
+                (from_account.account_id, -(amount as i64)),
+                (to_account.account_id, amount as i64),
+            ],
+        )
+        .await?;
+
+        Self::charge_fee(
+            &mut *conn,
+            from_account.account_id,
+            fee,
+            EntryReference::Transaction(transaction.transaction_id),
+        )
+        .await?;
+
+        Self::record_event(
+            &mut *conn,
+            &from_account.username,
+            kind.event_type(),
+            &transaction,
+        )
+        .await?;
+        if to_account.username != from_account.username {
+            Self::record_event(
+                &mut *conn,
+                &to_account.username,
+                kind.event_type(),
+                &transaction,
+            )
+            .await?;
+        }
 
-        Ok(true)
+        Self::record_audit(
+            &mut *conn,
+            source.context,
+            AuditEntry {
+                actor: source.actor,
+                action: AuditAction::Transfer,
+                target: Some(&to_account.username),
+                before: None,
+                after: Some(json!({
+                    "transaction_id": transaction.transaction_id,
+                    "from_user": from_account.username,
+                    "amount": amount,
+                    "fee": fee,
+                    "kind": kind,
+                    "original_transaction_id": original_transaction_id,
+                })),
+            },
+        )
+        .await?;
+
+        Ok(transaction.transaction_id)
     }
 
     pub async fn get_transaction(&self, id: Uuid) -> sqlx::Result<Transaction> {
         sqlx::query_as!(
             Transaction,
-            "SELECT * FROM transactions WHERE transaction_id = $1",
+            r#"SELECT transaction_id, from_user, to_user, amount, fee, kind AS "kind: TransactionKind",
+            original_transaction_id, created_at
+            FROM transactions WHERE transaction_id = $1"#,
             id
         )
         .fetch_one(&self.pool)
         .await
     }
 
-    pub async fn get_transactions_list(&self, username: &str) -> sqlx::Result<Vec<Transaction>> {
+    /// Every transaction of the user, used where transactions are combined with other records
+    pub async fn get_transaction_history(&self, username: &str) -> sqlx::Result<Vec<Transaction>> {
         sqlx::query_as!(
             Transaction,
-            "SELECT * FROM transactions WHERE from_user = $1 or to_user = $2",
+            r#"SELECT transaction_id, from_user, to_user, amount, fee, kind AS "kind: TransactionKind",
+            original_transaction_id, created_at
+            FROM transactions WHERE from_user = $1 or to_user = $2"#,
             username,
             username
         )
         .fetch_all(&self.pool)
         .await
     }
+
+    /// A page of the user's transactions after the `(created_at, transaction_id)` position `after`.
+    /// Sent and received transactions are read separately so each half walks its own index
+    pub async fn get_transactions_list(
+        &self,
+        username: &str,
+        query: &TransactionListQuery,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Transaction>> {
+        let include_sent = query.direction != Some(Direction::Received);
+        let include_received = query.direction != Some(Direction::Sent);
+        let (after_created_at, after_transaction_id) = after.unzip();
+
+        match query.order.unwrap_or_default() {
+            SortOrder::Newest => {
+                sqlx::query_as!(
+                    Transaction,
+                    r#"SELECT transaction_id AS "transaction_id!", from_user AS "from_user!",
+                    to_user AS "to_user!", amount AS "amount!", fee AS "fee!",
+                    kind AS "kind!: TransactionKind",
+                    original_transaction_id, created_at AS "created_at!"
+                    FROM ((SELECT transaction_id, from_user, to_user, amount, fee, kind,
+                    original_transaction_id, created_at
+                    FROM transactions
+                    WHERE $2 AND from_user = $1
+                    AND ($4::TEXT IS NULL OR to_user = $4)
+                    AND amount BETWEEN COALESCE($5::INT, 0) AND COALESCE($6::INT, 2147483647)
+                    AND created_at >= COALESCE($7::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($8::TIMESTAMPTZ, 'infinity')
+                    AND (created_at, transaction_id) < (COALESCE($9::TIMESTAMPTZ, 'infinity'), COALESCE($10::UUID, 'ffffffff-ffff-ffff-ffff-ffffffffffff'))
+                    ORDER BY created_at DESC, transaction_id DESC
+                    LIMIT $11)
+                    UNION ALL
+                    (SELECT transaction_id, from_user, to_user, amount, fee, kind,
+                    original_transaction_id, created_at
+                    FROM transactions
+                    WHERE $3 AND to_user = $1 AND from_user <> $1
+                    AND ($4::TEXT IS NULL OR from_user = $4)
+                    AND amount BETWEEN COALESCE($5::INT, 0) AND COALESCE($6::INT, 2147483647)
+                    AND created_at >= COALESCE($7::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($8::TIMESTAMPTZ, 'infinity')
+                    AND (created_at, transaction_id) < (COALESCE($9::TIMESTAMPTZ, 'infinity'), COALESCE($10::UUID, 'ffffffff-ffff-ffff-ffff-ffffffffffff'))
+                    ORDER BY created_at DESC, transaction_id DESC
+                    LIMIT $11)) page
+                    ORDER BY created_at DESC, transaction_id DESC
+                    LIMIT $11"#,
+                    username,
+                    include_sent,
+                    include_received,
+                    query.counterparty,
+                    query.min_amount,
+                    query.max_amount,
+                    query.from,
+                    query.to,
+                    after_created_at,
+                    after_transaction_id,
+                    limit
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+            SortOrder::Oldest => {
+                sqlx::query_as!(
+                    Transaction,
+                    r#"SELECT transaction_id AS "transaction_id!", from_user AS "from_user!",
+                    to_user AS "to_user!", amount AS "amount!", fee AS "fee!",
+                    kind AS "kind!: TransactionKind",
+                    original_transaction_id, created_at AS "created_at!"
+                    FROM ((SELECT transaction_id, from_user, to_user, amount, fee, kind,
+                    original_transaction_id, created_at
+                    FROM transactions
+                    WHERE $2 AND from_user = $1
+                    AND ($4::TEXT IS NULL OR to_user = $4)
+                    AND amount BETWEEN COALESCE($5::INT, 0) AND COALESCE($6::INT, 2147483647)
+                    AND created_at >= COALESCE($7::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($8::TIMESTAMPTZ, 'infinity')
+                    AND (created_at, transaction_id) > (COALESCE($9::TIMESTAMPTZ, '-infinity'), COALESCE($10::UUID, '00000000-0000-0000-0000-000000000000'))
+                    ORDER BY created_at, transaction_id
+                    LIMIT $11)
+                    UNION ALL
+                    (SELECT transaction_id, from_user, to_user, amount, fee, kind,
+                    original_transaction_id, created_at
+                    FROM transactions
+                    WHERE $3 AND to_user = $1 AND from_user <> $1
+                    AND ($4::TEXT IS NULL OR from_user = $4)
+                    AND amount BETWEEN COALESCE($5::INT, 0) AND COALESCE($6::INT, 2147483647)
+                    AND created_at >= COALESCE($7::TIMESTAMPTZ, '-infinity') AND created_at < COALESCE($8::TIMESTAMPTZ, 'infinity')
+                    AND (created_at, transaction_id) > (COALESCE($9::TIMESTAMPTZ, '-infinity'), COALESCE($10::UUID, '00000000-0000-0000-0000-000000000000'))
+                    ORDER BY created_at, transaction_id
+                    LIMIT $11)) page
+                    ORDER BY created_at, transaction_id
+                    LIMIT $11"#,
+                    username,
+                    include_sent,
+                    include_received,
+                    query.counterparty,
+                    query.min_amount,
+                    query.max_amount,
+                    query.from,
+                    query.to,
+                    after_created_at,
+                    after_transaction_id,
+                    limit
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+        }
+    }
 }
@@ -1,25 +1,39 @@
 mod db;
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
-use chrono::Utc;
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 use validator::Validate;
 
-use crate::{app_state::AppState, db::Db, error::AppResult, utils::UserInfo};
+use crate::{
+    app_state::AppState,
+    audit::{AuditAction, AuditContext, AuditEntry, AuditSource},
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    ledger::EntryKind,
+    risk::{ReviewStatus, RiskEngine},
+    totp::StepUpCode,
+    utils::{AdminRole, RoleInfo, UserInfo},
+    webhook::EventType,
+};
 
 pub(super) fn get_router(app_state: AppState) -> Router {
     Router::new()
         .route("/", post(create_transaction))
+        .route("/batch", post(create_batch))
         .route("/:id", get(get_transaction_by_id))
         .route("/", get(transactions_list))
+        .route("/:id/refund", post(refund_transaction))
+        .route("/:id/reversal", post(reverse_transaction))
         .with_state(app_state)
 }
 #[utoipa::path(
@@ -27,35 +41,340 @@ pub(super) fn get_router(app_state: AppState) -> Router {
     path = "/transactions",
     tag = "Transactions",
     request_body = TransactionRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of moving money again"),
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required above the step-up threshold of users with two-factor authentication")
+    ),
     responses(
         (status = 200, description = "Transacion successfully executed"),
+        (status = 202, description = "Transfer held for review by a risk rule, returns the review id", body = Uuid),
         (status = 402, description = "Insufficient balance"),
         (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "A fresh TOTP code is required for this amount, a transfer limit would be exceeded or a risk rule refused the transfer"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 429, description = "Too many invalid codes, try again later"),
         (status = 500, description = "Internal Server Error"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn create_transaction(
     State(db): State<Db>,
+    State(risk_engine): State<RiskEngine>,
     UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    StepUpCode(step_up_code): StepUpCode,
+    audit_context: AuditContext,
     Json(transaciton_request): Json<TransactionRequest>,
 ) -> AppResult<impl IntoResponse> {
     transaciton_request.validate()?;
 
-    if !db
-        .process_transaction(&username, transaciton_request)
-        .await?
-    {
-        return Ok((
-            http::StatusCode::PAYMENT_REQUIRED,
-            "Insufficent balance in user account",
-        )
-            .into_response());
+    let fingerprint = request_fingerprint("POST /transactions", &transaciton_request)?;
+
+    let step_up_db = db.clone();
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                step_up_db
+                    .require_step_up(
+                        conn,
+                        username,
+                        transaciton_request.amount as i64,
+                        step_up_code.as_deref(),
+                    )
+                    .await?;
+
+                let source = AuditSource {
+                    actor: Some(username),
+                    context: &audit_context,
+                };
+                match Db::process_transaction(
+                    conn,
+                    username,
+                    &transaciton_request,
+                    Some(&risk_engine),
+                    &source,
+                )
+                .await?
+                {
+                    TransferOutcome::Executed(_) => Ok((http::StatusCode::OK, String::new())),
+                    TransferOutcome::InsufficientBalance => Ok((
+                        http::StatusCode::PAYMENT_REQUIRED,
+                        "Insufficent balance in user account".to_string(),
+                    )),
+                    TransferOutcome::ReviewRequired { rule, reason } => {
+                        let review_id = Db::hold_transfer_for_review(
+                            conn,
+                            username,
+                            &transaciton_request,
+                            &rule,
+                            &reason,
+                        )
+                        .await?;
+
+                        Db::record_audit(
+                            conn,
+                            &audit_context,
+                            AuditEntry {
+                                actor: Some(username),
+                                action: AuditAction::TransferReview,
+                                target: Some(&review_id.to_string()),
+                                before: None,
+                                after: Some(serde_json::json!({
+                                    "status": ReviewStatus::Pending,
+                                    "to_user": transaciton_request.to_user,
+                                    "amount": transaciton_request.amount,
+                                    "rule": rule,
+                                    "reason": reason,
+                                })),
+                            },
+                        )
+                        .await?;
+
+                        Ok((http::StatusCode::ACCEPTED, review_id.to_string()))
+                    }
+                }
+            })
+        },
+    )
+    .await
+}
+
+///Refunds a received transfer fully or partially back to its sender. Returns the id of the refund
+#[utoipa::path(
+    post,
+    path = "/transactions/{id}/refund",
+    tag = "Transactions",
+    request_body = RefundRequest,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Id of the transfer to refund"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of refunding again")
+    ),
+    responses(
+        (status = 200, description = "Refund successfully executed", body = Uuid),
+        (status = 402, description = "Insufficient balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "Only the recipient of the transfer can refund it"),
+        (status = 404, description = "Transaction not found"),
+        (status = 409, description = "The transaction is not a transfer or the amount exceeds what is left to refund"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn refund_transaction(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+    Json(refund_request): Json<RefundRequest>,
+) -> AppResult<impl IntoResponse> {
+    refund_request.validate()?;
+
+    let fingerprint =
+        request_fingerprint(&format!("POST /transactions/{id}/refund"), &refund_request)?;
+
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let outcome = Db::refund_transaction(
+                    conn,
+                    id,
+                    TransactionKind::Refund,
+                    Some(username),
+                    refund_request.amount,
+                    &AuditSource {
+                        actor: Some(username),
+                        context: &audit_context,
+                    },
+                )
+                .await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+///Reverses a transfer on behalf of support staff. Returns the id of the reversal
+#[utoipa::path(
+    post,
+    path = "/transactions/{id}/reversal",
+    tag = "Transactions",
+    request_body = RefundRequest,
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Id of the transfer to reverse"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of reversing again")
+    ),
+    responses(
+        (status = 200, description = "Reversal successfully executed", body = Uuid),
+        (status = 402, description = "Insufficient balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "User is not an admin"),
+        (status = 404, description = "Transaction not found"),
+        (status = 409, description = "The transaction is not a transfer or the amount exceeds what is left to refund"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn reverse_transaction(
+    State(db): State<Db>,
+    RoleInfo { username, .. }: RoleInfo<AdminRole>,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
+    Path(id): Path<Uuid>,
+    Json(refund_request): Json<RefundRequest>,
+) -> AppResult<impl IntoResponse> {
+    refund_request.validate()?;
+
+    let fingerprint = request_fingerprint(
+        &format!("POST /transactions/{id}/reversal"),
+        &refund_request,
+    )?;
+
+    run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, admin| {
+            Box::pin(async move {
+                let outcome = Db::refund_transaction(
+                    conn,
+                    id,
+                    TransactionKind::Reversal,
+                    None,
+                    refund_request.amount,
+                    &AuditSource {
+                        actor: Some(admin),
+                        context: &audit_context,
+                    },
+                )
+                .await?;
+
+                Ok(outcome.into_response_parts())
+            })
+        },
+    )
+    .await
+}
+
+///Executes many transfers from the logged in user at once.
+///In atomic mode either every transfer is executed or none, in best effort mode the failing transfers are skipped
+#[utoipa::path(
+    post,
+    path = "/transactions/batch",
+    tag = "Transactions",
+    request_body = BatchRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of moving money again"),
+        ("X-TOTP-Code" = Option<String>, Header, description = "Fresh code of the authenticator app, required above the step-up threshold of users with two-factor authentication")
+    ),
+    responses(
+        (status = 200, description = "Batch executed, failed items are reported in best effort mode", body = BatchResponse),
+        (status = 409, description = "Atomic batch aborted because of the reported items, nothing was executed", body = BatchResponse),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "A fresh TOTP code is required for this amount, a transfer limit would be exceeded or a risk rule refused or held a transfer"),
+        (status = 422, description = "Invalid batch or Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_batch(
+    State(db): State<Db>,
+    State(risk_engine): State<RiskEngine>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    StepUpCode(step_up_code): StepUpCode,
+    audit_context: AuditContext,
+    Json(batch_request): Json<BatchRequest>,
+) -> AppResult<impl IntoResponse> {
+    batch_request.validate()?;
+
+    let fingerprint = request_fingerprint("POST /transactions/batch", &batch_request)?;
+
+    let step_up_db = db.clone();
+    let mut response = run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                // The whole batch counts, so splitting a transfer doesn't avoid the step-up
+                let total = batch_request
+                    .transfers
+                    .iter()
+                    .map(|transfer| transfer.amount as i64)
+                    .sum();
+                step_up_db
+                    .require_step_up(conn, username, total, step_up_code.as_deref())
+                    .await?;
+
+                let batch_response = Db::process_batch(
+                    conn,
+                    username,
+                    &batch_request.transfers,
+                    batch_request.mode,
+                    &risk_engine,
+                    &AuditSource {
+                        actor: Some(username),
+                        context: &audit_context,
+                    },
+                )
+                .await?;
+
+                let status = match batch_response.executed {
+                    true => http::StatusCode::OK,
+                    false => http::StatusCode::CONFLICT,
+                };
+
+                Ok((
+                    status,
+                    serde_json::to_string(&batch_response).map_err(anyhow::Error::from)?,
+                ))
+            })
+        },
+    )
+    .await?;
+
+    // Stored responses are replayed as plain text, only the batch outcomes carry JSON
+    if matches!(
+        response.status(),
+        http::StatusCode::OK | http::StatusCode::CONFLICT
+    ) {
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
     }
 
-    Ok(().into_response())
+    Ok(response)
 }
 
 #[utoipa::path(
@@ -72,7 +391,8 @@ async fn create_transaction(
         (status = 500, description = "Internal Server Error"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn get_transaction_by_id(
@@ -93,25 +413,89 @@ async fn get_transaction_by_id(
     Ok(Json(transaction).into_response())
 }
 
-///Transactions list belonging to a User
+///Transactions list belonging to a User, one page at a time.
+///Pass the `next_cursor` of a page as `cursor` with the same filters and order to get the next one
 #[utoipa::path(
     get,
     path = "/transactions",
     tag = "Transactions",
+    params(TransactionListQuery),
     responses(
-        (status = 200, description = "Transacions list  successfully retreived", body = Vec<Transaction>),
+        (status = 200, description = "Transacions list  successfully retreived", body = TransactionPage),
         (status = 401, description = "Incorrect Credentials"),
+        (status = 422, description = "Invalid query parameters or cursor"),
         (status = 500, description = "Internal Server Error"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn transactions_list(
     State(db): State<Db>,
     UserInfo { username }: UserInfo,
+    Query(query): Query<TransactionListQuery>,
 ) -> AppResult<impl IntoResponse> {
-    Ok(Json(db.get_transactions_list(&username).await?).into_response())
+    query.validate()?;
+
+    let after = match query.cursor.as_deref().map(decode_cursor) {
+        None => None,
+        Some(Some(position)) => Some(position),
+        Some(None) => {
+            return Ok((http::StatusCode::UNPROCESSABLE_ENTITY, "Invalid cursor").into_response())
+        }
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    // One extra row tells whether there is a next page
+    let mut items = db
+        .get_transactions_list(&username, &query, after, limit + 1)
+        .await?;
+
+    let next_cursor = match items.len() as i64 > limit {
+        true => {
+            items.truncate(limit as usize);
+            items.last().map(encode_cursor)
+        }
+        false => None,
+    };
+
+    Ok(Json(TransactionPage { items, next_cursor }).into_response())
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// Cursors are the hex encoded `(created_at, transaction_id)` of the last transaction of a page
+fn encode_cursor(transaction: &Transaction) -> String {
+    hex::encode(format!(
+        "{}|{}",
+        transaction
+            .created_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        transaction.transaction_id
+    ))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(DateTime<Utc>, Uuid)> {
+    let decoded = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let (created_at, transaction_id) = decoded.split_once('|')?;
+
+    Some((
+        DateTime::parse_from_rfc3339(created_at).ok()?.to_utc(),
+        transaction_id.parse().ok()?,
+    ))
+}
+
+/// Result of [`Db::process_transaction`]
+pub(crate) enum TransferOutcome {
+    Executed(Uuid),
+    InsufficientBalance,
+    /// A risk rule wants the transfer reviewed before it is made. Only `POST /transactions`
+    /// holds it for a review, other callers refuse the transfer
+    ReviewRequired {
+        rule: String,
+        reason: String,
+    },
 }
 
 #[derive(Serialize, ToSchema, Deserialize)]
@@ -120,10 +504,184 @@ pub(crate) struct Transaction {
     pub from_user: String,
     pub to_user: String,
     pub amount: i32,
+    /// Fee the sender paid on top of the amount, credited to the platform
+    pub fee: i32,
+    pub kind: TransactionKind,
+    /// The transfer a refund or reversal gives money back for
+    pub original_transaction_id: Option<Uuid>,
     pub created_at: chrono::DateTime<Utc>,
 }
 
-#[derive(Deserialize, ToSchema, Validate)]
+/// Stored as the `transaction_kind` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "transaction_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TransactionKind {
+    Transfer,
+    /// Money given back by the recipient
+    Refund,
+    /// Money given back by an admin
+    Reversal,
+}
+
+impl TransactionKind {
+    fn entry_kind(self) -> EntryKind {
+        match self {
+            TransactionKind::Transfer => EntryKind::Transfer,
+            TransactionKind::Refund => EntryKind::Refund,
+            TransactionKind::Reversal => EntryKind::Reversal,
+        }
+    }
+
+    fn event_type(self) -> EventType {
+        match self {
+            TransactionKind::Transfer => EventType::TransactionCreated,
+            TransactionKind::Refund => EventType::RefundCreated,
+            TransactionKind::Reversal => EventType::ReversalCreated,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct BatchRequest {
+    #[validate(length(min = 1, max = 1000), nested)]
+    pub transfers: Vec<TransactionRequest>,
+    #[serde(default)]
+    pub mode: BatchMode,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchMode {
+    /// All transfers are executed or none
+    #[default]
+    Atomic,
+    /// Failing transfers are skipped and reported
+    BestEffort,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct BatchResponse {
+    /// False if an atomic batch was aborted
+    pub executed: bool,
+    pub items: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub(crate) struct BatchItemResult {
+    /// Position of the transfer in the request
+    pub index: usize,
+    pub transaction_id: Option<Uuid>,
+    pub error: Option<BatchItemError>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchItemError {
+    InsufficientBalance,
+    UnknownRecipient,
+    /// The recipient's account is closed or frozen for incoming transfers
+    RecipientLocked,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct RefundRequest {
+    /// Everything that was not refunded yet when left out
+    #[validate(range(min = 1))]
+    pub amount: Option<i32>,
+}
+
+pub(crate) enum RefundOutcome {
+    Refunded(Uuid),
+    NotFound,
+    NotRecipient,
+    /// Refunds and reversals can't be refunded themselves
+    NotRefundable,
+    ExceedsRefundable {
+        refundable: i32,
+    },
+    InsufficientBalance,
+}
+
+impl RefundOutcome {
+    fn into_response_parts(self) -> (http::StatusCode, String) {
+        match self {
+            RefundOutcome::Refunded(transaction_id) => {
+                (http::StatusCode::OK, transaction_id.to_string())
+            }
+            RefundOutcome::NotFound => (
+                http::StatusCode::NOT_FOUND,
+                "Transaction not found".to_string(),
+            ),
+            RefundOutcome::NotRecipient => (
+                http::StatusCode::FORBIDDEN,
+                "Only the recipient of a transaction can refund it".to_string(),
+            ),
+            RefundOutcome::NotRefundable => (
+                http::StatusCode::CONFLICT,
+                "Only transfers can be refunded".to_string(),
+            ),
+            RefundOutcome::ExceedsRefundable { refundable } => (
+                http::StatusCode::CONFLICT,
+                format!("Only {refundable} of the transaction can still be refunded"),
+            ),
+            RefundOutcome::InsufficientBalance => (
+                http::StatusCode::PAYMENT_REQUIRED,
+                "Insufficent balance in recipient account".to_string(),
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct TransactionListQuery {
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Page size, 50 by default
+    #[validate(range(min = 1, max = 200))]
+    pub limit: Option<i64>,
+    /// Only sent or only received transactions
+    pub direction: Option<Direction>,
+    /// Only transactions with this user
+    #[validate(length(min = 4, max = 16))]
+    pub counterparty: Option<String>,
+    #[validate(range(min = 1))]
+    pub min_amount: Option<i32>,
+    #[validate(range(min = 1))]
+    pub max_amount: Option<i32>,
+    /// Only transactions created at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only transactions created before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Newest first by default
+    pub order: Option<SortOrder>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+/// Transactions created at the same time are ordered by id, so pages never overlap
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TransactionPage {
+    pub items: Vec<Transaction>,
+    /// Missing on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
 pub(crate) struct TransactionRequest {
     #[validate(length(min = 4, max = 16))]
     pub to_user: String,
@@ -1,30 +1,71 @@
-use crate::db::Db;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
 
-use super::HashedUserCredentials;
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+};
+
+use super::{HashedUserCredentials, IssuedTokens, RefreshOutcome, Role};
 
 impl Db {
     pub async fn signup_user(
         &self,
         hashed_user_credentials: HashedUserCredentials,
+        audit_context: &AuditContext,
     ) -> sqlx::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
         sqlx::query!(
             "INSERT INTO user_credentials(username, password) VALUES($1, $2)",
             hashed_user_credentials.username,
             hashed_user_credentials.hashed_password
         )
-        .execute(&self.pool)
+        .execute(&mut *transaction)
+        .await?;
+
+        Self::create_ledger_account(&mut transaction, &hashed_user_credentials.username).await?;
+
+        Self::record_audit(
+            &mut transaction,
+            audit_context,
+            AuditEntry {
+                actor: Some(&hashed_user_credentials.username),
+                action: AuditAction::Signup,
+                target: Some(&hashed_user_credentials.username),
+                before: None,
+                after: None,
+            },
+        )
         .await?;
+
+        transaction.commit().await?;
         Ok(())
     }
 
-    pub async fn get_hashed_password_of_user(&self, username: &str) -> sqlx::Result<String> {
+    pub async fn get_hashed_password_of_user(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Option<(String, Role)>> {
         sqlx::query!(
-            "SELECT password FROM user_credentials WHERE username = $1",
+            r#"SELECT password, role AS "role: Role" FROM user_credentials WHERE username = $1"#,
             username
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await
-        .map(|record| record.password)
+        .map(|record| record.map(|record| (record.password, record.role)))
+    }
+
+    /// Gives the admin role to the listed users, used to bootstrap the first admins
+    pub async fn promote_admins(&self, usernames: &[String]) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE user_credentials SET role = 'admin' WHERE username = ANY($1)",
+            usernames
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
     }
 
     pub async fn check_if_username_exists(&self, username: &str) -> sqlx::Result<bool> {
@@ -42,4 +83,285 @@ impl Db {
 
         Ok(true)
     }
+
+    pub async fn create_refresh_token(
+        &self,
+        username: &str,
+        family_id: Uuid,
+        issued: &IssuedTokens,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO refresh_tokens(family_id, username, token_hash, access_jti, access_expires_at, expires_at)
+            VALUES($1, $2, $3, $4, $5, $6)",
+            family_id,
+            username,
+            issued.refresh_token_hash,
+            issued.access_jti,
+            issued.access_expires_at,
+            issued.expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the refresh token as used and stores its successor in the same family.
+    /// A token that was used before revokes its whole family instead
+    pub async fn rotate_refresh_token(
+        &self,
+        token_hash: &str,
+        issued: &IssuedTokens,
+    ) -> sqlx::Result<RefreshOutcome> {
+        let mut tx = self.pool.begin().await?;
+
+        let Some(token) = sqlx::query!(
+            r#"SELECT refresh_tokens.family_id, refresh_tokens.username, refresh_tokens.used_at,
+            refresh_tokens.revoked_at, refresh_tokens.expires_at, user_credentials.role AS "role: Role"
+            FROM refresh_tokens JOIN user_credentials USING (username)
+            WHERE refresh_tokens.token_hash = $1 FOR UPDATE OF refresh_tokens"#,
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(RefreshOutcome::Invalid);
+        };
+
+        if token.revoked_at.is_some() || token.expires_at <= chrono::Utc::now() {
+            return Ok(RefreshOutcome::Invalid);
+        }
+
+        if token.used_at.is_some() {
+            Self::revoke_token_families(&mut tx, &[token.family_id]).await?;
+            tx.commit().await?;
+            return Ok(RefreshOutcome::Reused);
+        }
+
+        sqlx::query!(
+            "UPDATE refresh_tokens SET used_at = now() WHERE token_hash = $1",
+            token_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO refresh_tokens(family_id, username, token_hash, access_jti, access_expires_at, expires_at)
+            VALUES($1, $2, $3, $4, $5, $6)",
+            token.family_id,
+            token.username,
+            issued.refresh_token_hash,
+            issued.access_jti,
+            issued.access_expires_at,
+            issued.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(RefreshOutcome::Rotated {
+            username: token.username,
+            role: token.role,
+            family_id: token.family_id,
+        })
+    }
+
+    pub async fn revoke_token_family(&self, family_id: Uuid) -> sqlx::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::revoke_token_families(&mut tx, &[family_id]).await?;
+        tx.commit().await
+    }
+
+    pub async fn revoke_user_tokens(&self, username: &str) -> sqlx::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::revoke_user_token_families(&mut tx, username, None).await?;
+        tx.commit().await
+    }
+
+    /// Stores the new password hash and revokes every API key of the user
+    /// and every login except `keep_family`
+    pub async fn change_password(
+        &self,
+        username: &str,
+        hashed_password: &str,
+        keep_family: Option<Uuid>,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE user_credentials SET password = $2 WHERE username = $1",
+            username,
+            hashed_password
+        )
+        .execute(&mut *tx)
+        .await?;
+        Self::revoke_user_token_families(&mut tx, username, keep_family).await?;
+        Self::revoke_user_api_keys(&mut tx, username).await?;
+        Self::record_audit(
+            &mut tx,
+            audit_context,
+            AuditEntry {
+                actor: Some(username),
+                action: AuditAction::PasswordChange,
+                target: Some(username),
+                before: None,
+                after: None,
+            },
+        )
+        .await?;
+
+        tx.commit().await
+    }
+
+    /// Replaces a hash made with outdated settings, the password itself stays the same
+    pub async fn update_password_hash(
+        &self,
+        username: &str,
+        hashed_password: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE user_credentials SET password = $2 WHERE username = $1",
+            username,
+            hashed_password
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stores a reset token and invalidates the earlier ones of the user.
+    /// Returns false if the user doesn't exist
+    pub async fn create_password_reset_token(
+        &self,
+        username: &str,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> sqlx::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE password_reset_tokens SET used_at = now() WHERE username = $1 AND used_at IS NULL",
+            username
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!(
+            "INSERT INTO password_reset_tokens(token_hash, username, expires_at)
+            SELECT $1, username, $3 FROM user_credentials WHERE username = $2",
+            token_hash,
+            username,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Uses up the reset token, stores the new password hash and revokes every login and API key
+    /// of the user.
+    /// Returns the user, or None if the token is unknown, used or expired
+    pub async fn reset_password(
+        &self,
+        token_hash: &str,
+        hashed_password: &str,
+        audit_context: &AuditContext,
+    ) -> sqlx::Result<Option<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        let Some(username) = sqlx::query_scalar!(
+            "UPDATE password_reset_tokens SET used_at = now()
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > now()
+            RETURNING username",
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            "UPDATE user_credentials SET password = $2 WHERE username = $1",
+            username,
+            hashed_password
+        )
+        .execute(&mut *tx)
+        .await?;
+        Self::revoke_user_token_families(&mut tx, &username, None).await?;
+        Self::revoke_user_api_keys(&mut tx, &username).await?;
+        Self::record_audit(
+            &mut tx,
+            audit_context,
+            AuditEntry {
+                actor: Some(&username),
+                action: AuditAction::PasswordReset,
+                target: Some(&username),
+                before: None,
+                after: None,
+            },
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(username))
+    }
+
+    async fn revoke_user_token_families(
+        conn: &mut sqlx::PgConnection,
+        username: &str,
+        keep_family: Option<Uuid>,
+    ) -> sqlx::Result<()> {
+        let family_ids = sqlx::query_scalar!(
+            "SELECT DISTINCT family_id FROM refresh_tokens
+            WHERE username = $1 AND revoked_at IS NULL AND family_id IS DISTINCT FROM $2",
+            username,
+            keep_family
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Self::revoke_token_families(conn, &family_ids).await
+    }
+
+    /// Revokes the refresh tokens of the families and every access token issued with them
+    /// that has not expired yet
+    async fn revoke_token_families(
+        conn: &mut sqlx::PgConnection,
+        family_ids: &[Uuid],
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "WITH revoked AS (
+                UPDATE refresh_tokens SET revoked_at = now()
+                WHERE family_id = ANY($1) AND revoked_at IS NULL
+                RETURNING access_jti, access_expires_at
+            )
+            INSERT INTO revoked_access_tokens(jti, expires_at)
+            SELECT access_jti, access_expires_at FROM revoked WHERE access_expires_at > now()
+            ON CONFLICT DO NOTHING",
+            family_ids
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        // Expired tokens are rejected anyway
+        sqlx::query!("DELETE FROM revoked_access_tokens WHERE expires_at <= now()")
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_access_token_revoked(&self, jti: Uuid) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM revoked_access_tokens WHERE jti = $1) AS "revoked!""#,
+            jti
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
 }
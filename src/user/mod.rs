@@ -7,14 +7,25 @@ use axum::{
     routing::{get, post},
     Json, Router,
 };
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use utoipa::ToSchema;
+use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
+    audit::{AuditAction, AuditContext, AuditEntry},
+    config::config,
     db::Db,
     error::{self, AppResult},
-    utils::{generate_token, hash_password, validate_password, UserInfo},
+    login_attempt::{login_blocked_until, LoginClient, LoginOutcome},
+    notifier::{Notification, Notifications},
+    totp::{ChallengeOutcome, SecondFactor},
+    utils::{
+        dummy_password_hash, generate_token, hash_password, password_needs_rehash,
+        validate_password, SessionInfo, UserInfo,
+    },
 };
 use validator::Validate;
 
@@ -22,7 +33,14 @@ pub(super) fn get_router(app_state: AppState) -> Router {
     Router::new()
         .route("/signup", post(signup))
         .route("/login", post(login))
+        .route("/login/2fa", post(complete_login))
         .route("/whoami", get(whoami))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+        .route("/logout-all", post(logout_all))
+        .route("/password", post(change_password))
+        .route("/password/reset-request", post(request_password_reset))
+        .route("/password/reset", post(reset_password))
         .with_state(app_state)
 }
 
@@ -39,6 +57,7 @@ pub(super) fn get_router(app_state: AppState) -> Router {
 )]
 async fn signup(
     State(db): State<Db>,
+    audit_context: AuditContext,
     Json(user_credentials): Json<UserCredentials>,
 ) -> AppResult<impl IntoResponse> {
     user_credentials.validate()?;
@@ -50,7 +69,8 @@ async fn signup(
         return Ok((http::StatusCode::CONFLICT).into_response());
     }
 
-    db.signup_user(user_credentials.try_into()?).await?;
+    db.signup_user(user_credentials.try_into()?, &audit_context)
+        .await?;
     Ok((http::StatusCode::CREATED).into_response())
 }
 
@@ -60,24 +80,389 @@ async fn signup(
     tag = "User Management",
     request_body =  UserCredentials,
     responses(
-        (status = 200, description = "User succesfully logged in"),
+        (status = 200, description = "User succesfully logged in", body = TokenPair),
+        (status = 202, description = "Password accepted, the login has to be completed with a second factor at /users/login/2fa", body = LoginChallenge),
         (status = 401, description = "Incorrect Credentials"),
+        (status = 429, description = "Too many failed logins for the username or from the address, retry after the Retry-After header"),
         (status = 500, description = "Internal Server Error"),
     ),
 )]
 async fn login(
     State(db): State<Db>,
+    client: LoginClient,
+    audit_context: AuditContext,
     Json(user_credentials): Json<UserCredentials>,
 ) -> AppResult<impl IntoResponse> {
-    let hashed_password = db
+    // Held until the attempt is recorded, so parallel guesses can't all pass the same check
+    let mut transaction = db.begin_login(&user_credentials.username).await?;
+
+    let username_failures =
+        Db::get_username_login_failures(&mut transaction, &user_credentials.username).await?;
+    let ip_failures = Db::get_ip_login_failures(&mut transaction, &client.ip_address).await?;
+    if let Some(retry_at) = login_blocked_until(&username_failures, &ip_failures) {
+        Db::record_login_attempt(
+            &mut transaction,
+            &user_credentials.username,
+            &client,
+            LoginOutcome::Throttled,
+        )
+        .await?;
+        transaction.commit().await?;
+        return Err(error::AppError::TooManyLoginAttempts { retry_at });
+    }
+
+    let stored_credentials = db
         .get_hashed_password_of_user(&user_credentials.username)
         .await?;
+    let password_valid = match &stored_credentials {
+        Some((hashed_password, _)) => {
+            validate_password(&user_credentials.password, hashed_password)?
+        }
+        None => {
+            validate_password(&user_credentials.password, dummy_password_hash())?;
+            false
+        }
+    };
+
+    let Some((hashed_password, role)) = stored_credentials.filter(|_| password_valid) else {
+        Db::record_login_attempt(
+            &mut transaction,
+            &user_credentials.username,
+            &client,
+            LoginOutcome::Failed,
+        )
+        .await?;
+        Db::record_audit(
+            &mut transaction,
+            &audit_context,
+            AuditEntry {
+                actor: Some(&user_credentials.username),
+                action: AuditAction::LoginFailed,
+                target: Some(&user_credentials.username),
+                before: None,
+                after: None,
+            },
+        )
+        .await?;
+        transaction.commit().await?;
+        return Err(error::AppError::Unauthorized);
+    };
+    Db::record_login_attempt(
+        &mut transaction,
+        &user_credentials.username,
+        &client,
+        LoginOutcome::Succeeded,
+    )
+    .await?;
+    Db::record_audit(
+        &mut transaction,
+        &audit_context,
+        AuditEntry {
+            actor: Some(&user_credentials.username),
+            action: AuditAction::LoginSucceeded,
+            target: Some(&user_credentials.username),
+            before: None,
+            after: None,
+        },
+    )
+    .await?;
+    transaction.commit().await?;
+
+    if password_needs_rehash(&hashed_password)? {
+        db.update_password_hash(
+            &user_credentials.username,
+            &hash_password(&user_credentials.password)?,
+        )
+        .await?;
+    }
+
+    if db.is_totp_enabled(&user_credentials.username).await? {
+        let challenge_token = random_token();
+        db.create_login_challenge(
+            &user_credentials.username,
+            &hash_token(&challenge_token),
+            Utc::now() + Duration::seconds(config().LOGIN_CHALLENGE_TTL_SECONDS),
+        )
+        .await?;
+
+        return Ok((
+            http::StatusCode::ACCEPTED,
+            Json(LoginChallenge {
+                challenge_token,
+                expires_in: config().LOGIN_CHALLENGE_TTL_SECONDS,
+            }),
+        )
+            .into_response());
+    }
+
+    Ok(Json(start_session(&db, user_credentials.username, role).await?).into_response())
+}
+
+///Completes the login of a User with two-factor authentication using a code of the
+///authenticator app or one of the recovery codes
+#[utoipa::path(
+    post,
+    path = "/users/login/2fa",
+    tag = "User Management",
+    request_body = LoginChallengeRequest,
+    responses(
+        (status = 200, description = "User succesfully logged in", body = TokenPair),
+        (status = 401, description = "Invalid, expired or exhausted challenge, or invalid code"),
+        (status = 429, description = "Too many invalid codes, try again later"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+)]
+async fn complete_login(
+    State(db): State<Db>,
+    Json(challenge_request): Json<LoginChallengeRequest>,
+) -> AppResult<impl IntoResponse> {
+    match db
+        .complete_login_challenge(
+            &hash_token(&challenge_request.challenge_token),
+            &challenge_request.second_factor,
+        )
+        .await?
+    {
+        ChallengeOutcome::Completed { username, role } => {
+            Ok(Json(start_session(&db, username, role).await?).into_response())
+        }
+        ChallengeOutcome::InvalidCode => {
+            Ok((http::StatusCode::UNAUTHORIZED, "Invalid code").into_response())
+        }
+        ChallengeOutcome::Invalid => Ok((
+            http::StatusCode::UNAUTHORIZED,
+            "Invalid or expired challenge, log in again",
+        )
+            .into_response()),
+    }
+}
+
+/// Issues the tokens of a new login
+async fn start_session(db: &Db, username: String, role: Role) -> AppResult<TokenPair> {
+    // Every login starts a new refresh token family
+    let family_id = Uuid::new_v4();
+    let issued = IssuedTokens::generate();
+    db.create_refresh_token(&username, family_id, &issued)
+        .await?;
+
+    issued.into_token_pair(username, role, family_id)
+}
+
+///Exchanges a refresh token for a new access token and refresh token.
+///Every refresh token can only be used once, using it again revokes all tokens of the login
+#[utoipa::path(
+    post,
+    path = "/users/refresh",
+    tag = "User Management",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Tokens successfully refreshed", body = TokenPair),
+        (status = 401, description = "Invalid, expired or reused refresh token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+)]
+async fn refresh(
+    State(db): State<Db>,
+    Json(refresh_request): Json<RefreshRequest>,
+) -> AppResult<impl IntoResponse> {
+    let issued = IssuedTokens::generate();
+
+    match db
+        .rotate_refresh_token(&hash_token(&refresh_request.refresh_token), &issued)
+        .await?
+    {
+        RefreshOutcome::Rotated {
+            username,
+            role,
+            family_id,
+        } => Ok(Json(issued.into_token_pair(username, role, family_id)?).into_response()),
+        RefreshOutcome::Invalid => Ok((
+            http::StatusCode::UNAUTHORIZED,
+            "Invalid or expired refresh token",
+        )
+            .into_response()),
+        RefreshOutcome::Reused => Ok((
+            http::StatusCode::UNAUTHORIZED,
+            "Refresh token was already used, all tokens of this login were revoked",
+        )
+            .into_response()),
+    }
+}
+
+///Revokes the access token and refresh tokens of the current login
+#[utoipa::path(
+    post,
+    path = "/users/logout",
+    tag = "User Management",
+    responses(
+        (status = 200, description = "User succesfully logged out"),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn logout(
+    State(db): State<Db>,
+    SessionInfo { family_id, .. }: SessionInfo,
+) -> AppResult<impl IntoResponse> {
+    db.revoke_token_family(family_id).await?;
+
+    Ok(http::StatusCode::OK)
+}
+
+///Revokes the access tokens and refresh tokens of every login of the User
+#[utoipa::path(
+    post,
+    path = "/users/logout-all",
+    tag = "User Management",
+    responses(
+        (status = 200, description = "User succesfully logged out everywhere"),
+        (status = 401, description = "Invalid bearer token"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn logout_all(
+    State(db): State<Db>,
+    SessionInfo { username, .. }: SessionInfo,
+) -> AppResult<impl IntoResponse> {
+    db.revoke_user_tokens(&username).await?;
+
+    Ok(http::StatusCode::OK)
+}
 
-    if !validate_password(&user_credentials.password, &hashed_password)? {
+///Changes the password of the logged in User. Every other login of the User is logged out
+///and every API key of the User is revoked
+#[utoipa::path(
+    post,
+    path = "/users/password",
+    tag = "User Management",
+    request_body = PasswordChangeRequest,
+    responses(
+        (status = 200, description = "Password succesfully changed"),
+        (status = 401, description = "Invalid bearer token or incorrect current password"),
+        (status = 422, description = "Invalid new password"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = [])
+    )
+)]
+async fn change_password(
+    State(db): State<Db>,
+    State(notifications): State<Notifications>,
+    SessionInfo {
+        username,
+        family_id,
+    }: SessionInfo,
+    audit_context: AuditContext,
+    Json(password_change_request): Json<PasswordChangeRequest>,
+) -> AppResult<impl IntoResponse> {
+    password_change_request.validate()?;
+
+    let (hashed_password, _) = db
+        .get_hashed_password_of_user(&username)
+        .await?
+        .ok_or(error::AppError::Unauthorized)?;
+    if !validate_password(&password_change_request.current_password, &hashed_password)? {
         return Err(error::AppError::Unauthorized);
     }
 
-    Ok(generate_token(3600, user_credentials.username)?)
+    db.change_password(
+        &username,
+        &hash_password(&password_change_request.new_password)?,
+        Some(family_id),
+        &audit_context,
+    )
+    .await?;
+    notifications
+        .send(&username, Notification::PasswordChanged)
+        .await;
+
+    Ok(http::StatusCode::OK)
+}
+
+///Sends a single use password reset token to the User through the configured notifier.
+///The response is the same whether the user exists or not
+#[utoipa::path(
+    post,
+    path = "/users/password/reset-request",
+    tag = "User Management",
+    request_body = PasswordResetRequest,
+    responses(
+        (status = 202, description = "Reset token sent if the user exists"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+)]
+async fn request_password_reset(
+    State(db): State<Db>,
+    State(notifications): State<Notifications>,
+    Json(reset_request): Json<PasswordResetRequest>,
+) -> AppResult<impl IntoResponse> {
+    let token = random_token();
+    let expires_at = Utc::now() + Duration::seconds(config().PASSWORD_RESET_TTL_SECONDS);
+
+    if db
+        .create_password_reset_token(&reset_request.username, &hash_token(&token), expires_at)
+        .await?
+    {
+        notifications
+            .send(
+                &reset_request.username,
+                Notification::PasswordReset { token, expires_at },
+            )
+            .await;
+    }
+
+    Ok(http::StatusCode::ACCEPTED)
+}
+
+///Sets a new password with a reset token. Every login of the User is logged out
+///and every API key of the User is revoked
+#[utoipa::path(
+    post,
+    path = "/users/password/reset",
+    tag = "User Management",
+    request_body = PasswordResetConfirmation,
+    responses(
+        (status = 200, description = "Password succesfully reset"),
+        (status = 401, description = "Invalid, used or expired reset token"),
+        (status = 422, description = "Invalid new password"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+)]
+async fn reset_password(
+    State(db): State<Db>,
+    State(notifications): State<Notifications>,
+    audit_context: AuditContext,
+    Json(reset_confirmation): Json<PasswordResetConfirmation>,
+) -> AppResult<impl IntoResponse> {
+    reset_confirmation.validate()?;
+
+    let Some(username) = db
+        .reset_password(
+            &hash_token(&reset_confirmation.token),
+            &hash_password(&reset_confirmation.new_password)?,
+            &audit_context,
+        )
+        .await?
+    else {
+        return Ok((
+            http::StatusCode::UNAUTHORIZED,
+            "Invalid or expired reset token",
+        )
+            .into_response());
+    };
+
+    notifications
+        .send(&username, Notification::PasswordChanged)
+        .await;
+
+    Ok(http::StatusCode::OK.into_response())
 }
 
 #[utoipa::path(
@@ -89,12 +474,37 @@ async fn login(
         (status = 401, description = "Invalid bearer token"),
     ),
     security(
-        ("USER_JWT" = [])
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
     )
 )]
 async fn whoami(UserInfo { username }: UserInfo) -> AppResult<impl IntoResponse> {
     Ok(username.into_response())
 }
+/// Stored as the `user_role` database enum and embedded in access tokens
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    User,
+    /// Looks up users and transactions and freezes accounts
+    Support,
+    /// Everything support can do, plus roles, reversals and balance adjustments
+    Admin,
+    /// Read-only access to users and transactions
+    Auditor,
+}
+
+/// Stored as the `account_type` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "account_type", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccountType {
+    Personal,
+    /// A business accepting payments through checkout sessions
+    Merchant,
+}
+
 #[derive(Serialize, Deserialize, Validate, ToSchema, Debug)]
 pub(crate) struct UserCredentials {
     #[validate(length(min = 4, max = 16))]
@@ -103,6 +513,124 @@ pub(crate) struct UserCredentials {
     password: String,
 }
 
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TokenPair {
+    /// Short lived JWT for the Authorization header
+    pub access_token: String,
+    /// Single use token for `/users/refresh`
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Validate, ToSchema)]
+pub(crate) struct PasswordChangeRequest {
+    current_password: String,
+    #[validate(length(min = 8, max = 100))]
+    new_password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct PasswordResetRequest {
+    username: String,
+}
+
+#[derive(Deserialize, Validate, ToSchema)]
+pub(crate) struct PasswordResetConfirmation {
+    /// Token sent by the reset request
+    token: String,
+    #[validate(length(min = 8, max = 100))]
+    new_password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct LoginChallenge {
+    /// Completes the login at `/users/login/2fa` together with a code
+    pub challenge_token: String,
+    /// Seconds until the challenge expires
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct LoginChallengeRequest {
+    pub challenge_token: String,
+    #[serde(flatten)]
+    pub second_factor: SecondFactor,
+}
+
+/// A freshly generated token pair before the access token is signed
+pub(crate) struct IssuedTokens {
+    refresh_token: String,
+    pub refresh_token_hash: String,
+    pub access_jti: Uuid,
+    pub access_expires_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl IssuedTokens {
+    fn generate() -> Self {
+        let refresh_token = random_token();
+
+        IssuedTokens {
+            refresh_token_hash: hash_token(&refresh_token),
+            refresh_token,
+            access_jti: Uuid::new_v4(),
+            access_expires_at: Utc::now() + Duration::seconds(config().ACCESS_TOKEN_TTL_SECONDS),
+            expires_at: Utc::now() + Duration::seconds(config().REFRESH_TOKEN_TTL_SECONDS),
+        }
+    }
+
+    fn into_token_pair(
+        self,
+        username: String,
+        role: Role,
+        family_id: Uuid,
+    ) -> AppResult<TokenPair> {
+        Ok(TokenPair {
+            access_token: generate_token(
+                config().ACCESS_TOKEN_TTL_SECONDS,
+                username,
+                role,
+                self.access_jti,
+                family_id,
+            )?,
+            refresh_token: self.refresh_token,
+            token_type: "Bearer",
+            expires_in: config().ACCESS_TOKEN_TTL_SECONDS,
+        })
+    }
+}
+
+pub(crate) enum RefreshOutcome {
+    /// The role is read again, so role changes apply from the next refresh on
+    Rotated {
+        username: String,
+        role: Role,
+        family_id: Uuid,
+    },
+    /// Unknown, expired or revoked token
+    Invalid,
+    /// The token was used before, so its family was revoked
+    Reused,
+}
+
+fn random_token() -> String {
+    // Two v4 uuids give 244 random bits from the OS random number generator
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Refresh tokens, login challenges and reset tokens are stored as SHA-256 digests so a database
+/// leak doesn't expose usable tokens
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 pub(crate) struct HashedUserCredentials {
     username: String,
     hashed_password: String,
@@ -1,11 +1,13 @@
+use std::{marker::PhantomData, sync::OnceLock};
+
 use anyhow::anyhow;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
 use axum::{
     async_trait,
-    extract::FromRequestParts,
+    extract::{FromRef, FromRequestParts},
     http::{request::Parts, StatusCode},
     response::{IntoResponse, Response},
     RequestPartsExt,
@@ -20,13 +22,29 @@ use jsonwebtoken::{
     EncodingKey, Header, TokenData,
 };
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    api_key::{hash_api_key, API_KEY_HEADER},
+    config::config,
+    db::Db,
+    error::AppError,
+    user::Role,
+};
+
+/// Settings new passwords are hashed with. Stored hashes made with different settings are
+/// replaced on the next successful login, see [`password_needs_rehash`]
+const PASSWORD_ALGORITHM: Algorithm = Algorithm::Argon2id;
+const PASSWORD_VERSION: Version = Version::V0x13;
 
-use crate::{config::config, error::AppError};
+fn password_hasher() -> Argon2<'static> {
+    Argon2::new(PASSWORD_ALGORITHM, PASSWORD_VERSION, Params::default())
+}
 
 pub(crate) fn hash_password(password: &str) -> anyhow::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
 
-    let argon2 = Argon2::default();
+    let argon2 = password_hasher();
 
     Ok(argon2
         .hash_password(password.as_bytes(), &salt)
@@ -36,15 +54,45 @@ pub(crate) fn hash_password(password: &str) -> anyhow::Result<String> {
 
 pub(crate) fn validate_password(password: &str, hash: &str) -> anyhow::Result<bool> {
     let parsed_hash = PasswordHash::new(hash).map_err(|e| anyhow!("{e}"))?;
-    Ok(Argon2::default()
+    Ok(password_hasher()
         .verify_password(password.as_bytes(), &parsed_hash)
         .is_ok())
 }
 
+/// Hash of a random password that unknown usernames are checked against, so they take as long
+/// to reject as wrong passwords
+pub(crate) fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    DUMMY_HASH.get_or_init(|| {
+        hash_password(&Uuid::new_v4().to_string()).expect("Unable to hash the dummy password")
+    })
+}
+
+/// Whether the stored hash was made with other settings than [`hash_password`] uses now
+pub(crate) fn password_needs_rehash(hash: &str) -> anyhow::Result<bool> {
+    let parsed_hash = PasswordHash::new(hash).map_err(|e| anyhow!("{e}"))?;
+    let stored_params = Params::try_from(&parsed_hash).map_err(|e| anyhow!("{e}"))?;
+
+    let argon2 = password_hasher();
+    let params = argon2.params();
+
+    Ok(parsed_hash.algorithm != PASSWORD_ALGORITHM.ident()
+        || parsed_hash.version != Some(PASSWORD_VERSION.into())
+        || stored_params.m_cost() != params.m_cost()
+        || stored_params.t_cost() != params.t_cost()
+        || stored_params.p_cost() != params.p_cost())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct CustomClaims {
     sub: String,
     exp: i64,
+    role: Role,
+    /// Id of the token, revoked tokens are looked up by it
+    jti: Uuid,
+    /// Refresh token family the token was issued for
+    fid: Uuid,
 }
 
 fn encode_jwt(secret: &[u8], claims: &CustomClaims) -> Result<String, Error> {
@@ -59,53 +107,186 @@ fn decode_jwt(secret: &[u8], token: &str) -> Result<TokenData<CustomClaims>, Err
     jsonwebtoken::decode::<CustomClaims>(token, &key, &validation)
 }
 
-pub(crate) fn generate_token(expiry_time: i64, user_id: String) -> Result<String, Error> {
+pub(crate) fn generate_token(
+    expiry_time: i64,
+    user_id: String,
+    role: Role,
+    jti: Uuid,
+    family_id: Uuid,
+) -> Result<String, Error> {
     let secret_key = config().JWT_SECRET.as_bytes();
     let claims = CustomClaims {
         sub: user_id,
         exp: (Utc::now() + Duration::seconds(expiry_time)).timestamp(),
+        role,
+        jti,
+        fid: family_id,
     };
     encode_jwt(secret_key, &claims)
 }
 
 //validate the token and also check if it is expired or not
-async fn validate_token(token: &str) -> Result<String, Error> {
+async fn validate_token(token: &str) -> Result<CustomClaims, Error> {
     let secret_key = config().JWT_SECRET.as_bytes();
     let token_data = decode_jwt(secret_key, token);
-    let username = match token_data {
+    let claims = match token_data {
         Ok(data) => {
             if data.claims.exp < (Utc::now()).timestamp() {
                 return Err(Error::from(ErrorKind::ExpiredSignature));
             }
-            data.claims.sub
+            data.claims
         }
         Err(e) => {
             return Err(e);
         }
     };
 
-    Ok(username)
+    Ok(claims)
 }
 
+/// Validates the bearer token of the request and rejects tokens revoked by a logout
+async fn authenticate<S>(parts: &mut Parts, state: &S) -> Result<CustomClaims, Response>
+where
+    Db: FromRef<S>,
+{
+    // Extract the token from the authorization header
+    let TypedHeader(Authorization(bearer)) = parts
+        .extract::<TypedHeader<Authorization<Bearer>>>()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+
+    let claims = validate_token(bearer.token())
+        .await
+        .map_err(|e| AppError::from(e).into_response())?;
+
+    let db = Db::from_ref(state);
+    if db
+        .is_access_token_revoked(claims.jti)
+        .await
+        .map_err(|e| AppError::from(e).into_response())?
+    {
+        return Err(AppError::Unauthorized.into_response());
+    }
+
+    Ok(claims)
+}
+
+/// A user authenticated by an access token or by an API key in the `X-API-Key` header
 pub(crate) struct UserInfo {
     pub username: String,
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for UserInfo {
+impl<S: Send + Sync> FromRequestParts<S> for UserInfo
+where
+    Db: FromRef<S>,
+{
     type Rejection = Response;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(api_key) = parts.headers.get(API_KEY_HEADER) {
+            let api_key = api_key
+                .to_str()
+                .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+
+            let db = Db::from_ref(state);
+            return match db
+                .authenticate_api_key(&hash_api_key(api_key))
+                .await
+                .map_err(|e| AppError::from(e).into_response())?
+            {
+                Some(username) => Ok(UserInfo { username }),
+                None => Err(AppError::Unauthorized.into_response()),
+            };
+        }
+
+        let claims = authenticate(parts, state).await?;
 
-        let username = validate_token(bearer.token())
-            .await
-            .map_err(|e| AppError::from(e).into_response())?;
+        Ok(UserInfo {
+            username: claims.sub,
+        })
+    }
+}
+
+/// A logged in user together with the login session the access token belongs to
+pub(crate) struct SessionInfo {
+    pub username: String,
+    pub family_id: Uuid,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SessionInfo
+where
+    Db: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = authenticate(parts, state).await?;
+
+        Ok(SessionInfo {
+            username: claims.sub,
+            family_id: claims.fid,
+        })
+    }
+}
+
+/// Roles allowed to use an endpoint guarded by [`RoleInfo`]
+pub(crate) trait RequiredRole {
+    const ROLES: &'static [Role];
+}
+
+/// Changing roles and balances
+pub(crate) struct AdminRole;
+
+impl RequiredRole for AdminRole {
+    const ROLES: &'static [Role] = &[Role::Admin];
+}
+
+/// Acting on accounts, e.g. freezing them
+pub(crate) struct OperatorRole;
+
+impl RequiredRole for OperatorRole {
+    const ROLES: &'static [Role] = &[Role::Support, Role::Admin];
+}
+
+/// Reading the audit log
+pub(crate) struct AuditorRole;
+
+impl RequiredRole for AuditorRole {
+    const ROLES: &'static [Role] = &[Role::Admin, Role::Auditor];
+}
+
+/// Read-only access to every account
+pub(crate) struct StaffRole;
+
+impl RequiredRole for StaffRole {
+    const ROLES: &'static [Role] = &[Role::Support, Role::Admin, Role::Auditor];
+}
+
+/// A logged in user whose role is one of the roles of `R`
+pub(crate) struct RoleInfo<R> {
+    pub username: String,
+    required: PhantomData<R>,
+}
+
+#[async_trait]
+impl<S: Send + Sync, R: RequiredRole> FromRequestParts<S> for RoleInfo<R>
+where
+    Db: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = authenticate(parts, state).await?;
+
+        if !R::ROLES.contains(&claims.role) {
+            return Err(AppError::Forbidden.into_response());
+        }
 
-        Ok(UserInfo { username })
+        Ok(RoleInfo {
+            username: claims.sub,
+            required: PhantomData,
+        })
     }
 }
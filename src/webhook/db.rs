@@ -0,0 +1,251 @@
+use serde::Serialize;
+use sqlx::{types::Json, PgConnection};
+use uuid::Uuid;
+
+use crate::db::Db;
+
+use super::{
+    DeliveryStatus, DueDelivery, EventType, WebhookDelivery, WebhookDeliveryAttempt,
+    WebhookEndpoint, WebhookEndpointRequest,
+};
+
+impl Db {
+    /// Writes an event to the outbox inside an already open database transaction
+    /// and queues a delivery for every active endpoint of the user subscribed to it
+    pub async fn record_event(
+        conn: &mut PgConnection,
+        username: &str,
+        event_type: EventType,
+        payload: &(impl Serialize + Sync),
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"WITH event AS (
+                INSERT INTO account_events(username, event_type, payload) VALUES($1, $2, $3)
+                RETURNING event_id
+            )
+            INSERT INTO webhook_deliveries(event_id, endpoint_id)
+            SELECT event.event_id, webhook_endpoints.endpoint_id
+            FROM event, webhook_endpoints
+            WHERE webhook_endpoints.username = $1 AND webhook_endpoints.active
+            AND (cardinality(webhook_endpoints.event_types) = 0 OR $2 = ANY(webhook_endpoints.event_types))"#,
+            username,
+            event_type.as_str(),
+            Json(payload) as _
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_webhook_endpoint(
+        &self,
+        username: &str,
+        endpoint_request: &WebhookEndpointRequest,
+        secret: &str,
+    ) -> sqlx::Result<WebhookEndpoint> {
+        let event_types: Vec<String> = endpoint_request
+            .event_types
+            .iter()
+            .map(|event_type| event_type.as_str().to_string())
+            .collect();
+
+        sqlx::query_as!(
+            WebhookEndpoint,
+            "INSERT INTO webhook_endpoints(username, url, secret, event_types) VALUES($1, $2, $3, $4)
+            RETURNING endpoint_id, url, event_types, active, created_at",
+            username,
+            endpoint_request.url,
+            secret,
+            &event_types
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_webhook_endpoints_list(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<WebhookEndpoint>> {
+        sqlx::query_as!(
+            WebhookEndpoint,
+            "SELECT endpoint_id, url, event_types, active, created_at FROM webhook_endpoints
+            WHERE username = $1 ORDER BY created_at DESC",
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Deactivates the endpoint and cancels its pending deliveries.
+    /// Returns false if the user has no endpoint with this id
+    pub async fn deactivate_webhook_endpoint(
+        &self,
+        endpoint_id: Uuid,
+        username: &str,
+    ) -> sqlx::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            "UPDATE webhook_endpoints SET active = false WHERE endpoint_id = $1 AND username = $2",
+            endpoint_id,
+            username
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() != 1 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET status = 'cancelled', updated_at = now()
+            WHERE endpoint_id = $1 AND status = 'pending'",
+            endpoint_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_webhook_deliveries_list(
+        &self,
+        username: &str,
+    ) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"SELECT webhook_deliveries.delivery_id, webhook_deliveries.endpoint_id,
+            webhook_deliveries.event_id, account_events.event_type,
+            webhook_deliveries.status AS "status: DeliveryStatus", webhook_deliveries.attempts,
+            webhook_deliveries.next_attempt_at, webhook_deliveries.created_at
+            FROM webhook_deliveries
+            JOIN webhook_endpoints USING (endpoint_id)
+            JOIN account_events USING (event_id)
+            WHERE webhook_endpoints.username = $1
+            ORDER BY webhook_deliveries.created_at DESC
+            LIMIT 100"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_webhook_delivery_attempts(
+        &self,
+        delivery_id: Uuid,
+        username: &str,
+    ) -> sqlx::Result<Vec<WebhookDeliveryAttempt>> {
+        sqlx::query_as!(
+            WebhookDeliveryAttempt,
+            "SELECT webhook_delivery_attempts.response_status, webhook_delivery_attempts.error,
+            webhook_delivery_attempts.created_at
+            FROM webhook_delivery_attempts
+            JOIN webhook_deliveries USING (delivery_id)
+            JOIN webhook_endpoints USING (endpoint_id)
+            WHERE webhook_delivery_attempts.delivery_id = $1 AND webhook_endpoints.username = $2
+            ORDER BY webhook_delivery_attempts.attempt_id",
+            delivery_id,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Queues the delivery again with a fresh set of attempts.
+    /// Returns false if the user has no delivery with this id or its endpoint was deactivated
+    pub async fn schedule_redelivery(
+        &self,
+        delivery_id: Uuid,
+        username: &str,
+    ) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE webhook_deliveries SET status = 'pending', attempts = 0,
+            next_attempt_at = now(), updated_at = now()
+            FROM webhook_endpoints
+            WHERE webhook_deliveries.endpoint_id = webhook_endpoints.endpoint_id
+            AND webhook_deliveries.delivery_id = $1 AND webhook_endpoints.username = $2
+            AND webhook_endpoints.active",
+            delivery_id,
+            username
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Claims due deliveries by pushing their next attempt a few minutes into the future,
+    /// so a crashed worker retries them later and concurrent workers don't send them twice.
+    /// Deliveries to deactivated endpoints are never claimed
+    pub async fn claim_due_deliveries(&self, limit: i64) -> sqlx::Result<Vec<DueDelivery>> {
+        sqlx::query_as!(
+            DueDelivery,
+            "WITH due AS (
+                SELECT webhook_deliveries.delivery_id FROM webhook_deliveries
+                JOIN webhook_endpoints USING (endpoint_id)
+                WHERE webhook_deliveries.status = 'pending'
+                AND webhook_deliveries.next_attempt_at <= now() AND webhook_endpoints.active
+                ORDER BY webhook_deliveries.next_attempt_at
+                LIMIT $1
+                FOR UPDATE OF webhook_deliveries SKIP LOCKED
+            ), claimed AS (
+                UPDATE webhook_deliveries SET next_attempt_at = now() + interval '5 minutes'
+                FROM due WHERE webhook_deliveries.delivery_id = due.delivery_id
+                RETURNING webhook_deliveries.delivery_id, webhook_deliveries.attempts,
+                webhook_deliveries.endpoint_id, webhook_deliveries.event_id
+            )
+            SELECT claimed.delivery_id, claimed.attempts, webhook_endpoints.url,
+            webhook_endpoints.secret, account_events.event_id, account_events.event_type,
+            account_events.payload, account_events.created_at
+            FROM claimed
+            JOIN webhook_endpoints ON webhook_endpoints.endpoint_id = claimed.endpoint_id
+            JOIN account_events ON account_events.event_id = claimed.event_id",
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Logs an attempt and either completes the delivery or schedules the next attempt.
+    /// A failed attempt marks the delivery as failed when `last_attempt` is set.
+    /// A delivery cancelled while it was being sent stays cancelled
+    pub async fn record_delivery_attempt(
+        &self,
+        delivery_id: Uuid,
+        response_status: Option<i16>,
+        error: Option<&str>,
+        last_attempt: bool,
+        retry_in_seconds: i64,
+    ) -> sqlx::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO webhook_delivery_attempts(delivery_id, response_status, error) VALUES($1, $2, $3)",
+            delivery_id,
+            response_status,
+            error
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let status = match (error, last_attempt) {
+            (None, _) => DeliveryStatus::Succeeded,
+            (Some(_), true) => DeliveryStatus::Failed,
+            (Some(_), false) => DeliveryStatus::Pending,
+        };
+
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET status = $2, attempts = attempts + 1,
+            next_attempt_at = now() + make_interval(secs => $3), updated_at = now()
+            WHERE delivery_id = $1 AND status = 'pending'",
+            delivery_id,
+            status as DeliveryStatus,
+            retry_in_seconds as f64
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+}
@@ -0,0 +1,433 @@
+mod db;
+mod target;
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{app_state::AppState, config::config, db::Db, error::AppResult, utils::UserInfo};
+
+use self::target::{check_host, validate_endpoint_url, PublicResolver};
+
+/// How often the delivery worker looks for due deliveries
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+/// How many deliveries the worker claims at once
+const DELIVERY_BATCH_SIZE: i64 = 50;
+/// Delay before the first retry, doubled with every failed attempt
+const RETRY_BASE_DELAY_SECONDS: i64 = 30;
+/// Upper bound of the delay between two attempts
+const RETRY_MAX_DELAY_SECONDS: i64 = 6 * 60 * 60;
+
+pub(crate) const SIGNATURE_HEADER: &str = "Webhook-Signature";
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/endpoints", post(create_webhook_endpoint))
+        .route("/endpoints", get(webhook_endpoints_list))
+        .route("/endpoints/:id", delete(delete_webhook_endpoint))
+        .route("/deliveries", get(webhook_deliveries_list))
+        .route("/deliveries/:id/attempts", get(webhook_delivery_attempts))
+        .route("/deliveries/:id/redeliver", post(redeliver_webhook))
+        .with_state(app_state)
+}
+
+/// Events users can receive on their webhook endpoints
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventType {
+    #[serde(rename = "transaction.created")]
+    TransactionCreated,
+    #[serde(rename = "deposit.completed")]
+    DepositCompleted,
+    #[serde(rename = "refund.created")]
+    RefundCreated,
+    #[serde(rename = "reversal.created")]
+    ReversalCreated,
+}
+
+impl EventType {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            EventType::TransactionCreated => "transaction.created",
+            EventType::DepositCompleted => "deposit.completed",
+            EventType::RefundCreated => "refund.created",
+            EventType::ReversalCreated => "reversal.created",
+        }
+    }
+}
+
+/// Sends the events written to the outbox to the registered endpoints in the background
+pub(crate) fn spawn_delivery_worker(db: Db) {
+    tokio::spawn(async move {
+        // Redirects are not followed, they could point anywhere
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build the webhook http client");
+
+        loop {
+            match deliver_due(&db, &client).await {
+                Ok(0) => {}
+                Ok(delivered) => tracing::info!("attempted {delivered} webhook deliveries"),
+                Err(err) => tracing::error!("failed to deliver webhooks: {err}"),
+            }
+            tokio::time::sleep(DELIVERY_INTERVAL).await;
+        }
+    });
+}
+
+async fn deliver_due(db: &Db, client: &reqwest::Client) -> anyhow::Result<usize> {
+    let deliveries = db.claim_due_deliveries(DELIVERY_BATCH_SIZE).await?;
+    let count = deliveries.len();
+
+    for delivery in deliveries {
+        let body = serde_json::to_string(&WebhookPayload {
+            event_id: delivery.event_id,
+            event_type: &delivery.event_type,
+            created_at: delivery.created_at,
+            data: &delivery.payload,
+        })?;
+        let timestamp = Utc::now().timestamp();
+
+        // Endpoints registered before their address was checked are given up on right away
+        let target = Url::parse(&delivery.url)
+            .map_err(|err| err.to_string())
+            .and_then(|url| check_host(&url));
+        if let Err(err) = target {
+            db.record_delivery_attempt(delivery.delivery_id, None, Some(&err), true, 0)
+                .await?;
+            continue;
+        }
+
+        let result = client
+            .post(&delivery.url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(
+                SIGNATURE_HEADER,
+                format!(
+                    "t={timestamp},v1={}",
+                    sign(&delivery.secret, timestamp, &body)
+                ),
+            )
+            .header("Webhook-Event-Type", &delivery.event_type)
+            .header("Webhook-Delivery-Id", delivery.delivery_id.to_string())
+            .body(body)
+            .send()
+            .await;
+
+        let (response_status, error) = match result {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16() as i16), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16() as i16),
+                Some(format!("endpoint responded with {}", response.status())),
+            ),
+            Err(err) => (None, Some(error_chain(&err))),
+        };
+
+        let retry_in_seconds = RETRY_BASE_DELAY_SECONDS
+            .saturating_mul(1 << delivery.attempts.min(20))
+            .min(RETRY_MAX_DELAY_SECONDS);
+
+        db.record_delivery_attempt(
+            delivery.delivery_id,
+            response_status,
+            error.as_deref(),
+            delivery.attempts + 1 >= config().WEBHOOK_MAX_ATTEMPTS,
+            retry_in_seconds,
+        )
+        .await?;
+    }
+
+    Ok(count)
+}
+
+/// The error with its causes, which tell why a connection failed, e.g. an address that is not public
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        // Some errors already show their cause
+        let cause_message = cause.to_string();
+        if !message.ends_with(&cause_message) {
+            message = format!("{message}: {cause_message}");
+        }
+        source = cause.source();
+    }
+    message
+}
+
+/// Hex encoded HMAC-SHA256 of `"{timestamp}.{body}"`, which endpoints recompute to verify a delivery
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+///Registers an endpoint that receives the events of the logged in user.
+///The returned secret signs every payload and is only shown once
+#[utoipa::path(
+    post,
+    path = "/webhooks/endpoints",
+    tag = "Webhooks",
+    request_body = WebhookEndpointRequest,
+    responses(
+        (status = 201, description = "Endpoint successfully registered", body = CreatedWebhookEndpoint),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 422, description = "Invalid endpoint"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn create_webhook_endpoint(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Json(endpoint_request): Json<WebhookEndpointRequest>,
+) -> AppResult<impl IntoResponse> {
+    endpoint_request.validate()?;
+
+    let secret = format!("whsec_{}", Uuid::new_v4().simple());
+    let endpoint = db
+        .create_webhook_endpoint(&username, &endpoint_request, &secret)
+        .await?;
+
+    Ok((
+        http::StatusCode::CREATED,
+        Json(CreatedWebhookEndpoint { endpoint, secret }),
+    ))
+}
+
+///Webhook endpoints registered by a User
+#[utoipa::path(
+    get,
+    path = "/webhooks/endpoints",
+    tag = "Webhooks",
+    responses(
+        (status = 200, description = "Endpoints successfully retreived", body = Vec<WebhookEndpoint>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn webhook_endpoints_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_webhook_endpoints_list(&username).await?))
+}
+
+///Stops delivering events to an endpoint, deliveries still pending are cancelled
+#[utoipa::path(
+    delete,
+    path = "/webhooks/endpoints/{id}",
+    tag = "Webhooks",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Endpoint id")
+    ),
+    responses(
+        (status = 200, description = "Endpoint successfully deactivated"),
+        (status = 404, description = "Endpoint not found"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn delete_webhook_endpoint(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if !db.deactivate_webhook_endpoint(id, &username).await? {
+        return Ok(http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(http::StatusCode::OK)
+}
+
+///Latest deliveries to the endpoints of a User
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries",
+    tag = "Webhooks",
+    responses(
+        (status = 200, description = "Deliveries successfully retreived", body = Vec<WebhookDelivery>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn webhook_deliveries_list(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_webhook_deliveries_list(&username).await?))
+}
+
+///Every attempt of a delivery with the response of the endpoint
+#[utoipa::path(
+    get,
+    path = "/webhooks/deliveries/{id}/attempts",
+    tag = "Webhooks",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Delivery id")
+    ),
+    responses(
+        (status = 200, description = "Attempts successfully retreived", body = Vec<WebhookDeliveryAttempt>),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn webhook_delivery_attempts(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    Ok(Json(db.get_webhook_delivery_attempts(id, &username).await?))
+}
+
+///Delivers an event again, also after all automatic attempts failed
+#[utoipa::path(
+    post,
+    path = "/webhooks/deliveries/{id}/redeliver",
+    tag = "Webhooks",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Delivery id")
+    ),
+    responses(
+        (status = 200, description = "Delivery successfully scheduled"),
+        (status = 404, description = "Delivery not found or its endpoint was deactivated"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn redeliver_webhook(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    if !db.schedule_redelivery(id, &username).await? {
+        return Ok(http::StatusCode::NOT_FOUND);
+    }
+
+    Ok(http::StatusCode::OK)
+}
+
+/// Body of every webhook request
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event_id: Uuid,
+    event_type: &'a str,
+    created_at: chrono::DateTime<Utc>,
+    data: &'a serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct WebhookEndpointRequest {
+    /// Must be https on a public address
+    #[validate(url, custom(function = "validate_endpoint_url"))]
+    pub url: String,
+    /// Every event type is delivered when left empty
+    #[serde(default)]
+    pub event_types: Vec<EventType>,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct WebhookEndpoint {
+    pub endpoint_id: Uuid,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub active: bool,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CreatedWebhookEndpoint {
+    #[serde(flatten)]
+    pub endpoint: WebhookEndpoint,
+    /// Key of the HMAC-SHA256 signature sent in the `Webhook-Signature` header
+    pub secret: String,
+}
+
+/// Stored as the `webhook_delivery_status` database enum
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    /// Every automatic attempt failed
+    Failed,
+    /// The endpoint was deactivated before the event was delivered
+    Cancelled,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct WebhookDelivery {
+    pub delivery_id: Uuid,
+    pub endpoint_id: Uuid,
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: chrono::DateTime<Utc>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct WebhookDeliveryAttempt {
+    /// HTTP status returned by the endpoint, missing if the request failed before a response
+    pub response_status: Option<i16>,
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+/// A delivery claimed by the worker together with everything needed to send it
+pub(crate) struct DueDelivery {
+    pub delivery_id: Uuid,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+    pub event_id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub created_at: chrono::DateTime<Utc>,
+}
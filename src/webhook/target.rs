@@ -0,0 +1,96 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use url::{Host, Url};
+use validator::ValidationError;
+
+/// Endpoints must be reachable over https on a public address, so users can't make the
+/// server send requests into its own network
+pub(super) fn validate_endpoint_url(url: &str) -> Result<(), ValidationError> {
+    let url = Url::parse(url).map_err(|_| ValidationError::new("url"))?;
+    if url.scheme() != "https" {
+        return Err(ValidationError::new("https_required"));
+    }
+    check_host(&url).map_err(|_| ValidationError::new("public_host_required"))
+}
+
+/// Fails if the host of `url` is an address that is not public or a name for the local machine.
+/// Other names are checked by [`PublicResolver`] when they are resolved
+pub(super) fn check_host(url: &Url) -> Result<(), String> {
+    let public = match url.host() {
+        Some(Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        None => false,
+    };
+
+    match public {
+        true => Ok(()),
+        false => Err(format!("{url} is not a public address")),
+    }
+}
+
+/// Resolves host names for webhook deliveries and drops every address that is not public.
+/// Checking while connecting means a name can't resolve to a public address when the endpoint is
+/// registered and to an internal one when the delivery is sent
+pub(super) struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this network", shared address space, IETF protocol assignments, benchmarking, reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local, link local, documentation
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && second == 0x0db8)
+        // NAT64 and 6to4 addresses stand for an IPv4 address
+        || (first == 0x0064 && second == 0xff9b)
+        || first == 0x2002)
+}
@@ -0,0 +1,232 @@
+use serde_json::json;
+use uuid::Uuid;
+
+use sqlx::PgConnection;
+
+use crate::{
+    audit::{AuditAction, AuditContext, AuditEntry},
+    db::Db,
+    error::AppResult,
+    fee::FeeOperation,
+    ledger::{EntryKind, EntryReference, Movement, PAYOUTS_ACCOUNT, PAYOUTS_PENDING_ACCOUNT},
+    payout::{PayoutInstruction, PayoutOutcome, PayoutReport},
+};
+
+use super::{Withdrawal, WithdrawalRequest, WithdrawalStatus};
+
+impl Db {
+    /// Moves the funds of a new withdrawal from the user into the pending payouts account
+    /// and charges the withdrawal fee inside an already open database transaction.
+    /// Returns None without writing anything if the balance is insufficient.
+    /// Fails with [`AppError::LimitExceeded`](crate::error::AppError::LimitExceeded) if the
+    /// withdrawal goes over an outgoing limit of the user.
+    pub async fn reserve_withdrawal(
+        conn: &mut PgConnection,
+        username: &str,
+        withdrawal_request: &WithdrawalRequest,
+        audit_context: &AuditContext,
+    ) -> AppResult<Option<Uuid>> {
+        let account = Self::lock_user_accounts(&mut *conn, &[username])
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)?;
+        account.ensure_allows(Movement::Withdraw)?;
+
+        Self::get_limit_usage(&mut *conn, username)
+            .await?
+            .check_withdrawal(withdrawal_request.amount as i64)?;
+
+        let fee = Self::get_fee_plan(&mut *conn, username, FeeOperation::Withdrawal)
+            .await?
+            .fee(withdrawal_request.amount);
+
+        if account.available < withdrawal_request.amount as i64 + fee as i64 {
+            return Ok(None);
+        }
+
+        let withdrawal_id = sqlx::query!(
+            "INSERT INTO withdrawals(username, amount, fee, destination) VALUES($1, $2, $3, $4)
+            RETURNING withdrawal_id",
+            username,
+            withdrawal_request.amount,
+            fee,
+            withdrawal_request.destination
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .withdrawal_id;
+
+        let pending_account_id =
+            Self::system_account_id(&mut *conn, PAYOUTS_PENDING_ACCOUNT).await?;
+
+        Self::post_journal_entry(
+            &mut *conn,
+            EntryKind::WithdrawalReserve,
+            EntryReference::Withdrawal(withdrawal_id),
+            &[
+                (account.account_id, -(withdrawal_request.amount as i64)),
+                (pending_account_id, withdrawal_request.amount as i64),
+            ],
+        )
+        .await?;
+
+        Self::charge_fee(
+            &mut *conn,
+            account.account_id,
+            fee,
+            EntryReference::Withdrawal(withdrawal_id),
+        )
+        .await?;
+
+        Self::record_audit(
+            &mut *conn,
+            audit_context,
+            AuditEntry {
+                actor: Some(username),
+                action: AuditAction::Withdrawal,
+                target: Some(username),
+                before: Some(json!({ "balance": account.balance })),
+                after: Some(json!({
+                    "balance": account.balance - withdrawal_request.amount as i64 - fee as i64,
+                    "withdrawal_id": withdrawal_id,
+                    "amount": withdrawal_request.amount,
+                    "fee": fee,
+                    "destination": withdrawal_request.destination,
+                })),
+            },
+        )
+        .await?;
+
+        Ok(Some(withdrawal_id))
+    }
+
+    pub async fn get_withdrawal(&self, id: Uuid) -> sqlx::Result<Withdrawal> {
+        sqlx::query_as!(
+            Withdrawal,
+            r#"SELECT withdrawal_id, username, amount, fee, destination,
+            status AS "status: WithdrawalStatus",
+            provider_reference, failure_reason, created_at, updated_at
+            FROM withdrawals WHERE withdrawal_id = $1"#,
+            id
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn get_withdrawals_list(&self, username: &str) -> sqlx::Result<Vec<Withdrawal>> {
+        sqlx::query_as!(
+            Withdrawal,
+            r#"SELECT withdrawal_id, username, amount, fee, destination,
+            status AS "status: WithdrawalStatus",
+            provider_reference, failure_reason, created_at, updated_at
+            FROM withdrawals WHERE username = $1"#,
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Withdrawals that were not accepted by the payout provider yet
+    pub async fn get_pending_withdrawals(&self) -> sqlx::Result<Vec<PayoutInstruction>> {
+        sqlx::query_as!(
+            PayoutInstruction,
+            "SELECT withdrawal_id, amount, destination FROM withdrawals
+            WHERE status = 'pending' ORDER BY created_at"
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn mark_withdrawal_processing(
+        &self,
+        id: Uuid,
+        provider_reference: &str,
+    ) -> sqlx::Result<()> {
+        // The provider may have reported the outcome already, which must not be overwritten
+        sqlx::query!(
+            "UPDATE withdrawals SET status = 'processing', provider_reference = $1, updated_at = now()
+            WHERE withdrawal_id = $2 AND status = 'pending'",
+            provider_reference,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Completes the withdrawal or releases its funds and fee back to the user, depending on the report.
+    /// Reports for withdrawals that are already settled are ignored and false is returned.
+    pub async fn settle_withdrawal(&self, report: &PayoutReport) -> sqlx::Result<bool> {
+        let mut transaction = self.pool.begin().await?;
+
+        let Some(withdrawal) = sqlx::query!(
+            "SELECT username, amount, fee FROM withdrawals
+            WHERE withdrawal_id = $1 AND status IN ('pending', 'processing') FOR UPDATE",
+            report.withdrawal_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        let pending_account_id =
+            Self::system_account_id(&mut transaction, PAYOUTS_PENDING_ACCOUNT).await?;
+
+        let (kind, destination_account_id, status) = match report.outcome {
+            PayoutOutcome::Completed => (
+                EntryKind::WithdrawalComplete,
+                Self::system_account_id(&mut transaction, PAYOUTS_ACCOUNT).await?,
+                WithdrawalStatus::Completed,
+            ),
+            PayoutOutcome::Failed => {
+                let account_id =
+                    Self::lock_user_accounts(&mut transaction, &[withdrawal.username.as_str()])
+                        .await?
+                        .pop()
+                        .ok_or(sqlx::Error::RowNotFound)?
+                        .account_id;
+
+                Self::refund_fee(
+                    &mut transaction,
+                    account_id,
+                    withdrawal.fee,
+                    EntryReference::Withdrawal(report.withdrawal_id),
+                )
+                .await?;
+
+                (
+                    EntryKind::WithdrawalRelease,
+                    account_id,
+                    WithdrawalStatus::Failed,
+                )
+            }
+        };
+
+        Self::post_journal_entry(
+            &mut transaction,
+            kind,
+            EntryReference::Withdrawal(report.withdrawal_id),
+            &[
+                (pending_account_id, -(withdrawal.amount as i64)),
+                (destination_account_id, withdrawal.amount as i64),
+            ],
+        )
+        .await?;
+
+        sqlx::query!(
+            "UPDATE withdrawals SET status = $1, provider_reference = COALESCE($2, provider_reference),
+            failure_reason = $3, updated_at = now()
+            WHERE withdrawal_id = $4",
+            status as WithdrawalStatus,
+            report.provider_reference,
+            report.failure_reason,
+            report.withdrawal_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,167 @@
+mod db;
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::AuditContext,
+    db::Db,
+    error::AppResult,
+    idempotency::{request_fingerprint, run_idempotent, IdempotencyKey},
+    payout::Payouts,
+    utils::UserInfo,
+};
+
+pub(super) fn get_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/withdraw", post(withdraw))
+        .route("/withdrawals/:id", get(get_withdrawal_by_id))
+        .with_state(app_state)
+}
+
+///Reserves the funds, charges the withdrawal fee and hands the withdrawal to the payout provider.
+///Returns the withdrawal id
+#[utoipa::path(
+    post,
+    path = "/balance/withdraw",
+    tag = "Account Balance Management",
+    request_body = WithdrawalRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key replay the original response instead of withdrawing again")
+    ),
+    responses(
+        (status = 202, description = "Funds reserved, the payout is in progress", body = Uuid),
+        (status = 402, description = "Insufficient balance"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 403, description = "An outgoing limit would be exceeded"),
+        (status = 422, description = "Idempotency-Key was already used with a different request"),
+        (status = 423, description = "An account involved is frozen or closed"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn withdraw(
+    State(db): State<Db>,
+    State(payouts): State<Payouts>,
+    UserInfo { username }: UserInfo,
+    idempotency_key: IdempotencyKey,
+    audit_context: AuditContext,
+    Json(withdrawal_request): Json<WithdrawalRequest>,
+) -> AppResult<impl IntoResponse> {
+    withdrawal_request.validate()?;
+
+    let fingerprint = request_fingerprint("POST /balance/withdraw", &withdrawal_request)?;
+
+    let response = run_idempotent(
+        &db,
+        &username,
+        idempotency_key,
+        fingerprint,
+        |conn, username| {
+            Box::pin(async move {
+                let Some(withdrawal_id) =
+                    Db::reserve_withdrawal(conn, username, &withdrawal_request, &audit_context)
+                        .await?
+                else {
+                    return Ok((
+                        http::StatusCode::PAYMENT_REQUIRED,
+                        "Insufficent balance in user account".to_string(),
+                    ));
+                };
+
+                Ok((http::StatusCode::ACCEPTED, withdrawal_id.to_string()))
+            })
+        },
+    )
+    .await?;
+
+    payouts.dispatch_soon();
+
+    Ok(response)
+}
+
+#[utoipa::path(
+    get,
+    path = "/balance/withdrawals/{id}",
+    tag = "Account Balance Management",
+    params(
+        ("id" = Utoipa::Openapi::Schema::Uuid, Path, description = "Withdrawal id")
+    ),
+    responses(
+        (status = 200, description = "Withdrawal successfully retreived", body = Withdrawal),
+        (status = 403, description = "User is not authorized to view this withdrawal"),
+        (status = 401, description = "Incorrect Credentials"),
+        (status = 500, description = "Internal Server Error"),
+    ),
+    security(
+        ("USER_JWT" = []),
+        ("API_KEY" = [])
+    )
+)]
+async fn get_withdrawal_by_id(
+    State(db): State<Db>,
+    UserInfo { username }: UserInfo,
+    Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+    let withdrawal = db.get_withdrawal(id).await?;
+
+    if withdrawal.username != username {
+        return Ok((
+            http::StatusCode::FORBIDDEN,
+            "User is not allowed to view this withdrawal",
+        )
+            .into_response());
+    }
+
+    Ok(Json(withdrawal).into_response())
+}
+
+/// Lifecycle of a withdrawal, stored as the `withdrawal_status` database enum.
+/// `pending` until the provider accepted it, `processing` until the provider reported the outcome.
+#[derive(Serialize, Deserialize, ToSchema, sqlx::Type, Clone, Copy, Debug, PartialEq)]
+#[sqlx(type_name = "withdrawal_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WithdrawalStatus {
+    Pending,
+    Processing,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, ToSchema, Deserialize)]
+pub(crate) struct Withdrawal {
+    pub withdrawal_id: Uuid,
+    pub username: String,
+    pub amount: i32,
+    /// Paid on top of the amount, given back if the payout fails
+    pub fee: i32,
+    pub destination: String,
+    pub status: WithdrawalStatus,
+    pub provider_reference: Option<String>,
+    pub failure_reason: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
+pub(crate) struct WithdrawalRequest {
+    #[validate(range(min = 1))]
+    pub amount: i32,
+    /// Where the provider should send the money, e.g. a bank account number
+    #[validate(length(min = 1, max = 255))]
+    pub destination: String,
+}